@@ -0,0 +1,175 @@
+//! Append-only audit log of the commands a server sends, one JSON object per line.
+//!
+//! A `dispatched` entry is written before a command is published, and a `result`
+//! entry when the client's result arrives. Every entry records who ran the server
+//! (`operator`, as `user@host`) and carries `prev_hash`, the SHA-256 of the line
+//! before it, so removing or editing a line breaks the chain from there on. Run
+//! `verify` over a log to check the chain. Entries are synced to disk as they are
+//! written so they survive a crash.
+
+use crate::{Command, CommandResult, RsNatsError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// `prev_hash` of the first entry in a log
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditEvent {
+    Dispatched,
+    Result,
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// RFC 3339 time the entry was written, in UTC
+    pub timestamp: String,
+    pub event: AuditEvent,
+    pub operator: String,
+    pub client_id: String,
+    pub request_id: String,
+    /// The command as shown in the console, for `dispatched` entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// Whether the command succeeded, for `result` entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub success: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// RFC 3339 time the command started on the client, by its clock, for `result` entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub prev_hash: String,
+}
+
+/// Open audit log file and the hash of its last line
+pub struct AuditLog {
+    path: PathBuf,
+    operator: String,
+    state: Mutex<(File, String)>,
+}
+
+impl AuditLog {
+    /// Open the log at `path` for appending, creating it if needed. An existing log
+    /// is continued, chaining new entries onto its last line.
+    pub fn open(path: &Path) -> Result<Self, RsNatsError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(open_error(path, e)),
+        };
+        let last_hash = contents.lines()
+            .rfind(|line| !line.trim().is_empty())
+            .map(hash_line)
+            .unwrap_or_else(|| GENESIS_HASH.to_string());
+
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(path).map_err(|e| open_error(path, e))?;
+
+        let hostname = whoami::fallible::hostname().unwrap_or_else(|_| "unknown-host".to_string());
+        Ok(Self {
+            path: path.to_path_buf(),
+            operator: format!("{}@{}", whoami::username(), hostname),
+            state: Mutex::new((file, last_hash)),
+        })
+    }
+
+    /// Record that `command` is about to be sent. Commands must not be sent if this fails.
+    pub fn command_dispatched(&self, client_id: &str, request_id: &str, command: &Command) -> Result<(), RsNatsError> {
+        self.append(AuditEvent::Dispatched, client_id, request_id, |entry| {
+            entry.command = Some(command.to_string());
+        })
+    }
+
+    /// Record the result of a command
+    pub fn result_received(&self, client_id: &str, result: &CommandResult) -> Result<(), RsNatsError> {
+        let request_id = result.request_id.as_deref().unwrap_or_default();
+        self.append(AuditEvent::Result, client_id, request_id, |entry| {
+            entry.success = Some(result.success);
+            entry.exit_code = result.exit_code;
+            entry.started_at = result.started_at
+                .map(|started_at| humantime::format_rfc3339_millis(started_at).to_string());
+            entry.error = result.error.clone();
+        })
+    }
+
+    fn append(
+        &self,
+        event: AuditEvent,
+        client_id: &str,
+        request_id: &str,
+        fill: impl FnOnce(&mut AuditEntry),
+    ) -> Result<(), RsNatsError> {
+        let mut state = self.state.lock().unwrap();
+        let (file, last_hash) = &mut *state;
+
+        let mut entry = AuditEntry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event,
+            operator: self.operator.clone(),
+            client_id: client_id.to_string(),
+            request_id: request_id.to_string(),
+            command: None,
+            success: None,
+            exit_code: None,
+            started_at: None,
+            error: None,
+            prev_hash: last_hash.clone(),
+        };
+        fill(&mut entry);
+
+        let line = serde_json::to_string(&entry)
+            .map_err(|e| RsNatsError::SerializationError(format!("Failed to serialize audit entry: {}", e)))?;
+        file.write_all(format!("{}\n", line).as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| RsNatsError::ConfigError(format!(
+                "Failed to write audit log {}: {}", self.path.display(), e
+            )))?;
+        *last_hash = hash_line(&line);
+        Ok(())
+    }
+}
+
+/// Check the hash chain of the audit log at `path`, returning the number of entries.
+/// Fails at the first line that doesn't parse or doesn't follow from the line before.
+pub fn verify(path: &Path) -> Result<usize, RsNatsError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| open_error(path, e))?;
+    let mut expected = GENESIS_HASH.to_string();
+    let mut count = 0;
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: AuditEntry = serde_json::from_str(line).map_err(|e| RsNatsError::SerializationError(format!(
+            "{}:{}: not an audit entry: {}", path.display(), index + 1, e
+        )))?;
+        if entry.prev_hash != expected {
+            return Err(RsNatsError::SerializationError(format!(
+                "{}:{}: hash chain broken, the entry before it was changed or removed", path.display(), index + 1
+            )));
+        }
+        expected = hash_line(line);
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn hash_line(line: &str) -> String {
+    Sha256::digest(line.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn open_error(path: &Path, e: std::io::Error) -> RsNatsError {
+    RsNatsError::ConfigError(format!("Failed to open audit log {}: {}", path.display(), e))
+}
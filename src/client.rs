@@ -1,179 +1,547 @@
-use rs_nats_lib::{Command, CommandResult, CommandType, DEFAULT_NATS_URL, DEFAULT_SUBJECT_PREFIX, RsNatsError, SystemInfo, get_client_id, get_os_type, LogLevel};
+use crate::{check_payload_size, connect, logging, netstat, packages, AgentInfo, Codec, Command, CommandAck, CommandFailure, CommandPolicy, ConnectionOptions, CommandRequest, CommandResult, CommandType, ClientTime, DiskInfo, FileRange, LoadInfo, NetworkInterface, PackageList, ProbeOutcome, ProbeResult, ServiceInfo, ServiceState, Shell, ShellInput, OutputChunk, ScheduledJob, DEFAULT_COMMAND_TIMEOUT_SECS, DEFAULT_HEARTBEAT_SECS, DEFAULT_MAX_CONCURRENT_COMMANDS, DEFAULT_MAX_DOWNLOAD_BYTES, DEFAULT_MAX_OUTPUT_BYTES, DEFAULT_NATS_URL, DEFAULT_RATE_LIMIT, DEFAULT_REDACTED_ENV, DEFAULT_SUBJECT_PREFIX, PROTOCOL_VERSION, RsNatsError, SystemInfo, TlsOptions, format_bytes, format_unix_time, get_client_id, is_no_responders, wildcard_match, LOAD_SAMPLE_INTERVAL, get_os_type, redact_url, validate_client_id, shutdown_signal, LogLevel, SubjectLayout};
 use anyhow::Result;
-use async_nats::Client;
+use async_trait::async_trait;
+use async_nats::jetstream::consumer;
+use async_nats::jetstream::message::Acker;
+use async_nats::jetstream::AckKind;
+use async_nats::{Client, Subject, Subscriber};
+use bytes::Bytes;
 use log::{debug, error, info, warn};
+use rand::Rng;
+use serde::Deserialize;
 use futures_util::stream::StreamExt;
-use serde_json::{from_slice, to_string};
-use std::process::Command as ProcessCommand;
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
+use futures_util::FutureExt;
+use serde_json::{to_string, Value};
+use std::ffi::OsString;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use sysinfo::{CpuRefreshKind, DiskRefreshKind, Disks, System};
+use std::process::{Command as ProcessCommand, Stdio};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::process::Command as AsyncProcessCommand;
+use tokio::sync::{mpsc, oneshot, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
 
+/// How long to keep reading a killed command's pipes before giving up
+const PIPE_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Largest piece of output read from a pipe, and so the largest streamed chunk
+const STREAM_CHUNK_BYTES: usize = 32 * 1024;
+
+/// Chunks buffered between the pipe readers and the stream publisher
+const STREAM_CHANNEL_CAPACITY: usize = 64;
+
+/// How often a tailed file is checked for new lines
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a tail with nothing new sends an empty chunk, so the server doesn't give up on it
+const TAIL_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// How long to keep reading a shell session's terminal after the shell exits
+const SHELL_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+/// How much of the end of a file is read to find the lines shown when a tail starts
+const TAIL_BACKLOG_BYTES: u64 = 64 * 1024;
+
+/// Extra time past the command timeout before JetStream redelivers an unacknowledged command
+const JETSTREAM_ACK_GRACE: Duration = Duration::from_secs(30);
+
+/// How often JetStream is told a command is still being handled, well within
+/// `JETSTREAM_ACK_GRACE` so it isn't redelivered meanwhile
+const JETSTREAM_PROGRESS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long to wait for the OS to list disks. A hung network mount can block it forever.
+const DISK_USAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to try telling the server we're leaving before exiting anyway
+const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Shell commands still running, by request id, with a channel to cancel them
+type RunningCommands = Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>;
+
+/// Commands waiting for a free slot to run, see `acquire_slot`
+#[derive(Clone)]
+struct CommandSlots {
+    slots: Arc<Semaphore>,
+    max: usize,
+    waiting: Arc<AtomicUsize>,
+}
+
+/// File in the state directory holding the id the client last ran under
+const CLIENT_ID_FILE: &str = "client-id";
+
+/// Warn each time this many more commands are waiting for a slot
+const QUEUE_WARN_DEPTH: usize = 16;
+
+/// Jobs waiting for their time to run, by job id, with a channel to cancel them
+type ScheduledJobs = Arc<Mutex<HashMap<String, (ScheduledJob, oneshot::Sender<()>)>>>;
+
+/// Where commands arrive from: a plain subscription, or a durable JetStream consumer
+/// whose messages must be acknowledged once handled
+enum CommandStream {
+    Core(Subscriber),
+    JetStream(Box<consumer::pull::Stream>),
+}
+
+impl CommandStream {
+    /// The next command payload, with the subject to send its result to if the server
+    /// asked for a reply, and the handle to acknowledge it in JetStream mode.
+    /// Returns `None` once the subscription has ended.
+    async fn next(&mut self) -> Option<(Bytes, Option<Subject>, Option<Acker>)> {
+        match self {
+            CommandStream::Core(subscription) => {
+                subscription.next().await.map(|msg| (msg.payload, msg.reply, None))
+            },
+            CommandStream::JetStream(messages) => loop {
+                match messages.next().await? {
+                    // The reply subject of a JetStream message is for acknowledging it
+                    Ok(msg) => {
+                        let (msg, acker) = msg.split();
+                        return Some((msg.payload, None, Some(acker)));
+                    },
+                    Err(e) => warn!("Failed to receive command from JetStream: {}", e),
+                }
+            },
+        }
+    }
+}
+
+async fn ack(acker: Option<&Acker>) {
+    if let Some(acker) = acker {
+        if let Err(e) = acker.ack().await {
+            warn!("Failed to acknowledge command: {}", e);
+        }
+    }
+}
+
+/// Tells JetStream a command is still being handled, so it isn't delivered again
+/// while it waits for a slot or runs for longer than `ack_wait`. Stops when dropped.
+struct ProgressReporter(JoinHandle<()>);
+
+impl ProgressReporter {
+    fn start(acker: Arc<Acker>) -> Self {
+        Self(tokio::spawn(async move {
+            loop {
+                sleep(JETSTREAM_PROGRESS_INTERVAL).await;
+                if let Err(e) = acker.ack_with(AckKind::Progress).await {
+                    warn!("Failed to report command progress: {}", e);
+                }
+            }
+        }))
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// How the client retries registering while no server is answering, and
+/// resubscribing after its command subscription ends
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryConfig {
+    /// Wait before the first retry
+    pub initial_backoff: Duration,
+    /// Longest wait between retries
+    pub max_backoff: Duration,
+    /// Factor the wait grows by after each retry, at least 1
+    pub multiplier: f64,
+    /// Give up registering after this many attempts; `None` retries forever.
+    /// Resubscribing always retries forever.
+    pub max_attempts: Option<u32>,
+    /// Each wait is randomly up to this fraction shorter or longer, though never longer
+    /// than `max_backoff`, so clients cut off together don't all retry at the same
+    /// moment. From 0 up to but not including 1.
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+            multiplier: 1.5,
+            max_attempts: None,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryConfig {
+    fn validate(&self) -> Result<(), RsNatsError> {
+        if self.initial_backoff.is_zero() {
+            return Err(RsNatsError::ConfigError("Initial retry backoff must be greater than zero".to_string()));
+        }
+        if self.max_backoff < self.initial_backoff {
+            return Err(RsNatsError::ConfigError("Maximum retry backoff must not be less than the initial backoff".to_string()));
+        }
+        if !(self.multiplier >= 1.0 && self.multiplier.is_finite()) {
+            return Err(RsNatsError::ConfigError(format!("Retry backoff multiplier must be at least 1, got {}", self.multiplier)));
+        }
+        if self.max_attempts == Some(0) {
+            return Err(RsNatsError::ConfigError("Retry attempts must be at least 1".to_string()));
+        }
+        if !(0.0..1.0).contains(&self.jitter) {
+            return Err(RsNatsError::ConfigError(format!("Retry jitter must be from 0 up to 1, got {}", self.jitter)));
+        }
+        Ok(())
+    }
+}
+
+/// Exponential backoff between retries, following a `RetryConfig`
+struct Backoff<'a> {
+    config: &'a RetryConfig,
+    current: Duration,
+}
+
+impl<'a> Backoff<'a> {
+    fn new(config: &'a RetryConfig) -> Self {
+        Self { config, current: config.initial_backoff }
+    }
+    
+    /// The delay to wait before the next attempt, with jitter applied. The jitter
+    /// doesn't carry over, so delays still grow as configured on average.
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = std::cmp::min(self.current.mul_f64(self.config.multiplier), self.config.max_backoff);
+        if self.config.jitter == 0.0 {
+            return delay;
+        }
+        let jittered = delay.mul_f64(1.0 + rand::thread_rng().gen_range(-self.config.jitter..=self.config.jitter));
+        std::cmp::min(jittered, self.config.max_backoff)
+    }
+}
+
+/// Code run for `Command::Custom` commands of one name, registered with
+/// `SupportClientBuilder::handler`. Implement it with `#[async_trait]`, which is
+/// re-exported as `rs_nats_lib::async_trait`.
+#[async_trait]
+pub trait CommandHandler: Send + Sync {
+    /// Handle a command sent with these arguments. The result's request id is filled
+    /// in afterwards.
+    async fn handle(&self, args: &Value) -> CommandResult;
+}
+
+/// Handlers of `Command::Custom` by name
+type CommandHandlers = Arc<HashMap<String, Arc<dyn CommandHandler>>>;
+
+/// Support client that registers with a server and executes the commands it receives
 pub struct SupportClient {
     nats_client: Client,
+    /// With credentials redacted, for `Command::GetAgentInfo`
+    nats_url: String,
+    started: Instant,
     subject_prefix: String,
+    subjects: Arc<SubjectLayout>,
     client_id: String,
+    command_timeout: Duration,
+    file_root: Option<PathBuf>,
+    max_download_bytes: u64,
+    max_output_bytes: u64,
+    codec: Codec,
+    heartbeat_interval: Duration,
+    policy: CommandPolicy,
+    tags: HashMap<String, String>,
+    reconnected: Arc<Notify>,
+    jetstream: bool,
+    power_commands: bool,
+    redact_env: Vec<String>,
+    running: RunningCommands,
+    scheduled: ScheduledJobs,
+    retry: RetryConfig,
+    rate_limit: f64,
+    slots: CommandSlots,
+    state_dir: Option<PathBuf>,
+    shell: Shell,
+    handlers: CommandHandlers,
+    log_file: Option<PathBuf>,
+    windows_service: bool,
 }
 
 impl SupportClient {
+    /// Start building a client; see `SupportClientBuilder` for the settings
+    pub fn builder() -> SupportClientBuilder {
+        SupportClientBuilder::default()
+    }
+    
+    /// Same as the matching `SupportClientBuilder` settings, with `None` for the defaults
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         nats_url: Option<&str>, 
         subject_prefix: Option<&str>,
         client_id: Option<&str>,
+        command_timeout: Option<Duration>,
+        file_root: Option<PathBuf>,
+        max_download_bytes: Option<u64>,
+        codec: Option<Codec>,
+        heartbeat_interval: Option<Duration>,
+        connection: Option<ConnectionOptions>,
+        policy: Option<CommandPolicy>,
+        tags: Option<HashMap<String, String>>,
+        jetstream: bool,
+        power_commands: bool,
+        redact_env: Option<Vec<String>>,
     ) -> Result<Self> {
-        let url = nats_url.unwrap_or(DEFAULT_NATS_URL);
-        let prefix = subject_prefix.unwrap_or(DEFAULT_SUBJECT_PREFIX).to_string();
-        let id = client_id.map(|s| s.to_string()).unwrap_or_else(get_client_id);
-        
-        info!("Connecting to NATS server at {}", url);
-        let nats_client = async_nats::connect(url).await.map_err(|e| {
-            RsNatsError::ConnectionError(format!("Failed to connect to NATS: {}", e))
-        })?;
-        
-        Ok(Self {
-            nats_client,
-            subject_prefix: prefix,
-            client_id: id,
-        })
+        SupportClientBuilder {
+            nats_url: nats_url.map(str::to_string),
+            subject_prefix: subject_prefix.map(str::to_string),
+            client_id: client_id.map(str::to_string),
+            command_timeout,
+            file_root,
+            max_download_bytes,
+            codec,
+            heartbeat_interval,
+            connection,
+            policy,
+            tags,
+            jetstream,
+            power_commands: Some(power_commands),
+            redact_env,
+            ..SupportClientBuilder::default()
+        }.build().await
     }
     
     pub async fn run(&self) -> Result<()> {
-        let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<bool>(1);
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<Stop>(1);
         
-        // Register with the server - keep trying indefinitely until successful
         info!("Registering with server as {}", self.client_id);
-        self.register_with_retry(true).await?;
+        self.register_with_retry().await?;
         
         // Subscribe to commands
-        let command_subject = format!("{}.command.{}", self.subject_prefix, self.client_id);
-        info!("Subscribing to commands on {}", command_subject);
+        info!("Subscribing to commands on {}", self.subjects.command(&self.subject_prefix, &self.client_id));
+        let command_subscription = self.subscribe_commands().await?;
         
-        let command_subscription = self.nats_client.subscribe(command_subject).await?;
+        let mut command_handler = self.spawn_command_handler(command_subscription, shutdown_tx.clone());
         
+        // Heartbeat to server
         let nats = self.nats_client.clone();
         let client_id = self.client_id.clone();
-        let prefix = self.subject_prefix.clone();
-        let shutdown_tx_clone = shutdown_tx.clone();
+        let heartbeat_subject = self.subjects.heartbeat(&self.subject_prefix);
+        let heartbeat_interval = self.heartbeat_interval;
+        
+        let heartbeat = tokio::spawn(async move {
+            loop {
+                sleep(heartbeat_interval).await;
+                
+                let _ = nats.publish(heartbeat_subject.clone(), client_id.clone().into()).await;
+                debug!("Sent heartbeat");
+            }
+        });
         
-        // Handle incoming commands
+        // Stop on SIGINT/SIGTERM the same way as on a Shutdown command
+        let signal_tx = shutdown_tx.clone();
         tokio::spawn(async move {
-            let mut command_stream = command_subscription;
-            while let Some(msg) = command_stream.next().await {
-                match from_slice::<Command>(&msg.payload) {
-                    Ok(command) => {
-                        info!("Received command: {}", command);
-                        
-                        let result = match command {
-                            Command::Ping => {
-                                CommandResult {
-                                    success: true,
-                                    output: "Pong".to_string(),
-                                    error: None,
-                                    command_type: CommandType::Internal,
-                                }
-                            },
-                            Command::Execute(cmd) => {
-                                execute_command(&cmd)
-                            },
-                            Command::GetSystemInfo => {
-                                let sys_info = get_system_info();
-                                // Use serde_json to serialize the system info properly
-                                match to_string(&sys_info) {
-                                    Ok(json) => {
-                                        CommandResult {
-                                            success: true,
-                                            output: json,
-                                            error: None,
-                                            command_type: CommandType::Internal,
-                                        }
-                                    },
-                                    Err(e) => {
-                                        CommandResult {
-                                            success: false,
-                                            output: String::new(),
-                                            error: Some(format!("Failed to serialize system info: {}", e)),
-                                            command_type: CommandType::Internal,
-                                        }
-                                    }
-                                }
-                            },
-                            Command::Shutdown => {
-                                info!("Received shutdown command");
-                                let _ = shutdown_tx_clone.send(true).await;
-                                CommandResult {
-                                    success: true,
-                                    output: "Client shutting down".to_string(),
-                                    error: None,
-                                    command_type: CommandType::Internal,
-                                }
-                            },
-                            Command::LogEvent { level, message } => {
-                                match level {
-                                    LogLevel::Debug => debug!("{}", message),
-                                    LogLevel::Info => info!("{}", message),
-                                    LogLevel::Warning => warn!("{}", message),
-                                    LogLevel::Error => error!("{}", message),
-                                }
-                                
-                                CommandResult {
-                                    success: true,
-                                    output: format!("Logged: [{}] {}", level, message),
-                                    error: None,
-                                    command_type: CommandType::Internal,
-                                }
-                            }
-                        };
-                        
-                        // Send the result back
-                        let response_subject = format!("{}.response.{}", prefix, client_id);
-                        match to_string(&result) {
-                            Ok(json) => {
-                                info!("Sending response to {}: {}", response_subject, json);
-                                let send_result = nats.publish(response_subject, json.into()).await;
-                                match send_result {
-                                    Ok(_) => info!("Successfully sent response"),
-                                    Err(e) => error!("Failed to send response: {}", e),
-                                }
-                            },
-                            Err(e) => {
-                                error!("Failed to serialize result: {}", e);
+            shutdown_signal().await;
+            info!("Received termination signal");
+            let _ = signal_tx.send(Stop::Shutdown).await;
+        });
+        
+        // Run until shut down. Re-register after a reconnect in case the server dropped
+        // this client while it was away, and resubscribe if the command subscription ends.
+        let stop = loop {
+            tokio::select! {
+                stop = shutdown_rx.recv() => break stop,
+                _ = self.reconnected.notified() => {
+                    info!("Registering with server again after reconnecting");
+                    tokio::select! {
+                        stop = shutdown_rx.recv() => break stop,
+                        registered = self.register_with_retry() => {
+                            if let Err(e) = registered {
+                                warn!("Failed to register again after reconnecting: {}", e);
                             }
-                        }
-                    },
-                    Err(e) => {
-                        error!("Failed to parse command: {}", e);
+                        },
                     }
-                }
+                    continue;
+                },
+                _ = &mut command_handler => {},
             }
+            warn!("Command subscription ended, resubscribing");
+            
+            let subscription = tokio::select! {
+                stop = shutdown_rx.recv() => break stop,
+                subscription = self.resubscribe() => subscription,
+            };
+            command_handler = self.spawn_command_handler(subscription, shutdown_tx.clone());
+        };
+        info!("Client shutting down");
+        
+        // Nor keep heartbeating once deregistered
+        heartbeat.abort();
+        self.deregister().await;
+        
+        if let Some(Stop::Restart) = stop {
+            restart_agent()?;
+        }
+        Ok(())
+    }
+    
+    /// Handle commands from `subscription` until it ends, sending results back to the server.
+    /// Each command runs in its own task, so a long one can be cancelled while it runs.
+    fn spawn_command_handler(&self, mut commands: CommandStream, shutdown_tx: mpsc::Sender<Stop>) -> JoinHandle<()> {
+        let context = Arc::new(CommandContext {
+            nats: self.nats_client.clone(),
+            client_id: self.client_id.clone(),
+            prefix: self.subject_prefix.clone(),
+            subjects: self.subjects.clone(),
+            command_timeout: self.command_timeout,
+            file_root: self.file_root.clone(),
+            max_download_bytes: self.max_download_bytes,
+            max_output_bytes: self.max_output_bytes,
+            codec: self.codec,
+            policy: self.policy.clone(),
+            tags: self.tags.clone(),
+            power_commands: self.power_commands,
+            redact_env: self.redact_env.clone(),
+            running: self.running.clone(),
+            scheduled: self.scheduled.clone(),
+            slots: self.slots.clone(),
+            shell: self.shell,
+            handlers: self.handlers.clone(),
+            log_file: self.log_file.clone(),
+            windows_service: self.windows_service,
+            agent_info: self.agent_info(),
+            started: self.started,
+            shutdown_tx,
         });
         
-        // Heartbeat to server
-        let nats = self.nats_client.clone();
-        let client_id = self.client_id.clone();
-        let prefix = self.subject_prefix.clone();
+        let mut limiter = (self.rate_limit > 0.0).then(|| RateLimiter::new(self.rate_limit));
         
         tokio::spawn(async move {
-            loop {
-                sleep(Duration::from_secs(30)).await;
-                
-                let heartbeat_subject = format!("{}.heartbeat", prefix);
-                let _ = nats.publish(heartbeat_subject, client_id.clone().into()).await;
-                debug!("Sent heartbeat");
+            while let Some((payload, reply, acker)) = commands.next().await {
+                match context.codec.decode::<CommandRequest>(&payload) {
+                    // A flood of commands mustn't keep a runaway one from being cancelled
+                    Ok(request) if !matches!(request.command, Command::CancelRequest { .. })
+                        && limiter.as_mut().is_some_and(|limiter| !limiter.try_acquire()) => {
+                        tokio::spawn(refuse_rate_limited(context.clone(), request, reply, acker));
+                    },
+                    Ok(request) => {
+                        tokio::spawn(handle_request(context.clone(), request, reply, acker));
+                    },
+                    Err(e) => {
+                        tokio::spawn(refuse_malformed(context.clone(), payload, e, reply, acker));
+                    }
+                }
             }
-        });
+        })
+    }
+    
+    /// This client's settings for `Command::GetAgentInfo`. Uptime and log level
+    /// change while it runs and are filled in when asked.
+    fn agent_info(&self) -> AgentInfo {
+        AgentInfo {
+            client_id: self.client_id.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            build: format!(
+                "{} {}-{}",
+                if cfg!(debug_assertions) { "debug" } else { "release" },
+                std::env::consts::ARCH,
+                std::env::consts::OS,
+            ),
+            protocol_version: PROTOCOL_VERSION,
+            pid: std::process::id(),
+            executable: std::env::current_exe().ok().map(|path| path.display().to_string()),
+            uptime_secs: 0,
+            nats_url: self.nats_url.clone(),
+            subject_prefix: self.subject_prefix.clone(),
+            jetstream: self.jetstream,
+            encoding: self.codec.encoding.to_string(),
+            compression: self.codec.compression.to_string(),
+            encrypted: self.codec.is_encrypted(),
+            log_level: String::new(),
+            log_file: self.log_file.as_ref().map(|path| path.display().to_string()),
+            command_timeout_secs: self.command_timeout.as_secs(),
+            heartbeat_secs: self.heartbeat_interval.as_secs(),
+            file_root: self.file_root.as_ref().map(|root| root.display().to_string()),
+            max_download_bytes: self.max_download_bytes,
+            max_output_bytes: self.max_output_bytes,
+            rate_limit: self.rate_limit,
+            max_concurrent_commands: self.slots.max,
+            state_dir: self.state_dir.as_ref().map(|dir| dir.display().to_string()),
+            shell: self.shell.to_string(),
+            power_commands: self.power_commands,
+            policy_allow: self.policy.allow.clone(),
+            policy_deny: self.policy.deny.clone(),
+            redact_env: self.redact_env.clone(),
+            tags: self.tags.clone(),
+            capabilities: Command::ALL_NAMES.iter().map(|name| name.to_string()).collect(),
+            custom_commands: {
+                let mut names: Vec<String> = self.handlers.keys().cloned().collect();
+                names.sort();
+                names
+            },
+        }
+    }
+    
+    /// Start receiving this client's commands, from its durable JetStream consumer
+    /// in JetStream mode and from a plain subscription otherwise
+    async fn subscribe_commands(&self) -> Result<CommandStream> {
+        if self.jetstream {
+            // Leave time for the command to finish before JetStream redelivers it
+            let ack_wait = self.command_timeout + JETSTREAM_ACK_GRACE;
+            let messages = crate::jetstream::consume_commands(
+                &self.nats_client,
+                &self.subjects,
+                &self.subject_prefix,
+                &self.client_id,
+                ack_wait,
+            ).await?;
+            Ok(CommandStream::JetStream(Box::new(messages)))
+        } else {
+            let command_subject = self.subjects.command(&self.subject_prefix, &self.client_id);
+            Ok(CommandStream::Core(self.nats_client.subscribe(command_subject).await?))
+        }
+    }
+    
+    /// Subscribe to commands again and re-register, since the server may have dropped
+    /// this client in the meantime. Retries with backoff until both succeed.
+    async fn resubscribe(&self) -> CommandStream {
+        let mut backoff = Backoff::new(&self.retry);
         
-        // Wait for shutdown signal
-        let _ = shutdown_rx.recv().await;
-        info!("Client shutting down");
+        loop {
+            let attempt = async {
+                let subscription = self.subscribe_commands().await?;
+                self.register().await?;
+                Ok::<_, anyhow::Error>(subscription)
+            };
+            match attempt.await {
+                Ok(subscription) => {
+                    info!("Resubscribed to commands");
+                    return subscription;
+                },
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!("Failed to resubscribe: {}, retrying in {:?}", e, delay);
+                    sleep(delay).await;
+                }
+            }
+        }
+    }
+    
+    /// Tell the server this client is going away. Best-effort: a dead connection
+    /// must not hold up shutdown.
+    async fn deregister(&self) {
+        let deregister_subject = self.subjects.deregister(&self.subject_prefix);
+        let publish = async {
+            self.nats_client.publish(deregister_subject, self.client_id.clone().into()).await?;
+            self.nats_client.flush().await?;
+            Ok::<(), anyhow::Error>(())
+        };
         
-        Ok(())
+        match tokio::time::timeout(DEREGISTER_TIMEOUT, publish).await {
+            Ok(Ok(())) => info!("Deregistered from server"),
+            Ok(Err(e)) => warn!("Failed to deregister: {}", e),
+            Err(_) => warn!("Timed out deregistering from server"),
+        }
     }
     
-    // Modified to run indefinitely if needed
-    async fn register_with_retry(&self, run_indefinitely: bool) -> Result<()> {
+    /// Register, retrying as set by the `RetryConfig` while no server is answering
+    async fn register_with_retry(&self) -> Result<()> {
         let mut attempts = 0;
-        let initial_backoff = Duration::from_secs(2);
-        let max_backoff = Duration::from_secs(60); // Cap at 1 minute between retries
-        let mut current_backoff = initial_backoff;
+        let mut backoff = Backoff::new(&self.retry);
         
         loop {
             attempts += 1;
@@ -181,34 +549,18 @@ impl SupportClient {
             match self.register().await {
                 Ok(()) => return Ok(()),
                 Err(e) => {
-                    // Just check error message for "no responders" string
-                    let error_msg = e.to_string();
-                    let is_no_responders = error_msg.contains("no responders");
-                    
-                    if is_no_responders {
-                        warn!("No server ready yet, retrying in {:?} (attempt {})", 
-                            current_backoff, attempts);
-                        sleep(current_backoff).await;
-                        
-                        // Increase backoff for next attempt (exponential backoff)
-                        // but cap at max_backoff
-                        current_backoff = std::cmp::min(
-                            Duration::from_secs_f32(current_backoff.as_secs_f32() * 1.5),
-                            max_backoff
-                        );
-                        
-                        // Keep trying indefinitely if specified
-                        if run_indefinitely {
-                            continue;
+                    if is_no_responders(&e) {
+                        if self.retry.max_attempts.is_some_and(|max| attempts >= max) {
+                            return Err(anyhow::anyhow!("Failed to register after {} attempts: {:#}", attempts, e));
                         }
                         
-                        // Otherwise give up after 10 attempts
-                        if attempts >= 10 {
-                            return Err(anyhow::anyhow!("Failed to register after {} attempts: {}", attempts, e));
-                        }
+                        let delay = backoff.next_delay();
+                        warn!("No server ready yet, retrying in {:?} (attempt {})", 
+                            delay, attempts);
+                        sleep(delay).await;
                     } else {
                         // If it's some other error, don't retry
-                        return Err(anyhow::anyhow!("Registration failed: {}", e));
+                        return Err(anyhow::anyhow!("Registration failed: {:#}", e));
                     }
                 }
             }
@@ -216,11 +568,11 @@ impl SupportClient {
     }
     
     async fn register(&self) -> Result<()> {
-        let register_subject = format!("{}.register", self.subject_prefix);
-        let system_info = get_system_info();
+        let register_subject = self.subjects.register(&self.subject_prefix);
+        let system_info = get_system_info(&self.tags);
         
-        match to_string(&system_info) {
-            Ok(json) => {
+        match self.codec.encode(&system_info) {
+            Ok(payload) => {
                 // Create headers with client_id
                 let mut headers = async_nats::HeaderMap::new();
                 headers.insert("client_id", self.client_id.as_str());
@@ -231,7 +583,7 @@ impl SupportClient {
                     self.nats_client.request_with_headers(
                         register_subject, 
                         headers,
-                        json.into()
+                        payload.into()
                     )
                 ).await {
                     Ok(resp_result) => {
@@ -247,7 +599,8 @@ impl SupportClient {
                                 
                                 Ok(())
                             },
-                            Err(e) => Err(anyhow::anyhow!("Registration request failed: {}", e))
+                            // Kept as the cause so `is_no_responders` can find it
+                            Err(e) => Err(anyhow::Error::new(e).context("Registration request failed"))
                         }
                     },
                     Err(_) => Err(anyhow::anyhow!("Registration request timed out"))
@@ -260,99 +613,2783 @@ impl SupportClient {
     }
 }
 
-fn get_system_info() -> SystemInfo {
-    let hostname = whoami::fallible::hostname().unwrap_or_else(|_| "unknown-host".to_string());
-    let username = whoami::username();
-    let os_type = get_os_type();
-    let os_version = get_os_version();
+async fn upload_file(path: &str, contents: &[u8], mode: Option<u32>, file_root: Option<&Path>) -> CommandResult {
+    let target = match resolve_file_path(path, file_root) {
+        Ok(target) => target,
+        Err(e) => {
+            return CommandResult::new(false, String::new(), Some(e), CommandType::Internal);
+        }
+    };
     
-    SystemInfo {
-        hostname,
-        username,
-        os_type,
-        os_version,
+    if let Err(e) = tokio::fs::write(&target, contents).await {
+        return CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("Failed to write {}: {}", target.display(), e)),
+            CommandType::Internal,
+        );
     }
+    
+    if let Some(mode) = mode {
+        if let Err(e) = set_file_mode(&target, mode).await {
+            return CommandResult::new(
+                false,
+                String::new(),
+                Some(format!("Wrote {} but failed to set mode {:o}: {}", target.display(), mode, e)),
+                CommandType::Internal,
+            );
+        }
+    }
+    
+    info!("Wrote {} bytes to {}", contents.len(), target.display());
+    CommandResult::new(
+        true,
+        format!("Wrote {} bytes to {}", contents.len(), target.display()),
+        None,
+        CommandType::Internal,
+    )
 }
 
-fn get_os_version() -> Option<String> {
-    if cfg!(target_os = "windows") {
-        // Windows-specific implementation
-        let output = ProcessCommand::new("cmd")
-            .args(&["/c", "ver"])
-            .output();
-            
-        match output {
-            Ok(out) => {
-                let version = String::from_utf8_lossy(&out.stdout);
-                Some(version.trim().to_string())
+async fn download_file(path: &str, max_bytes: u64, file_root: Option<&Path>) -> CommandResult {
+    let target = match resolve_file_path(path, file_root) {
+        Ok(target) => target,
+        Err(e) => {
+            return CommandResult::new(false, String::new(), Some(e), CommandType::Internal);
+        }
+    };
+    
+    let size = match tokio::fs::metadata(&target).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            return CommandResult::new(
+                false,
+                String::new(),
+                Some(format!("Failed to read {}: {}", target.display(), e)),
+                CommandType::Internal,
+            );
+        }
+    };
+    
+    if size > max_bytes {
+        return CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("{} is {} bytes, larger than the {} byte download limit", target.display(), size, max_bytes)),
+            CommandType::Internal,
+        );
+    }
+    
+    match tokio::fs::read(&target).await {
+        Ok(contents) => {
+            let mut result = CommandResult::new(
+                true,
+                format!("Read {} bytes from {}", contents.len(), target.display()),
+                None,
+                CommandType::Internal,
+            );
+            result.data = contents;
+            result
+        },
+        Err(e) => {
+            CommandResult::new(
+                false,
+                String::new(),
+                Some(format!("Failed to read {}: {}", target.display(), e)),
+                CommandType::Internal,
+            )
+        }
+    }
+}
+
+/// Read up to `length` bytes of a file from `offset` on, for paging through files
+/// too large to download whole
+async fn read_file_range(path: &str, offset: u64, length: u64, max_bytes: u64, file_root: Option<&Path>) -> CommandResult {
+    let failed = |error: String| CommandResult::new(false, String::new(), Some(error), CommandType::Internal);
+    
+    let target = match resolve_file_path(path, file_root) {
+        Ok(target) => target,
+        Err(e) => return failed(e),
+    };
+    if length > max_bytes {
+        return failed(format!("{} bytes asked for, more than the {} byte download limit", length, max_bytes));
+    }
+    
+    let read = async {
+        let mut file = tokio::fs::File::open(&target).await?;
+        let total_size = file.metadata().await?.len();
+        if offset > total_size {
+            return Ok(Err(total_size));
+        }
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        let mut contents = Vec::new();
+        file.take(length).read_to_end(&mut contents).await?;
+        Ok::<_, std::io::Error>(Ok((contents, total_size)))
+    };
+    
+    match read.await {
+        Ok(Ok((contents, total_size))) => {
+            let range = FileRange { offset, length: contents.len() as u64, total_size };
+            match to_string(&range) {
+                Ok(json) => {
+                    let mut result = CommandResult::new(true, json, None, CommandType::Internal);
+                    result.data = contents;
+                    result
+                },
+                Err(e) => failed(format!("Failed to serialize file range: {}", e)),
+            }
+        },
+        Ok(Err(total_size)) => failed(format!(
+            "Offset {} is past the end of {}, which is {} bytes", offset, target.display(), total_size
+        )),
+        Err(e) => failed(format!("Failed to read {}: {}", target.display(), e)),
+    }
+}
+
+/// Ask the platform's service manager about the service `name`
+async fn service_status(name: &str) -> CommandResult {
+    let failed = |error: String| CommandResult::new(false, String::new(), Some(error), CommandType::Internal);
+    
+    // Names go to the service manager as arguments, so keep options out
+    let valid = !name.is_empty() && !name.starts_with('-')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "@._:-".contains(c));
+    if !valid {
+        return failed(format!("Invalid service name: {}", name));
+    }
+    
+    let run = |program: &str, args: &[&str]| {
+        let mut command = AsyncProcessCommand::new(program);
+        command.args(args);
+        async move { command.output().await }
+    };
+    
+    let queried = if cfg!(target_os = "linux") {
+        match (run("systemctl", &["is-active", name]).await, run("systemctl", &["status", "--no-pager", name]).await) {
+            (Ok(active), Ok(status)) => {
+                // `status` exits with 4 for units that don't exist
+                let state = if status.status.code() == Some(4) {
+                    ServiceState::NotFound
+                } else {
+                    systemd_state(String::from_utf8_lossy(&active.stdout).trim())
+                };
+                let details = if status.stdout.is_empty() { &status.stderr } else { &status.stdout };
+                Ok((state, String::from_utf8_lossy(details).to_string()))
             },
-            Err(_) => None,
+            (Err(e), _) | (_, Err(e)) => Err(format!("Failed to run systemctl: {}", e)),
         }
-    } else if cfg!(target_os = "linux") {
-        // Linux-specific implementation
-        let output = ProcessCommand::new("cat")
-            .arg("/etc/os-release")
-            .output();
-            
-        match output {
-            Ok(out) => {
-                let release_info = String::from_utf8_lossy(&out.stdout);
-                for line in release_info.lines() {
-                    if line.starts_with("PRETTY_NAME=") {
-                        return Some(line.trim_start_matches("PRETTY_NAME=")
-                            .trim_matches('"')
-                            .to_string());
-                    }
-                }
-                None
+    } else if cfg!(target_os = "windows") {
+        match run("sc", &["query", name]).await {
+            Ok(output) => {
+                let text = String::from_utf8_lossy(&output.stdout).to_string();
+                Ok((sc_state(&text), text))
             },
-            Err(_) => None,
+            Err(e) => Err(format!("Failed to run sc: {}", e)),
+        }
+    } else if cfg!(target_os = "macos") {
+        match run("launchctl", &["list", name]).await {
+            Ok(output) if output.status.success() => {
+                let text = String::from_utf8_lossy(&output.stdout).to_string();
+                Ok((launchctl_state(&text), text))
+            },
+            Ok(output) => Ok((ServiceState::NotFound, String::from_utf8_lossy(&output.stderr).to_string())),
+            Err(e) => Err(format!("Failed to run launchctl: {}", e)),
         }
     } else {
-        // Fallback for other platforms
-        None
+        Err("Service status is not supported on this platform".to_string())
+    };
+    
+    let (state, details) = match queried {
+        Ok(queried) => queried,
+        Err(e) => return failed(e),
+    };
+    let info = ServiceInfo { name: name.to_string(), state, details: details.trim_end().to_string() };
+    match to_string(&info) {
+        Ok(json) => CommandResult::new(true, json, None, CommandType::Internal),
+        Err(e) => failed(format!("Failed to serialize service status: {}", e)),
     }
 }
 
-fn execute_command(cmd: &str) -> CommandResult {
-    let command_result = if cfg!(target_os = "windows") {
-        ProcessCommand::new("cmd")
-            .args(&["/c", cmd])
-            .output()
-    } else {
-        ProcessCommand::new("sh")
-            .args(&["-c", cmd])
-            .output()
-    };
+/// State reported by `systemctl is-active`
+fn systemd_state(state: &str) -> ServiceState {
+    match state {
+        "active" => ServiceState::Active,
+        "activating" | "reloading" | "refreshing" => ServiceState::Activating,
+        "deactivating" => ServiceState::Deactivating,
+        "inactive" | "maintenance" => ServiceState::Inactive,
+        "failed" => ServiceState::Failed,
+        _ => ServiceState::Unknown,
+    }
+}
+
+/// State from the `STATE : 4  RUNNING` line of `sc query`, which fails with error
+/// 1060 for services that don't exist
+fn sc_state(output: &str) -> ServiceState {
+    if output.contains("1060") {
+        return ServiceState::NotFound;
+    }
+    let state = output.lines()
+        .find_map(|line| line.trim().strip_prefix("STATE"))
+        .and_then(|line| line.split_whitespace().nth(2));
+    match state {
+        Some("RUNNING") => ServiceState::Active,
+        Some("START_PENDING") | Some("CONTINUE_PENDING") => ServiceState::Activating,
+        Some("STOP_PENDING") | Some("PAUSE_PENDING") => ServiceState::Deactivating,
+        Some("STOPPED") | Some("PAUSED") => ServiceState::Inactive,
+        _ => ServiceState::Unknown,
+    }
+}
+
+/// State from the properties `launchctl list <label>` prints: running jobs have a
+/// `PID`, and stopped ones a `LastExitStatus` that is non-zero if they failed
+fn launchctl_state(output: &str) -> ServiceState {
+    let property = |key: &str| output.lines()
+        .find_map(|line| line.trim().strip_prefix(&format!("\"{}\" = ", key)))
+        .map(|value| value.trim_end_matches(';').to_string());
+    if property("PID").is_some() {
+        return ServiceState::Active;
+    }
+    match property("LastExitStatus").as_deref() {
+        Some("0") | None => ServiceState::Inactive,
+        Some(_) => ServiceState::Failed,
+    }
+}
+
+async fn kill_process(pid: u32, force: bool) -> CommandResult {
+    if pid <= 1 {
+        return CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("Refusing to kill PID {}", pid)),
+            CommandType::Internal,
+        );
+    }
     
-    match command_result {
-        Ok(output) => {
-            let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            
-            if output.status.success() {
-                CommandResult {
-                    success: true,
-                    output: stdout,
-                    error: if stderr.is_empty() { None } else { Some(stderr) },
-                    command_type: CommandType::Shell,
-                }
-            } else {
-                CommandResult {
-                    success: false,
-                    output: stdout,
-                    error: Some(stderr),
-                    command_type: CommandType::Shell,
-                }
-            }
+    if pid == std::process::id() {
+        return CommandResult::new(
+            false,
+            String::new(),
+            Some("Refusing to kill the client's own process, use Shutdown instead".to_string()),
+            CommandType::Internal,
+        );
+    }
+    
+    match send_kill(pid, force).await {
+        Ok(()) => {
+            info!("Sent {} to process {}", if force { "kill" } else { "terminate" }, pid);
+            CommandResult::new(
+                true,
+                format!("Signal delivered to process {}", pid),
+                None,
+                CommandType::Internal,
+            )
         },
         Err(e) => {
-            CommandResult {
-                success: false,
-                output: String::new(),
-                error: Some(format!("Failed to execute command: {}", e)),
-                command_type: CommandType::Shell,
-            }
+            CommandResult::new(
+                false,
+                String::new(),
+                Some(format!("Failed to kill process {}: {}", pid, e)),
+                CommandType::Internal,
+            )
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(unix)]
+async fn send_kill(pid: u32, force: bool) -> Result<(), String> {
+    let signal = if force { libc::SIGKILL } else { libc::SIGTERM };
+    let pid = libc::pid_t::try_from(pid).map_err(|_| "PID out of range".to_string())?;
+    
+    // SAFETY: kill() has no memory safety requirements, it only takes integers
+    if unsafe { libc::kill(pid, signal) } == 0 {
+        return Ok(());
+    }
+    
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ESRCH) => Err("no such process".to_string()),
+        Some(libc::EPERM) => Err("permission denied".to_string()),
+        _ => Err(err.to_string()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn send_kill(pid: u32, force: bool) -> Result<(), String> {
+    let pid_arg = pid.to_string();
+    let mut args = vec!["/PID", pid_arg.as_str()];
+    if force {
+        args.push("/F");
+    }
+    
+    let output = AsyncProcessCommand::new("taskkill")
+        .args(&args)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run taskkill: {}", e))?;
+    
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// List usage of every mounted filesystem
+async fn get_disk_usage() -> CommandResult {
+    // Listing stats every mount, which can block on an unreachable network share,
+    // so do it off the runtime and give up on it after a while
+    let listing = tokio::task::spawn_blocking(|| {
+        let disks = Disks::new_with_refreshed_list_specifics(DiskRefreshKind::nothing().with_storage());
+        disks.list().iter()
+            .map(|disk| {
+                let total_bytes = disk.total_space();
+                let available_bytes = disk.available_space();
+                let used_percent = if total_bytes == 0 {
+                    0.0
+                } else {
+                    (total_bytes - available_bytes.min(total_bytes)) as f64 * 100.0 / total_bytes as f64
+                };
+                DiskInfo {
+                    mount_point: disk.mount_point().display().to_string(),
+                    file_system: disk.file_system().to_string_lossy().to_string(),
+                    total_bytes,
+                    available_bytes,
+                    used_percent,
+                    removable: disk.is_removable(),
+                }
+            })
+            .collect::<Vec<_>>()
+    });
+    
+    let error = match tokio::time::timeout(DISK_USAGE_TIMEOUT, listing).await {
+        Ok(Ok(disks)) => {
+            return match to_string(&disks) {
+                Ok(json) => CommandResult::new(true, json, None, CommandType::Internal),
+                Err(e) => CommandResult::new(
+                    false,
+                    String::new(),
+                    Some(format!("Failed to serialize disk usage: {}", e)),
+                    CommandType::Internal,
+                ),
+            };
+        },
+        Ok(Err(e)) => format!("Failed to list disks: {}", e),
+        Err(_) => format!(
+            "Listing disks took longer than {}s, a network mount may be unreachable",
+            DISK_USAGE_TIMEOUT.as_secs()
+        ),
+    };
+    warn!("{}", error);
+    CommandResult::new(false, String::new(), Some(error), CommandType::Internal)
+}
+
+/// List the machine's open sockets and their owners. Anything hidden for lack of
+/// privileges is explained on stderr, the listing still succeeds.
+async fn list_connections() -> CommandResult {
+    let error = match tokio::task::spawn_blocking(netstat::list_connections).await {
+        Ok(Ok(listing)) => {
+            return match to_string(&listing.connections) {
+                Ok(json) => CommandResult::new(true, json, listing.note, CommandType::Internal),
+                Err(e) => CommandResult::new(
+                    false,
+                    String::new(),
+                    Some(format!("Failed to serialize connections: {}", e)),
+                    CommandType::Internal,
+                ),
+            };
+        },
+        Ok(Err(e)) => format!("Failed to list connections: {}", e),
+        Err(e) => format!("Failed to list connections: {}", e),
+    };
+    warn!("{}", error);
+    CommandResult::new(false, String::new(), Some(error), CommandType::Internal)
+}
+
+/// List the installed packages whose names contain `filter`, returning `limit` of them
+/// from `offset` on. Gives up after `timeout`, killing the package manager.
+async fn list_packages(filter: Option<&str>, offset: usize, limit: usize, timeout: Duration) -> CommandResult {
+    let failed = |error: String| {
+        warn!("{}", error);
+        CommandResult::new(false, String::new(), Some(error), CommandType::Internal)
+    };
+    
+    let listing = match tokio::time::timeout(timeout, packages::list_packages()).await {
+        Ok(Ok(listing)) => listing,
+        Ok(Err(e)) => return failed(e),
+        Err(_) => return failed(format!("Listing packages took longer than {}s", timeout.as_secs())),
+    };
+    let filter = filter.map(str::to_lowercase);
+    let matching: Vec<_> = listing.packages.into_iter()
+        .filter(|package| filter.as_ref().is_none_or(|filter| package.name.to_lowercase().contains(filter)))
+        .collect();
+    let list = PackageList {
+        manager: listing.manager,
+        total: matching.len(),
+        offset,
+        packages: matching.into_iter().skip(offset).take(limit).collect(),
+    };
+    match to_string(&list) {
+        Ok(json) => CommandResult::new(true, json, None, CommandType::Internal),
+        Err(e) => failed(format!("Failed to serialize packages: {}", e)),
+    }
+}
+
+/// Collect the environment variables in `keys`, or all of them, hiding the values
+/// of those whose names match a `redact` pattern
+fn get_env(keys: Option<&[String]>, redact: &[String]) -> CommandResult {
+    let vars: BTreeMap<String, String> = match keys {
+        Some(keys) => keys.iter()
+            .filter_map(|key| std::env::var(key).ok().map(|value| (key.clone(), value)))
+            .collect(),
+        None => std::env::vars_os()
+            .map(|(key, value)| (key.to_string_lossy().to_string(), value.to_string_lossy().to_string()))
+            .collect(),
+    };
+    let vars: BTreeMap<String, String> = vars.into_iter()
+        .map(|(key, value)| {
+            if redact.iter().any(|pattern| wildcard_match(pattern, &key)) {
+                (key, "<redacted>".to_string())
+            } else {
+                (key, value)
+            }
+        })
+        .collect();
+    
+    match to_string(&vars) {
+        Ok(json) => CommandResult::new(true, json, None, CommandType::Internal),
+        Err(e) => CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("Failed to serialize environment: {}", e)),
+            CommandType::Internal,
+        ),
+    }
+}
+
+/// What to do to the client machine
+#[derive(Debug, Clone, Copy)]
+enum PowerAction {
+    /// Reboot after this many seconds
+    Reboot(u32),
+    PowerOff,
+}
+
+/// Hand `action` to the platform's shutdown command, which schedules it and returns.
+/// The result for an immediate action may not reach the server before the network goes down.
+async fn power_command(action: PowerAction, enabled: bool) -> CommandResult {
+    if !enabled {
+        return CommandResult::new(
+            false,
+            String::new(),
+            Some("Reboot and power off are disabled on this client".to_string()),
+            CommandType::Internal,
+        );
+    }
+    
+    let (description, args) = shutdown_args(action);
+    warn!("Scheduling {} at the server's request", description);
+    let output = match AsyncProcessCommand::new("shutdown").args(&args).output().await {
+        Ok(output) => output,
+        Err(e) => {
+            return CommandResult::new(
+                false,
+                String::new(),
+                Some(format!("Failed to run shutdown: {}", e)),
+                CommandType::Internal,
+            );
+        }
+    };
+    
+    if output.status.success() {
+        CommandResult::new(
+            true,
+            format!("Scheduled {}", description),
+            None,
+            CommandType::Internal,
+        )
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!("Failed to schedule {}: {}", description, stderr.trim());
+        CommandResult::new(
+            false,
+            String::new(),
+            Some(format!(
+                "Failed to schedule {} (the client may need to run with administrator/root privileges): {}",
+                description,
+                stderr.trim(),
+            )),
+            CommandType::Internal,
+        )
+    }
+}
+
+/// Arguments for `shutdown` and a description of what it will do. Unix `shutdown`
+/// only takes whole minutes, so reboot delays are rounded up.
+#[cfg(unix)]
+fn shutdown_args(action: PowerAction) -> (String, Vec<String>) {
+    match action {
+        PowerAction::Reboot(0) => ("reboot now".to_string(), vec!["-r".to_string(), "now".to_string()]),
+        PowerAction::Reboot(delay_secs) => {
+            let minutes = delay_secs.div_ceil(60);
+            (format!("reboot in {} minute(s)", minutes), vec!["-r".to_string(), format!("+{}", minutes)])
+        },
+        PowerAction::PowerOff => {
+            // -h only halts on some Linux init systems, -P always powers off there
+            let flag = if cfg!(target_os = "linux") { "-P" } else { "-h" };
+            ("power off now".to_string(), vec![flag.to_string(), "now".to_string()])
+        },
+    }
+}
+
+#[cfg(not(unix))]
+fn shutdown_args(action: PowerAction) -> (String, Vec<String>) {
+    match action {
+        PowerAction::Reboot(delay_secs) => (
+            format!("reboot in {} seconds", delay_secs),
+            vec!["/r".to_string(), "/t".to_string(), delay_secs.to_string()],
+        ),
+        PowerAction::PowerOff => (
+            "power off now".to_string(),
+            vec!["/s".to_string(), "/t".to_string(), "0".to_string()],
+        ),
+    }
+}
+
+#[cfg(unix)]
+async fn set_file_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await
+}
+
+#[cfg(not(unix))]
+async fn set_file_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    warn!("Ignoring file mode, not supported on this platform");
+    Ok(())
+}
+
+/// Resolve a path requested by the server, refusing anything outside `file_root` when one is set
+fn resolve_file_path(path: &str, file_root: Option<&Path>) -> Result<PathBuf, String> {
+    let requested = PathBuf::from(path);
+    let Some(root) = file_root else {
+        return Ok(requested);
+    };
+    
+    let root = root.canonicalize()
+        .map_err(|e| format!("File root {} is not accessible: {}", root.display(), e))?;
+    let target = if requested.is_absolute() { requested } else { root.join(requested) };
+    
+    // Follow symlinks for existing files so a link can't point outside the root
+    if let Ok(existing) = target.canonicalize() {
+        if !existing.starts_with(&root) {
+            return Err(format!("Path {} is outside the allowed directory {}", path, root.display()));
+        }
+        return Ok(existing);
+    }
+    
+    // The file itself may not exist yet, so check where its parent directory really is
+    let parent = target.parent()
+        .ok_or_else(|| format!("Invalid path: {}", path))?
+        .canonicalize()
+        .map_err(|e| format!("Directory for {} is not accessible: {}", path, e))?;
+    let file_name = target.file_name()
+        .ok_or_else(|| format!("Invalid path: {}", path))?;
+    
+    if !parent.starts_with(&root) {
+        return Err(format!("Path {} is outside the allowed directory {}", path, root.display()));
+    }
+    
+    Ok(parent.join(file_name))
+}
+
+fn get_system_info(tags: &HashMap<String, String>) -> SystemInfo {
+    let hostname = whoami::fallible::hostname().unwrap_or_else(|_| "unknown-host".to_string());
+    let username = whoami::username();
+    let os_type = get_os_type();
+    let os_version = get_os_version();
+    
+    let mut sys = System::new();
+    sys.refresh_memory();
+    sys.refresh_cpu_list(CpuRefreshKind::nothing());
+    
+    // sysinfo reports 0 where uptime isn't available
+    let uptime_secs = match System::uptime() {
+        0 => None,
+        secs => Some(secs),
+    };
+    
+    SystemInfo {
+        hostname,
+        username,
+        os_type,
+        os_version,
+        cpu_count: sys.cpus().len(),
+        total_memory_bytes: sys.total_memory(),
+        arch: std::env::consts::ARCH.to_string(),
+        uptime_secs,
+        tags: tags.clone(),
+        network: get_network_interfaces(),
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Command::ALL_NAMES.iter().map(|name| name.to_string()).collect(),
+    }
+}
+
+/// Measure CPU use over `LOAD_SAMPLE_INTERVAL` and read memory and swap use
+async fn get_load() -> CommandResult {
+    let mut sys = System::new();
+    sys.refresh_cpu_usage();
+    sleep(LOAD_SAMPLE_INTERVAL).await;
+    sys.refresh_cpu_usage();
+    sys.refresh_memory();
+    
+    let load_average = if cfg!(windows) {
+        None
+    } else {
+        let load = System::load_average();
+        Some([load.one, load.five, load.fifteen])
+    };
+    let load = LoadInfo {
+        cpu_percent: sys.global_cpu_usage(),
+        core_percents: sys.cpus().iter().map(|cpu| cpu.cpu_usage()).collect(),
+        load_average,
+        total_memory_bytes: sys.total_memory(),
+        used_memory_bytes: sys.used_memory(),
+        free_memory_bytes: sys.free_memory(),
+        available_memory_bytes: sys.available_memory(),
+        total_swap_bytes: sys.total_swap(),
+        used_swap_bytes: sys.used_swap(),
+    };
+    match to_string(&load) {
+        Ok(json) => CommandResult::new(true, json, None, CommandType::Internal),
+        Err(e) => CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("Failed to serialize load: {}", e)),
+            CommandType::Internal,
+        ),
+    }
+}
+
+/// Read the clock and the time zone it is shown in
+fn get_time() -> CommandResult {
+    let now = chrono::Local::now();
+    let time = ClientTime {
+        unix_millis: now.timestamp_millis().max(0) as u64,
+        utc: now.to_utc().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        local: now.to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+        timezone: iana_time_zone::get_timezone().ok(),
+        utc_offset_secs: now.offset().local_minus_utc(),
+    };
+    match to_string(&time) {
+        Ok(json) => CommandResult::new(true, json, None, CommandType::Internal),
+        Err(e) => CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("Failed to serialize time: {}", e)),
+            CommandType::Internal,
+        ),
+    }
+}
+
+/// Resolve `host` and connect to each of its addresses on `port` until one accepts,
+/// all within `timeout`
+async fn tcp_probe(host: &str, port: u16, timeout: Duration) -> CommandResult {
+    let started = Instant::now();
+    let mut address = None;
+    let attempt = tokio::time::timeout(timeout, async {
+        let mut last_error = None;
+        for addr in tokio::net::lookup_host((host, port)).await? {
+            address = Some(addr.to_string());
+            match tokio::net::TcpStream::connect(addr).await {
+                Ok(_) => return Ok(()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "host has no addresses")))
+    }).await;
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    
+    let (outcome, error) = match attempt {
+        Ok(Ok(())) => (ProbeOutcome::Connected, None),
+        // Names that don't resolve are a mistake in the request rather than an answer
+        Ok(Err(e)) if address.is_none() => {
+            return CommandResult::new(
+                false,
+                String::new(),
+                Some(format!("Could not resolve {}: {}", host, e)),
+                CommandType::Internal,
+            );
+        },
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => (ProbeOutcome::Refused, Some(e.to_string())),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::TimedOut => (ProbeOutcome::TimedOut, Some(e.to_string())),
+        Ok(Err(e)) => (ProbeOutcome::Failed, Some(e.to_string())),
+        Err(_) => (ProbeOutcome::TimedOut, None),
+    };
+    debug!("Probe of {}:{}: {} after {:.1}ms", host, port, outcome, latency_ms);
+    
+    let probe = ProbeResult { host: host.to_string(), port, address, outcome, latency_ms, error };
+    match to_string(&probe) {
+        Ok(json) => CommandResult::new(true, json, None, CommandType::Internal),
+        Err(e) => CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("Failed to serialize probe result: {}", e)),
+            CommandType::Internal,
+        ),
+    }
+}
+
+/// List network interfaces with their addresses, or nothing if the platform won't say
+fn get_network_interfaces() -> Vec<NetworkInterface> {
+    use network_interface::{Addr, NetworkInterfaceConfig};
+    
+    let found = match network_interface::NetworkInterface::show() {
+        Ok(found) => found,
+        Err(e) => {
+            warn!("Failed to list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    
+    // Some platforms report an entry per address, so merge them by name
+    let mut interfaces: Vec<NetworkInterface> = Vec::new();
+    for found in found {
+        let index = match interfaces.iter().position(|interface| interface.name == found.name) {
+            Some(index) => index,
+            None => {
+                interfaces.push(NetworkInterface {
+                    name: found.name.clone(),
+                    mac: None,
+                    ipv4: Vec::new(),
+                    ipv6: Vec::new(),
+                    loopback: false,
+                });
+                interfaces.len() - 1
+            }
+        };
+        let interface = &mut interfaces[index];
+        if interface.mac.is_none() {
+            interface.mac = found.mac_addr.filter(|mac| !mac.is_empty());
+        }
+        interface.loopback |= found.internal;
+        for addr in found.addr {
+            match addr {
+                Addr::V4(v4) => interface.ipv4.push(v4.ip),
+                Addr::V6(v6) => interface.ipv6.push(v6.ip),
+            }
+        }
+    }
+    interfaces
+}
+
+fn get_os_version() -> Option<String> {
+    if cfg!(target_os = "windows") {
+        if let Some(version) = windows_registry_version() {
+            return Some(version);
+        }
+        // Fall back to `ver`, which only gives a build number in the system's language
+        let output = ProcessCommand::new("cmd")
+            .args(["/c", "ver"])
+            .output();
+            
+        match output {
+            Ok(out) => {
+                let version = String::from_utf8_lossy(&out.stdout);
+                Some(version.trim().to_string())
+            },
+            Err(_) => None,
+        }
+    } else if cfg!(target_os = "linux") {
+        // Linux-specific implementation
+        let output = ProcessCommand::new("cat")
+            .arg("/etc/os-release")
+            .output();
+            
+        match output {
+            Ok(out) => {
+                let release_info = String::from_utf8_lossy(&out.stdout);
+                for line in release_info.lines() {
+                    if line.starts_with("PRETTY_NAME=") {
+                        return Some(line.trim_start_matches("PRETTY_NAME=")
+                            .trim_matches('"')
+                            .to_string());
+                    }
+                }
+                None
+            },
+            Err(_) => None,
+        }
+    } else {
+        // Fallback for other platforms
+        None
+    }
+}
+
+/// Windows version from the registry, e.g. "Windows 11 Pro 23H2"
+#[cfg(windows)]
+fn windows_registry_version() -> Option<String> {
+    const KEY: &str = r"SOFTWARE\Microsoft\Windows NT\CurrentVersion";
+    let product_name = read_registry_string(KEY, "ProductName")?;
+    // Windows 10 before 20H2 only has ReleaseId
+    let display_version = read_registry_string(KEY, "DisplayVersion")
+        .or_else(|| read_registry_string(KEY, "ReleaseId"));
+    let build = read_registry_string(KEY, "CurrentBuildNumber");
+    Some(format_windows_version(&product_name, display_version.as_deref(), build.as_deref()))
+}
+
+#[cfg(not(windows))]
+fn windows_registry_version() -> Option<String> {
+    None
+}
+
+/// Read a string value under `HKEY_LOCAL_MACHINE\{subkey}`
+#[cfg(windows)]
+fn read_registry_string(subkey: &str, value: &str) -> Option<String> {
+    use windows_sys::Win32::Foundation::ERROR_SUCCESS;
+    use windows_sys::Win32::System::Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_SZ};
+    
+    let subkey: Vec<u16> = subkey.encode_utf16().chain(Some(0)).collect();
+    let value: Vec<u16> = value.encode_utf16().chain(Some(0)).collect();
+    let mut buf = [0u16; 256];
+    let mut size = std::mem::size_of_val(&buf) as u32;
+    // SAFETY: both names are NUL-terminated and `size` is the size of `buf` in bytes
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            subkey.as_ptr(),
+            value.as_ptr(),
+            RRF_RT_REG_SZ,
+            std::ptr::null_mut(),
+            buf.as_mut_ptr().cast(),
+            &mut size,
+        )
+    };
+    if status != ERROR_SUCCESS {
+        return None;
+    }
+    // `size` counts the terminating NUL
+    let len = (size as usize / 2).saturating_sub(1);
+    Some(String::from_utf16_lossy(&buf[..len]))
+}
+
+/// Combine the registry's ProductName, DisplayVersion, and CurrentBuildNumber into
+/// e.g. "Windows 11 Pro 23H2". Windows 11 still calls itself "Windows 10" in
+/// ProductName, so it is told apart by its build number, 22000 and up.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn format_windows_version(product_name: &str, display_version: Option<&str>, build: Option<&str>) -> String {
+    let mut version = product_name.trim().to_string();
+    let build = build.and_then(|build| build.trim().parse::<u32>().ok());
+    if build.is_some_and(|build| build >= 22000) {
+        version = version.replacen("Windows 10", "Windows 11", 1);
+    }
+    if let Some(display_version) = display_version.map(str::trim).filter(|v| !v.is_empty()) {
+        version.push(' ');
+        version.push_str(display_version);
+    }
+    version
+}
+
+/// Settings for a `SupportClient`. Anything not set falls back to the built-in default.
+#[derive(Clone, Default)]
+pub struct SupportClientBuilder {
+    nats_url: Option<String>,
+    subject_prefix: Option<String>,
+    subjects: Option<SubjectLayout>,
+    client_id: Option<String>,
+    command_timeout: Option<Duration>,
+    file_root: Option<PathBuf>,
+    max_download_bytes: Option<u64>,
+    max_output_bytes: Option<u64>,
+    codec: Option<Codec>,
+    heartbeat_interval: Option<Duration>,
+    connection: Option<ConnectionOptions>,
+    credentials: Option<PathBuf>,
+    token: Option<String>,
+    user_and_password: Option<(String, String)>,
+    tls: Option<TlsOptions>,
+    policy: Option<CommandPolicy>,
+    tags: Option<HashMap<String, String>>,
+    jetstream: bool,
+    power_commands: Option<bool>,
+    redact_env: Option<Vec<String>>,
+    retry: Option<RetryConfig>,
+    rate_limit: Option<f64>,
+    max_concurrent_commands: Option<usize>,
+    state_dir: Option<PathBuf>,
+    shell: Option<Shell>,
+    handlers: HashMap<String, Arc<dyn CommandHandler>>,
+    log_file: Option<PathBuf>,
+    windows_service: bool,
+}
+
+impl SupportClientBuilder {
+    /// NATS server to connect to [default: `DEFAULT_NATS_URL`]
+    pub fn nats_url(mut self, url: impl Into<String>) -> Self {
+        self.nats_url = Some(url.into());
+        self
+    }
+    
+    /// Prefix of every subject this client uses, which must be one the server serves
+    /// [default: `DEFAULT_SUBJECT_PREFIX`]
+    pub fn subject_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.subject_prefix = Some(prefix.into());
+        self
+    }
+    
+    /// Subjects to talk to the server on, which have to match the server's
+    /// [default: `SubjectLayout::default()`]
+    pub fn subject_layout(mut self, layout: SubjectLayout) -> Self {
+        self.subjects = Some(layout);
+        self
+    }
+    
+    /// Id to register under [default: `<user>-<hostname>`]
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+    
+    /// Kill shell commands running longer than this [default: `DEFAULT_COMMAND_TIMEOUT_SECS`]
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+    
+    /// Only allow file transfers under this directory
+    pub fn file_root(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file_root = Some(path.into());
+        self
+    }
+    
+    /// Refuse to send back larger files [default: `DEFAULT_MAX_DOWNLOAD_BYTES`]
+    pub fn max_download_bytes(mut self, bytes: u64) -> Self {
+        self.max_download_bytes = Some(bytes);
+        self
+    }
+    
+    /// Cut off stdout and stderr of shell commands after this many bytes each
+    /// [default: `DEFAULT_MAX_OUTPUT_BYTES`]
+    pub fn max_output_bytes(mut self, bytes: u64) -> Self {
+        self.max_output_bytes = Some(bytes);
+        self
+    }
+    
+    /// Message encoding and encryption, which must match the server's
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+    
+    /// How often to send heartbeats, which must match the server's interval
+    /// [default: `DEFAULT_HEARTBEAT_SECS`]
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+    
+    /// Reconnect and ping tuning for the NATS connection
+    pub fn connection(mut self, options: ConnectionOptions) -> Self {
+        self.connection = Some(options);
+        self
+    }
+    
+    /// Authenticate to NATS with a credentials (`.creds`) file
+    pub fn credentials(mut self, path: impl Into<PathBuf>) -> Self {
+        self.credentials = Some(path.into());
+        self
+    }
+    
+    /// Authenticate to NATS with a token
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+    
+    /// Authenticate to NATS with a user name and password
+    pub fn user_and_password(mut self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.user_and_password = Some((user.into(), password.into()));
+        self
+    }
+    
+    /// Require TLS for the NATS connection
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+    
+    /// Rules restricting which shell commands are run [default: allow everything]
+    pub fn policy(mut self, policy: CommandPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+    
+    /// Labels reported to the server for grouping clients
+    pub fn tags(mut self, tags: HashMap<String, String>) -> Self {
+        self.tags = Some(tags);
+        self
+    }
+    
+    /// Receive commands through a durable JetStream consumer; the server must be
+    /// started the same way
+    pub fn jetstream(mut self, enabled: bool) -> Self {
+        self.jetstream = enabled;
+        self
+    }
+    
+    /// Whether to honour requests to reboot or power off the machine [default: true]
+    pub fn power_commands(mut self, enabled: bool) -> Self {
+        self.power_commands = Some(enabled);
+        self
+    }
+    
+    /// Patterns of environment variables whose values are hidden, with `*` as a
+    /// wildcard [default: `DEFAULT_REDACTED_ENV`]
+    pub fn redact_env(mut self, patterns: Vec<String>) -> Self {
+        self.redact_env = Some(patterns);
+        self
+    }
+    
+    /// How registering and resubscribing are retried [default: `RetryConfig::default()`]
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+    
+    /// Refuse commands arriving faster than `per_sec` a second on average, in bursts
+    /// of up to `per_sec` commands; 0 turns the limit off [default: `DEFAULT_RATE_LIMIT`]
+    pub fn rate_limit(mut self, per_sec: f64) -> Self {
+        self.rate_limit = Some(per_sec);
+        self
+    }
+    
+    /// Run at most `max` shell commands and file transfers at once; more wait for one
+    /// to finish [default: `DEFAULT_MAX_CONCURRENT_COMMANDS`]
+    pub fn max_concurrent_commands(mut self, max: usize) -> Self {
+        self.max_concurrent_commands = Some(max);
+        self
+    }
+    
+    /// Remember the client id in this directory, and register under the remembered one
+    /// when no id is set, so a restarted client keeps its identity [default: don't]
+    pub fn state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state_dir = Some(dir.into());
+        self
+    }
+    
+    /// Run commands that don't name a shell with this one [default: `cmd` on Windows,
+    /// `sh` elsewhere]
+    pub fn shell(mut self, shell: Shell) -> Self {
+        self.shell = Some(shell);
+        self
+    }
+    
+    /// Handle `Command::Custom` commands named `name` with `handler`, replacing any
+    /// handler registered under that name before [default: none]
+    pub fn handler(mut self, name: impl Into<String>, handler: impl CommandHandler + 'static) -> Self {
+        self.handlers.insert(name.into(), Arc::new(handler));
+        self
+    }
+    
+    /// File the client's log is written to, sent for `Command::GetLogTail` without a
+    /// path. Logging itself is set up by the caller. [default: none]
+    pub fn log_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.log_file = Some(path.into());
+        self
+    }
+    
+    /// Whether the client runs as a Windows service, which `Command::RestartAgent` can't
+    /// restart: a new process wouldn't be connected to the service manager [default: false]
+    pub fn windows_service(mut self, windows_service: bool) -> Self {
+        self.windows_service = windows_service;
+        self
+    }
+    
+    /// Connect to NATS and create the client
+    pub async fn build(self) -> Result<SupportClient> {
+        let url = self.nats_url.as_deref().unwrap_or(DEFAULT_NATS_URL);
+        let prefix = self.subject_prefix.unwrap_or_else(|| DEFAULT_SUBJECT_PREFIX.to_string());
+        let subjects = self.subjects.unwrap_or_default();
+        subjects.validate()?;
+        let id = match (self.client_id, &self.state_dir) {
+            (Some(id), _) => id,
+            (None, Some(dir)) => load_client_id(dir)?.unwrap_or_else(get_client_id),
+            (None, None) => get_client_id(),
+        };
+        validate_client_id(&id)?;
+        if let Some(dir) = &self.state_dir {
+            // The client works without it, it just won't be recognized after a restart
+            if let Err(e) = save_client_id(dir, &id) {
+                warn!("Failed to save client id to {}: {}", dir.join(CLIENT_ID_FILE).display(), e);
+            }
+        }
+        let command_timeout = self.command_timeout
+            .unwrap_or(Duration::from_secs(DEFAULT_COMMAND_TIMEOUT_SECS));
+        let heartbeat_interval = self.heartbeat_interval
+            .unwrap_or(Duration::from_secs(DEFAULT_HEARTBEAT_SECS));
+        if heartbeat_interval.is_zero() {
+            return Err(RsNatsError::ConfigError("Heartbeat interval must be greater than zero".to_string()).into());
+        }
+        let retry = self.retry.unwrap_or_default();
+        retry.validate()?;
+        let rate_limit = self.rate_limit.unwrap_or(DEFAULT_RATE_LIMIT);
+        if !(rate_limit >= 0.0 && rate_limit.is_finite()) {
+            return Err(RsNatsError::ConfigError(format!("Rate limit must be a non-negative number, got {}", rate_limit)).into());
+        }
+        let max_concurrent = self.max_concurrent_commands.unwrap_or(DEFAULT_MAX_CONCURRENT_COMMANDS as usize);
+        if max_concurrent == 0 || max_concurrent > Semaphore::MAX_PERMITS {
+            return Err(RsNatsError::ConfigError(format!("Invalid maximum of concurrent commands: {}", max_concurrent)).into());
+        }
+        
+        let reconnected = Arc::new(Notify::new());
+        let mut connection = self.connection.unwrap_or_default();
+        connection.reconnected = Some(reconnected.clone());
+        if self.credentials.is_some() {
+            connection.credentials = self.credentials;
+        }
+        if self.token.is_some() {
+            connection.token = self.token;
+        }
+        if self.user_and_password.is_some() {
+            connection.user_and_password = self.user_and_password;
+        }
+        if self.tls.is_some() {
+            connection.tls = self.tls;
+        }
+        connection.name.get_or_insert_with(|| format!("rs-nats-client {}", id));
+        
+        info!("Connecting to NATS server at {}", redact_url(url));
+        let nats_client = connect(url, &connection).await?;
+        
+        Ok(SupportClient {
+            nats_client,
+            nats_url: redact_url(url),
+            started: Instant::now(),
+            subject_prefix: prefix,
+            subjects: Arc::new(subjects),
+            client_id: id,
+            command_timeout,
+            file_root: self.file_root,
+            max_download_bytes: self.max_download_bytes.unwrap_or(DEFAULT_MAX_DOWNLOAD_BYTES),
+            max_output_bytes: self.max_output_bytes.unwrap_or(DEFAULT_MAX_OUTPUT_BYTES),
+            codec: self.codec.unwrap_or_default(),
+            heartbeat_interval,
+            policy: self.policy.unwrap_or_default(),
+            tags: self.tags.unwrap_or_default(),
+            reconnected,
+            jetstream: self.jetstream,
+            power_commands: self.power_commands.unwrap_or(true),
+            redact_env: self.redact_env.unwrap_or_else(|| {
+                DEFAULT_REDACTED_ENV.iter().map(|pattern| pattern.to_string()).collect()
+            }),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            scheduled: Arc::new(Mutex::new(HashMap::new())),
+            retry,
+            rate_limit,
+            slots: CommandSlots {
+                slots: Arc::new(Semaphore::new(max_concurrent)),
+                max: max_concurrent,
+                waiting: Arc::new(AtomicUsize::new(0)),
+            },
+            state_dir: self.state_dir,
+            shell: self.shell.unwrap_or_default(),
+            handlers: Arc::new(self.handlers),
+            log_file: self.log_file,
+            windows_service: self.windows_service,
+        })
+    }
+}
+
+/// The client id saved in `state_dir` by an earlier run, if there is one
+fn load_client_id(state_dir: &Path) -> Result<Option<String>, RsNatsError> {
+    let path = state_dir.join(CLIENT_ID_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => {
+            let id = contents.trim();
+            if id.is_empty() {
+                return Ok(None);
+            }
+            info!("Resuming as {} from {}", id, path.display());
+            Ok(Some(id.to_string()))
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(RsNatsError::ConfigError(format!("Failed to read {}: {}", path.display(), e))),
+    }
+}
+
+/// Save the client id to `state_dir`, via a temporary file so a crash can't leave it half written
+fn save_client_id(state_dir: &Path, id: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(state_dir)?;
+    let path = state_dir.join(CLIENT_ID_FILE);
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, format!("{}\n", id))?;
+    std::fs::rename(&tmp_path, &path)
+}
+
+/// Token bucket allowing `rate` commands a second on average, in bursts of up to
+/// `rate` (at least one)
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        RateLimiter { rate, tokens: rate.max(1.0), refilled: Instant::now() }
+    }
+    
+    /// Take a token if one is left
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate.max(1.0));
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Answer a command that arrived over the rate limit without running it
+async fn refuse_rate_limited(context: Arc<CommandContext>, request: CommandRequest, reply: Option<Subject>, acker: Option<Acker>) {
+    warn!("Rate limit hit, refusing command {}: {}", request.request_id, request.command);
+    // Redelivering it would only add to the flood
+    ack(acker.as_ref()).await;
+    
+    let mut result = CommandResult::new(
+        false,
+        String::new(),
+        Some("rate limit exceeded, command not run".to_string()),
+        CommandType::Internal,
+    );
+    result.request_id = Some(request.request_id);
+    publish_result(&context.nats, &context.subjects, &context.prefix, context.codec, &context.client_id, reply, &result).await;
+}
+
+/// The part of a `CommandRequest` that is left to read when its command can't be parsed
+#[derive(Deserialize)]
+struct RequestId {
+    request_id: String,
+}
+
+/// Tell the server a command couldn't be parsed, e.g. because it is newer than this
+/// client, rather than leave it waiting for a result. The result carries the request's
+/// id if that much could be read.
+async fn refuse_malformed(context: Arc<CommandContext>, payload: Bytes, error: RsNatsError, reply: Option<Subject>, acker: Option<Acker>) {
+    let request_id = context.codec.decode::<RequestId>(&payload).ok().map(|id| id.request_id);
+    error!("Failed to parse command {}: {}", request_id.as_deref().unwrap_or("without id"), error);
+    // Redelivering it won't help
+    ack(acker.as_ref()).await;
+    
+    let mut result = CommandResult::new(
+        false,
+        String::new(),
+        Some(format!("could not parse command: {}", error)),
+        CommandType::Internal,
+    );
+    result.request_id = request_id;
+    publish_result(&context.nats, &context.subjects, &context.prefix, context.codec, &context.client_id, reply, &result).await;
+}
+
+/// Wait for a free slot to run a command in, reporting how many are waiting
+async fn acquire_slot(slots: &CommandSlots) -> OwnedSemaphorePermit {
+    if let Ok(permit) = slots.slots.clone().try_acquire_owned() {
+        return permit;
+    }
+    let waiting = slots.waiting.fetch_add(1, Ordering::SeqCst) + 1;
+    if waiting.is_multiple_of(QUEUE_WARN_DEPTH) {
+        warn!("{} commands waiting for one of {} running to finish", waiting, slots.max);
+    } else {
+        debug!("{} commands waiting for one of {} running to finish", waiting, slots.max);
+    }
+    let permit = slots.slots.clone().acquire_owned().await
+        .expect("command slots are never closed");
+    slots.waiting.fetch_sub(1, Ordering::SeqCst);
+    permit
+}
+
+/// Why the client stops running
+#[derive(Debug, Clone, Copy)]
+enum Stop {
+    Shutdown,
+    Restart,
+}
+
+/// Replace this process with a new one running the same executable with the same
+/// arguments. On Unix the process is replaced in place, keeping its pid, so a service
+/// manager sees no restart; elsewhere a new process is started and this one exits.
+fn restart_agent() -> Result<()> {
+    let mut executable = std::env::current_exe()?;
+    // After an upgrade replaced the binary, Linux reports the old one as "<path> (deleted)"
+    if let Some(path) = executable.to_str().and_then(|path| path.strip_suffix(" (deleted)")) {
+        executable = PathBuf::from(path);
+    }
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    info!("Restarting {}", executable.display());
+    
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        
+        // Only returns if it failed
+        let e = ProcessCommand::new(&executable).args(&args).exec();
+        Err(anyhow::anyhow!("Failed to restart {}: {}", executable.display(), e))
+    }
+    
+    #[cfg(not(unix))]
+    {
+        ProcessCommand::new(&executable).args(&args).spawn()
+            .map_err(|e| anyhow::anyhow!("Failed to restart {}: {}", executable.display(), e))?;
+        std::process::exit(0);
+    }
+}
+
+/// What a command handler needs from the client it runs in
+struct CommandContext {
+    nats: Client,
+    client_id: String,
+    prefix: String,
+    subjects: Arc<SubjectLayout>,
+    command_timeout: Duration,
+    file_root: Option<PathBuf>,
+    max_download_bytes: u64,
+    max_output_bytes: u64,
+    codec: Codec,
+    policy: CommandPolicy,
+    tags: HashMap<String, String>,
+    power_commands: bool,
+    redact_env: Vec<String>,
+    running: RunningCommands,
+    scheduled: ScheduledJobs,
+    slots: CommandSlots,
+    /// For commands that don't name one
+    shell: Shell,
+    handlers: CommandHandlers,
+    log_file: Option<PathBuf>,
+    /// Restarting is left to the service manager
+    windows_service: bool,
+    agent_info: AgentInfo,
+    started: Instant,
+    shutdown_tx: mpsc::Sender<Stop>,
+}
+
+/// Run one command and send its result back to the server
+async fn handle_request(context: Arc<CommandContext>, request: CommandRequest, reply: Option<Subject>, acker: Option<Acker>) {
+    let CommandContext {
+        ref nats,
+        ref client_id,
+        ref prefix,
+        ref subjects,
+        command_timeout,
+        ref file_root,
+        max_download_bytes,
+        max_output_bytes,
+        codec,
+        ref policy,
+        ref tags,
+        power_commands,
+        ref redact_env,
+        ref running,
+        ref scheduled,
+        ref slots,
+        shell: default_shell,
+        ref handlers,
+        ref log_file,
+        windows_service,
+        ref agent_info,
+        started: agent_started,
+        ref shutdown_tx,
+    } = *context;
+    
+    info!("Received command {}: {}", request.request_id, request.command);
+    publish_ack(nats, subjects, prefix, codec, client_id, &request.request_id).await;
+    
+    // A redelivered Shutdown or reboot would repeat on every restart, and a tail or
+    // shell session runs for longer than JetStream waits for an acknowledgement
+    let mut acker = acker.map(Arc::new);
+    if let Command::Shutdown | Command::RebootMachine { .. } | Command::PowerOff | Command::TailFile { .. } | Command::StartShell { .. } = request.command {
+        ack(acker.take().as_deref()).await;
+    }
+    // Nothing dedupes redeliveries, so one while the command is queued or still
+    // running would run it twice
+    let progress = acker.clone().map(ProgressReporter::start);
+    
+    // Held until the result is sent. A queued command has been acknowledged, so the
+    // server can tell it arrived.
+    let _slot = match request.command {
+        Command::Execute { .. } | Command::UploadFile { .. } | Command::DownloadFile { .. }
+            | Command::ReadFileRange { .. } | Command::GetDiskUsage | Command::ListConnections
+            | Command::Custom { .. } | Command::GetLogTail { .. } | Command::ListPackages { .. } => Some(acquire_slot(slots).await),
+        _ => None,
+    };
+    
+    let started = Instant::now();
+    let started_at = SystemTime::now();
+    let stop = match request.command {
+        Command::Shutdown => Some(Stop::Shutdown),
+        Command::RestartAgent if !windows_service => Some(Stop::Restart),
+        _ => None,
+    };
+    let mut result = match request.command {
+        Command::Ping => {
+            CommandResult::new(
+                true,
+                "Pong".to_string(),
+                None,
+                CommandType::Internal,
+            )
+        },
+        Command::Execute { command, cwd, env, stream: false, stdin, shell } => {
+            let cancel = track_running(running, &request.request_id);
+            let shell = shell.unwrap_or(default_shell);
+            let result = execute_command(&command, shell, cwd.as_deref(), env.as_ref(), stdin, command_timeout, policy, max_output_bytes, None, cancel).await;
+            running.lock().unwrap().remove(&request.request_id);
+            result
+        },
+        Command::Execute { command, cwd, env, stream: true, stdin, shell } => {
+            let cancel = track_running(running, &request.request_id);
+            let result = execute_streaming(
+                nats,
+                subjects,
+                prefix,
+                codec,
+                &request.request_id,
+                &command,
+                shell.unwrap_or(default_shell),
+                cwd.as_deref(),
+                env.as_ref(),
+                stdin,
+                command_timeout,
+                policy,
+                cancel,
+            ).await;
+            running.lock().unwrap().remove(&request.request_id);
+            result
+        },
+        Command::GetSystemInfo => {
+            let sys_info = get_system_info(tags);
+            // Use serde_json to serialize the system info properly
+            match to_string(&sys_info) {
+                Ok(json) => {
+                    CommandResult::new(
+                        true,
+                        json,
+                        None,
+                        CommandType::Internal,
+                    )
+                },
+                Err(e) => {
+                    CommandResult::new(
+                        false,
+                        String::new(),
+                        Some(format!("Failed to serialize system info: {}", e)),
+                        CommandType::Internal,
+                    )
+                }
+            }
+        },
+        Command::Shutdown => {
+            info!("Received shutdown command");
+            CommandResult::new(
+                true,
+                "Client shutting down".to_string(),
+                None,
+                CommandType::Internal,
+            )
+        },
+        Command::LogEvent { level, message } => {
+            match level {
+                LogLevel::Debug => debug!("{}", message),
+                LogLevel::Info => info!("{}", message),
+                LogLevel::Warning => warn!("{}", message),
+                LogLevel::Error => error!("{}", message),
+            }
+            
+            CommandResult::new(
+                true,
+                format!("Logged: [{}] {}", level, message),
+                None,
+                CommandType::Internal,
+            )
+        },
+        Command::UploadFile { path, contents, mode } => {
+            upload_file(&path, &contents, mode, file_root.as_deref()).await
+        },
+        Command::DownloadFile { path } => {
+            download_file(&path, max_download_bytes, file_root.as_deref()).await
+        },
+        Command::ReadFileRange { path, offset, length } => {
+            read_file_range(&path, offset, length, max_download_bytes, file_root.as_deref()).await
+        },
+        Command::KillProcess { pid, force } => {
+            kill_process(pid, force).await
+        },
+        Command::SetLogLevel(level) => {
+            match logging::set_log_level(level) {
+                Ok(active) => {
+                    info!("Log level changed to {}", active);
+                    CommandResult::new(
+                        true,
+                        format!("Log level set to {}", active),
+                        None,
+                        CommandType::Internal,
+                    )
+                },
+                Err(e) => {
+                    CommandResult::new(
+                        false,
+                        String::new(),
+                        Some(format!("Failed to change log level: {}", e)),
+                        CommandType::Internal,
+                    )
+                }
+            }
+        },
+        Command::RotateLog => {
+            match logging::rotate_log_file() {
+                Ok(rotated) => {
+                    info!("Log file rotated, earlier lines are in {}", rotated.display());
+                    CommandResult::new(true, rotated.display().to_string(), None, CommandType::Internal)
+                },
+                Err(e) => CommandResult::new(false, String::new(), Some(e.to_string()), CommandType::Internal),
+            }
+        },
+        Command::RebootMachine { delay_secs } => {
+            power_command(PowerAction::Reboot(delay_secs.unwrap_or(0)), power_commands).await
+        },
+        Command::PowerOff => {
+            power_command(PowerAction::PowerOff, power_commands).await
+        },
+        Command::GetEnv { keys } => {
+            get_env(keys.as_deref(), redact_env)
+        },
+        Command::GetDiskUsage => {
+            get_disk_usage().await
+        },
+        Command::GetAgentInfo => {
+            let info = AgentInfo {
+                uptime_secs: agent_started.elapsed().as_secs(),
+                log_level: log::max_level().to_string().to_lowercase(),
+                ..agent_info.clone()
+            };
+            match to_string(&info) {
+                Ok(json) => CommandResult::new(true, json, None, CommandType::Internal),
+                Err(e) => CommandResult::new(
+                    false,
+                    String::new(),
+                    Some(format!("Failed to serialize agent info: {}", e)),
+                    CommandType::Internal,
+                ),
+            }
+        },
+        Command::ListConnections => {
+            list_connections().await
+        },
+        Command::ServiceStatus { name } => {
+            service_status(&name).await
+        },
+        Command::ListPackages { filter, offset, limit } => {
+            list_packages(filter.as_deref(), offset, limit, command_timeout).await
+        },
+        Command::GetLoad => {
+            get_load().await
+        },
+        Command::GetTime => {
+            get_time()
+        },
+        Command::Custom { name, args } => {
+            run_custom(handlers, &name, &args).await
+        },
+        Command::TcpProbe { host, port, timeout_ms } => {
+            tcp_probe(&host, port, Duration::from_millis(timeout_ms).min(command_timeout)).await
+        },
+        Command::GetLogTail { path, lines } => {
+            get_log_tail(path.as_deref(), lines, log_file.as_deref(), file_root.as_deref(), max_output_bytes).await
+        },
+        Command::CancelRequest { request_id } => {
+            cancel_request(running, &request_id)
+        },
+        Command::DryRun { command } => {
+            dry_run(&command, policy, power_commands, file_root.as_deref(), default_shell, handlers)
+        },
+        Command::ScheduleExecute { command, run_at } => {
+            schedule_job(&context, &request.request_id, command, run_at)
+        },
+        Command::ListScheduled => {
+            list_scheduled(scheduled)
+        },
+        Command::CancelScheduled { job_id } => {
+            cancel_scheduled(scheduled, &job_id)
+        },
+        Command::TailFile { path, lines } => {
+            let cancel = track_running(running, &request.request_id);
+            let publisher = ChunkPublisher::new(nats, subjects, prefix, codec, &request.request_id);
+            let result = tail_file(publisher, &path, lines, file_root.as_deref(), cancel).await;
+            running.lock().unwrap().remove(&request.request_id);
+            result
+        },
+        Command::StartShell { .. } if !policy.is_empty() => {
+            warn!("Refusing shell session {}: the command policy can't be applied to it", request.request_id);
+            CommandResult::failed(
+                CommandFailure::PermissionDenied("shell sessions are not permitted on clients with a command policy".to_string()),
+                CommandType::Internal,
+            )
+        },
+        Command::StartShell { shell, rows, cols } => {
+            let cancel = track_running(running, &request.request_id);
+            let publisher = ChunkPublisher::new(nats, subjects, prefix, codec, &request.request_id);
+            let input_subject = subjects.shell_input(prefix, &request.request_id);
+            let result = shell_session(publisher, input_subject, shell.unwrap_or(default_shell), rows, cols, cancel).await;
+            running.lock().unwrap().remove(&request.request_id);
+            result
+        },
+        Command::RestartAgent if windows_service => {
+            CommandResult::new(
+                false,
+                String::new(),
+                Some("client runs as a Windows service, restart it with the service manager".to_string()),
+                CommandType::Internal,
+            )
+        },
+        Command::RestartAgent => {
+            info!("Received restart command");
+            CommandResult::new(
+                true,
+                "Client restarting".to_string(),
+                None,
+                CommandType::Internal,
+            )
+        },
+    };
+    result.request_id = Some(request.request_id);
+    // Shell commands time just the process; everything else is timed here
+    if let CommandType::Internal = result.command_type {
+        result.duration_ms = started.elapsed().as_millis() as u64;
+    }
+    result.started_at.get_or_insert(started_at);
+    
+    publish_result(nats, subjects, prefix, codec, client_id, reply, &result).await;
+    drop(progress);
+    ack(acker.as_deref()).await;
+    
+    // Only once the result has reached the NATS server and a JetStream delivery is
+    // acknowledged, so the server hears back and a restarted client doesn't receive
+    // the command again
+    if let Some(stop) = stop {
+        match tokio::time::timeout(DEREGISTER_TIMEOUT, nats.flush()).await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => warn!("Failed to flush the result before stopping: {}", e),
+            Err(_) => warn!("Timed out flushing the result before stopping"),
+        }
+        let _ = shutdown_tx.send(stop).await;
+    }
+}
+
+/// Send a command's result back to the server, to `reply` if it sent the command as a
+/// request and to the client's response subject otherwise
+async fn publish_result(nats: &Client, subjects: &SubjectLayout, prefix: &str, codec: Codec, client_id: &str, reply: Option<Subject>, result: &CommandResult) {
+    let response_subject = match reply {
+        Some(reply) => reply.to_string(),
+        None => subjects.response(prefix, client_id),
+    };
+    let mut encoded = codec.encode(result);
+    if let Some(Err(e)) = encoded.as_ref().ok().map(|payload| check_payload_size(nats, &response_subject, payload.len())) {
+        // Tell the server why rather than leaving it waiting for a result that never comes
+        warn!("Result not sent: {}", e);
+        let mut failed = CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("result not sent: {}, lower --max-output-bytes or use --stream", e)),
+            result.command_type.clone(),
+        );
+        failed.request_id = result.request_id.clone();
+        failed.duration_ms = result.duration_ms;
+        failed.started_at = result.started_at;
+        failed.exit_code = result.exit_code;
+        encoded = codec.encode(&failed);
+    }
+    match encoded {
+        Ok(payload) => {
+            info!("Sending response to {} ({} bytes)", response_subject, payload.len());
+            let send_result = nats.publish(response_subject, payload.into()).await;
+            match send_result {
+                Ok(_) => info!("Successfully sent response"),
+                Err(e) => error!("Failed to send response: {}", e),
+            }
+        },
+        Err(e) => {
+            error!("Failed to serialize result: {}", e);
+        }
+    }
+}
+
+/// Queue a shell command to run at `run_at`. Its result is published under `job_id`
+/// once it has run, like the result of any other command.
+fn schedule_job(context: &Arc<CommandContext>, job_id: &str, command: String, run_at: u64) -> CommandResult {
+    // Refuse now rather than when nobody is watching
+    if let Err(reason) = context.policy.check(&command) {
+        warn!("Refusing to schedule '{}': {}", command, reason);
+        return CommandResult::new(false, String::new(), Some("command not permitted".to_string()), CommandType::Internal);
+    }
+    
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    let job = ScheduledJob { job_id: job_id.to_string(), command: command.clone(), run_at };
+    context.scheduled.lock().unwrap().insert(job_id.to_string(), (job, cancel_tx));
+    info!("Scheduled job {} for {}: {}", job_id, format_unix_time(run_at), command);
+    let scheduled = CommandResult::new(
+        true,
+        format!("Scheduled job {} for {}", job_id, format_unix_time(run_at)),
+        None,
+        CommandType::Internal,
+    );
+    
+    let context = context.clone();
+    let job_id = job_id.to_string();
+    tokio::spawn(async move {
+        let delay = (UNIX_EPOCH + Duration::from_secs(run_at))
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        tokio::select! {
+            _ = sleep(delay) => {},
+            _ = cancel_rx => return,
+        }
+        context.scheduled.lock().unwrap().remove(&job_id);
+        
+        let _slot = acquire_slot(&context.slots).await;
+        info!("Running scheduled job {}: {}", job_id, command);
+        let cancel = track_running(&context.running, &job_id);
+        let mut result = execute_command(
+            &command,
+            context.shell,
+            None,
+            None,
+            None,
+            context.command_timeout,
+            &context.policy,
+            context.max_output_bytes,
+            None,
+            cancel,
+        ).await;
+        context.running.lock().unwrap().remove(&job_id);
+        result.request_id = Some(job_id);
+        publish_result(&context.nats, &context.subjects, &context.prefix, context.codec, &context.client_id, None, &result).await;
+    });
+    
+    scheduled
+}
+
+fn list_scheduled(scheduled: &ScheduledJobs) -> CommandResult {
+    let mut jobs: Vec<ScheduledJob> = scheduled.lock().unwrap().values().map(|(job, _)| job.clone()).collect();
+    jobs.sort_by_key(|job| job.run_at);
+    match to_string(&jobs) {
+        Ok(json) => CommandResult::new(true, json, None, CommandType::Internal),
+        Err(e) => CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("Failed to serialize scheduled jobs: {}", e)),
+            CommandType::Internal,
+        ),
+    }
+}
+
+fn cancel_scheduled(scheduled: &ScheduledJobs, job_id: &str) -> CommandResult {
+    match scheduled.lock().unwrap().remove(job_id) {
+        Some((job, cancel)) => {
+            let _ = cancel.send(());
+            info!("Cancelled scheduled job {}: {}", job_id, job.command);
+            CommandResult::new(true, format!("Cancelled scheduled job {}", job_id), None, CommandType::Internal)
+        },
+        None => CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("No scheduled job {}", job_id)),
+            CommandType::Internal,
+        ),
+    }
+}
+
+/// Run the handler registered for the custom command `name`. A panicking handler
+/// fails the command rather than leaving it unanswered.
+async fn run_custom(handlers: &CommandHandlers, name: &str, args: &Value) -> CommandResult {
+    let Some(handler) = handlers.get(name) else {
+        return CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("No handler for custom command {}", name)),
+            CommandType::Internal,
+        );
+    };
+    match std::panic::AssertUnwindSafe(handler.handle(args)).catch_unwind().await {
+        Ok(result) => result,
+        Err(_) => {
+            error!("Handler for custom command {} panicked", name);
+            CommandResult::new(
+                false,
+                String::new(),
+                Some(format!("Handler for custom command {} panicked", name)),
+                CommandType::Internal,
+            )
+        },
+    }
+}
+
+/// Report what `command` would do on this client, or why it would be refused
+fn dry_run(
+    command: &Command,
+    policy: &CommandPolicy,
+    power_commands: bool,
+    file_root: Option<&Path>,
+    default_shell: Shell,
+    handlers: &CommandHandlers,
+) -> CommandResult {
+    let outcome = match command {
+        Command::Execute { command, cwd, env, shell, .. } => {
+            let shell = shell.unwrap_or(default_shell);
+            policy.check(command)
+                .and_then(|()| policy.check_context(cwd.as_deref(), env.as_ref()))
+                .map_err(|_| "command not permitted".to_string())
+                .and_then(|()| match cwd {
+                    Some(cwd) if !Path::new(cwd).is_dir() => Err(format!("working directory {} does not exist", cwd)),
+                    _ => shell_argv(command, shell, cwd.as_deref()).map_err(|e| e.to_string()),
+                })
+                .map(|_| match cwd {
+                    Some(cwd) => format!("Would run with {} in {}: {}", shell, cwd, command),
+                    None => format!("Would run with {}: {}", shell, command),
+                })
+        },
+        Command::RebootMachine { .. } | Command::PowerOff if !power_commands => {
+            Err("Reboot and power off are disabled on this client".to_string())
+        },
+        Command::RebootMachine { delay_secs } => Ok(plan_power(PowerAction::Reboot(delay_secs.unwrap_or(0)))),
+        Command::PowerOff => Ok(plan_power(PowerAction::PowerOff)),
+        Command::UploadFile { path, contents, .. } => resolve_file_path(path, file_root)
+            .map(|target| format!("Would write {} bytes to {}", contents.len(), target.display())),
+        Command::DownloadFile { path } => resolve_file_path(path, file_root)
+            .map(|target| format!("Would read {}", target.display())),
+        Command::ReadFileRange { path, offset, length } => resolve_file_path(path, file_root)
+            .map(|target| format!("Would read {} bytes at offset {} of {}", length, offset, target.display())),
+        Command::ScheduleExecute { command, run_at } => policy.check(command)
+            .map_err(|_| "command not permitted".to_string())
+            .map(|()| format!("Would schedule for {}: {}", format_unix_time(*run_at), command)),
+        Command::TailFile { path, .. } => resolve_file_path(path, file_root)
+            .map(|target| format!("Would follow {}", target.display())),
+        Command::GetLogTail { path: Some(path), lines } => resolve_file_path(path, file_root)
+            .map(|target| format!("Would read the last {} lines of {}", lines, target.display())),
+        Command::Shutdown => Ok("Would shut down the client".to_string()),
+        Command::RestartAgent => Ok("Would restart the client".to_string()),
+        Command::Custom { name, .. } if !handlers.contains_key(name) => {
+            Err(format!("no handler for custom command {}", name))
+        },
+        other => Ok(format!("Would handle {}", other)),
+    };
+    
+    match outcome {
+        Ok(plan) => {
+            info!("Dry run: {}", plan);
+            CommandResult::new(true, plan, None, CommandType::Internal)
+        },
+        Err(reason) => CommandResult::new(false, String::new(), Some(format!("Would be refused: {}", reason)), CommandType::Internal),
+    }
+}
+
+fn plan_power(action: PowerAction) -> String {
+    let (description, args) = shutdown_args(action);
+    format!("Would schedule {}: shutdown {}", description, args.join(" "))
+}
+
+/// Register a shell command as running, returning the channel that cancels it
+fn track_running(running: &RunningCommands, request_id: &str) -> oneshot::Receiver<()> {
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    running.lock().unwrap().insert(request_id.to_string(), cancel_tx);
+    cancel_rx
+}
+
+fn cancel_request(running: &RunningCommands, request_id: &str) -> CommandResult {
+    let cancelled = running.lock().unwrap()
+        .remove(request_id)
+        .is_some_and(|cancel| cancel.send(()).is_ok());
+    if cancelled {
+        info!("Cancelling command {}", request_id);
+        CommandResult::new(true, format!("Cancelled {}", request_id), None, CommandType::Internal)
+    } else {
+        CommandResult::new(
+            false,
+            String::new(),
+            Some(format!("No running command with request id {}", request_id)),
+            CommandType::Internal,
+        )
+    }
+}
+
+/// Tell the sender `request_id` arrived. Failing to is logged but doesn't stop
+/// the command from running.
+async fn publish_ack(nats: &Client, subjects: &SubjectLayout, prefix: &str, codec: Codec, client_id: &str, request_id: &str) {
+    let ack_subject = subjects.ack(prefix, request_id);
+    let ack = CommandAck {
+        request_id: request_id.to_string(),
+        client_id: client_id.to_string(),
+    };
+    let published = match codec.encode(&ack) {
+        Ok(payload) => nats.publish(ack_subject, payload.into()).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = published {
+        warn!("Failed to acknowledge command {}: {}", request_id, e);
+    }
+}
+
+/// Run a shell command, publishing its output as ordered `OutputChunk`s on the
+/// request's stream subject followed by a final `done` chunk. The returned
+/// result carries only a summary, since the output has already been sent.
+#[allow(clippy::too_many_arguments)]
+async fn execute_streaming(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    codec: Codec,
+    request_id: &str,
+    cmd: &str,
+    shell: Shell,
+    cwd: Option<&str>,
+    env: Option<&HashMap<String, String>>,
+    stdin: Option<String>,
+    timeout: Duration,
+    policy: &CommandPolicy,
+    cancel: oneshot::Receiver<()>,
+) -> CommandResult {
+    let mut publisher = ChunkPublisher::new(nats, subjects, prefix, codec, request_id);
+    let (chunk_tx, mut chunk_rx) = mpsc::channel::<(bool, Vec<u8>)>(STREAM_CHANNEL_CAPACITY);
+    // The command closes the channel when it ends, after its last chunk
+    let forward = async {
+        while let Some((stderr, data)) = chunk_rx.recv().await {
+            publisher.send(stderr, data).await;
+        }
+    };
+    
+    // Nothing is collected to cut off when streaming
+    let (mut result, ()) = tokio::join!(
+        execute_command(cmd, shell, cwd, env, stdin, timeout, policy, u64::MAX, Some(chunk_tx), cancel),
+        forward,
+    );
+    
+    result.output = format!("Streamed {} bytes in {} chunks to {}", publisher.bytes, publisher.seq, publisher.subject);
+    publisher.finish(&result).await;
+    result
+}
+
+async fn publish_chunk(nats: &Client, subject: &str, codec: Codec, chunk: &OutputChunk) {
+    match codec.encode(chunk) {
+        Ok(payload) => {
+            if let Err(e) = nats.publish(subject.to_string(), payload.into()).await {
+                error!("Failed to publish output chunk {}: {}", chunk.seq, e);
+            }
+        },
+        Err(e) => error!("Failed to serialize output chunk {}: {}", chunk.seq, e),
+    }
+}
+
+/// Publishes output as numbered `OutputChunk`s on a request's stream subject
+struct ChunkPublisher<'a> {
+    nats: &'a Client,
+    subject: String,
+    codec: Codec,
+    request_id: String,
+    seq: u64,
+    bytes: usize,
+}
+
+impl<'a> ChunkPublisher<'a> {
+    fn new(nats: &'a Client, subjects: &SubjectLayout, prefix: &str, codec: Codec, request_id: &str) -> Self {
+        Self {
+            nats,
+            subject: subjects.stream(prefix, request_id),
+            codec,
+            request_id: request_id.to_string(),
+            seq: 0,
+            bytes: 0,
+        }
+    }
+    
+    async fn send(&mut self, stderr: bool, data: Vec<u8>) {
+        self.bytes += data.len();
+        let chunk = OutputChunk {
+            request_id: self.request_id.clone(),
+            seq: self.seq,
+            stderr,
+            data,
+            done: false,
+            success: true,
+            exit_code: None,
+            error: None,
+        };
+        publish_chunk(self.nats, &self.subject, self.codec, &chunk).await;
+        self.seq += 1;
+    }
+    
+    /// Send the final `done` chunk reporting how `result` ended
+    async fn finish(self, result: &CommandResult) {
+        let done = OutputChunk {
+            request_id: self.request_id,
+            seq: self.seq,
+            stderr: false,
+            data: Vec::new(),
+            done: true,
+            success: result.success,
+            exit_code: result.exit_code,
+            error: result.error.clone(),
+        };
+        publish_chunk(self.nats, &self.subject, self.codec, &done).await;
+    }
+}
+
+/// Run `shell` on a pseudo-terminal until it exits or `cancel` fires, see `Command::StartShell`
+async fn shell_session(
+    mut publisher: ChunkPublisher<'_>,
+    input_subject: String,
+    shell: Shell,
+    rows: u16,
+    cols: u16,
+    mut cancel: oneshot::Receiver<()>,
+) -> CommandResult {
+    info!("Starting {} shell session", shell);
+    let mut result = match run_shell(&mut publisher, input_subject, shell, rows, cols, &mut cancel).await {
+        Ok(Some(exit_code)) => {
+            let mut result = CommandResult::new(
+                true,
+                format!("Shell exited with {} after {} bytes in {} chunks", exit_code, publisher.bytes, publisher.seq),
+                None,
+                CommandType::Internal,
+            );
+            result.exit_code = Some(exit_code);
+            result
+        },
+        Ok(None) => CommandResult::failed(CommandFailure::Cancelled, CommandType::Internal),
+        Err(failure) => CommandResult::failed(failure, CommandType::Internal),
+    };
+    if let Some(error) = &result.error {
+        warn!("Shell session ended: {}", error);
+        result.output.clear();
+    }
+    publisher.finish(&result).await;
+    result
+}
+
+/// Feed `shell` the input arriving on `input_subject` and publish what it prints.
+/// Returns its exit code, or `None` if it was killed because `cancel` fired.
+async fn run_shell(
+    publisher: &mut ChunkPublisher<'_>,
+    input_subject: String,
+    shell: Shell,
+    rows: u16,
+    cols: u16,
+    cancel: &mut oneshot::Receiver<()>,
+) -> Result<Option<i32>, CommandFailure> {
+    use portable_pty::{native_pty_system, CommandBuilder, PtySize};
+    use std::io::{Read, Write};
+    
+    let spawn_failed = |reason: String| CommandFailure::SpawnFailed { program: shell.to_string(), reason };
+    let program = shell.program()
+        .ok_or_else(|| CommandFailure::InvalidCommand("a shell session needs a shell, not direct".to_string()))?;
+    let path = find_program(program, None)
+        .ok_or_else(|| CommandFailure::NotFound { what: format!("Shell {}", shell) })?;
+    // Subscribed before the shell starts, so nothing typed at its first prompt is lost
+    let mut inputs = publisher.nats.subscribe(input_subject).await
+        .map_err(|e| spawn_failed(format!("failed to subscribe to input: {}", e)))?;
+    
+    let pair = native_pty_system()
+        .openpty(PtySize { rows, cols, pixel_width: 0, pixel_height: 0 })
+        .map_err(|e| spawn_failed(format!("failed to open a pseudo-terminal: {}", e)))?;
+    let mut command = CommandBuilder::new(path);
+    command.args(interactive_args(shell));
+    if let Some(home) = dirs::home_dir() {
+        command.cwd(home);
+    }
+    let mut child = pair.slave.spawn_command(command).map_err(|e| spawn_failed(e.to_string()))?;
+    drop(pair.slave);
+    #[cfg(unix)]
+    disable_echo(&*pair.master);
+    let mut reader = pair.master.try_clone_reader().map_err(|e| spawn_failed(e.to_string()))?;
+    let mut writer = pair.master.take_writer().map_err(|e| spawn_failed(e.to_string()))?;
+    
+    // The terminal only reads and writes blocking, so each gets a thread
+    let (output_tx, mut output_rx) = mpsc::channel::<Vec<u8>>(STREAM_CHANNEL_CAPACITY);
+    std::thread::spawn(move || {
+        let mut buf = vec![0; STREAM_CHUNK_BYTES];
+        while let Ok(n @ 1..) = reader.read(&mut buf) {
+            if output_tx.blocking_send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    let (input_tx, mut input_rx) = mpsc::channel::<Vec<u8>>(STREAM_CHANNEL_CAPACITY);
+    std::thread::spawn(move || {
+        while let Some(data) = input_rx.blocking_recv() {
+            if writer.write_all(&data).and_then(|()| writer.flush()).is_err() {
+                break;
+            }
+        }
+    });
+    
+    let mut killer = child.clone_killer();
+    let mut exited = tokio::task::spawn_blocking(move || child.wait());
+    let mut cancelled = false;
+    let mut last_sent = Instant::now();
+    let mut ticks = tokio::time::interval(TAIL_KEEPALIVE);
+    
+    let status = loop {
+        tokio::select! {
+            status = &mut exited => break status,
+            Some(data) = output_rx.recv() => {
+                publisher.send(false, data).await;
+                last_sent = Instant::now();
+            },
+            Some(msg) = inputs.next() => match publisher.codec.decode::<ShellInput>(&msg.payload) {
+                Ok(input) => {
+                    let _ = input_tx.send(input.data).await;
+                },
+                Err(e) => warn!("Ignoring unreadable shell input: {}", e),
+            },
+            Ok(()) = &mut *cancel, if !cancelled => {
+                info!("Killing shell session");
+                cancelled = true;
+                if let Err(e) = killer.kill() {
+                    warn!("Failed to kill shell: {}", e);
+                }
+            },
+            _ = ticks.tick() => {
+                if last_sent.elapsed() >= TAIL_KEEPALIVE {
+                    publisher.send(false, Vec::new()).await;
+                    last_sent = Instant::now();
+                }
+            },
+        }
+    };
+    // Whatever the shell printed last may still be on its way through the terminal
+    while let Ok(Some(data)) = tokio::time::timeout(SHELL_DRAIN_TIMEOUT, output_rx.recv()).await {
+        publisher.send(false, data).await;
+    }
+    
+    let status = status
+        .map_err(|e| spawn_failed(e.to_string()))?
+        .map_err(|e| spawn_failed(format!("failed to wait for the shell: {}", e)))?;
+    Ok((!cancelled).then_some(status.exit_code() as i32))
+}
+
+/// Arguments that make `shell` interactive and leave echoing typed lines to the
+/// terminal, since the server sends whole lines it has already shown
+fn interactive_args(shell: Shell) -> &'static [&'static str] {
+    match shell {
+        Shell::Sh => &["-i"],
+        Shell::Bash => &["--noediting", "-i"],
+        Shell::Pwsh => &["-NoLogo"],
+        Shell::Cmd | Shell::Direct => &[],
+    }
+}
+
+/// Stop the terminal from echoing what is typed, which the operator has already seen
+#[cfg(unix)]
+fn disable_echo(master: &dyn portable_pty::MasterPty) {
+    let Some(fd) = master.as_raw_fd() else {
+        return;
+    };
+    // SAFETY: `fd` stays open for as long as `master`, and `termios` is fully
+    // initialized by `tcgetattr` before it is read
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(fd, &mut termios) == 0 {
+            termios.c_lflag &= !libc::ECHO;
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+                debug!("Failed to turn off terminal echo: {}", std::io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+/// The file being followed by `tail_file`
+struct TailedFile {
+    file: tokio::fs::File,
+    position: u64,
+    id: Option<u64>,
+}
+
+/// Identity of a file that survives renames, to notice when a path is replaced
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+/// Identity of a file that survives renames, to notice when a path is replaced
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Follow the file at `path` until `cancel` fires, see `Command::TailFile`
+async fn tail_file(
+    mut publisher: ChunkPublisher<'_>,
+    path: &str,
+    lines: usize,
+    file_root: Option<&Path>,
+    mut cancel: oneshot::Receiver<()>,
+) -> CommandResult {
+    let mut result = match resolve_file_path(path, file_root) {
+        Ok(target) => follow_file(&mut publisher, &target, lines, &mut cancel).await,
+        Err(e) => CommandResult::new(false, String::new(), Some(e), CommandType::Internal),
+    };
+    if result.success {
+        result.output = format!("Followed {} for {} bytes in {} chunks", path, publisher.bytes, publisher.seq);
+    }
+    publisher.finish(&result).await;
+    result
+}
+
+async fn follow_file(
+    publisher: &mut ChunkPublisher<'_>,
+    target: &Path,
+    lines: usize,
+    cancel: &mut oneshot::Receiver<()>,
+) -> CommandResult {
+    let failed = |action: &str, e: std::io::Error| CommandResult::new(
+        false,
+        String::new(),
+        Some(format!("Failed to {} {}: {}", action, target.display(), e)),
+        CommandType::Internal,
+    );
+    let mut tailed: Option<TailedFile> = None;
+    let mut first_open = true;
+    let mut waiting = false;
+    let mut last_sent = Instant::now();
+    let mut ticks = tokio::time::interval(TAIL_POLL_INTERVAL);
+    
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {},
+            Ok(()) = &mut *cancel => break,
+        }
+        let sent_before = publisher.seq;
+        let current = tokio::fs::metadata(target).await.ok();
+        
+        if let Some(open) = &mut tailed {
+            let len = open.file.metadata().await.map(|metadata| metadata.len()).unwrap_or(open.position);
+            if len < open.position {
+                publisher.send(true, format!("tail: {} was truncated\n", target.display()).into_bytes()).await;
+                open.position = 0;
+            }
+            if let Err(e) = read_appended(publisher, open).await {
+                return failed("read", e);
+            }
+            
+            // Once the old file is drained, move on to whatever now has its name
+            if current.as_ref().is_none_or(|metadata| file_id(metadata) != open.id) {
+                let what = if current.is_some() { "was replaced, following the new file" } else { "was removed" };
+                publisher.send(true, format!("tail: {} {}\n", target.display(), what).into_bytes()).await;
+                tailed = None;
+            }
+        }
+        
+        if tailed.is_none() {
+            match &current {
+                Some(metadata) => {
+                    let mut file = match tokio::fs::File::open(target).await {
+                        Ok(file) => file,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                        Err(e) => return failed("open", e),
+                    };
+                    // Show the last lines the first time; a file that appears later is new in full
+                    let mut position = 0;
+                    if first_open {
+                        let start = metadata.len().saturating_sub(TAIL_BACKLOG_BYTES);
+                        let mut backlog = Vec::new();
+                        let read = async {
+                            file.seek(std::io::SeekFrom::Start(start)).await?;
+                            (&mut file).take(TAIL_BACKLOG_BYTES).read_to_end(&mut backlog).await
+                        };
+                        if let Err(e) = read.await {
+                            return failed("read", e);
+                        }
+                        let shown = last_lines(&backlog, lines, start > 0);
+                        if !shown.is_empty() {
+                            publisher.send(false, shown.to_vec()).await;
+                        }
+                        position = start + backlog.len() as u64;
+                    }
+                    if waiting {
+                        publisher.send(true, format!("tail: {} appeared\n", target.display()).into_bytes()).await;
+                    }
+                    tailed = Some(TailedFile { file, position, id: file_id(metadata) });
+                    first_open = false;
+                    waiting = false;
+                },
+                None if !waiting => {
+                    publisher.send(true, format!("tail: {} not found, waiting for it to appear\n", target.display()).into_bytes()).await;
+                    waiting = true;
+                },
+                None => {},
+            }
+        }
+        
+        if publisher.seq != sent_before {
+            last_sent = Instant::now();
+        } else if last_sent.elapsed() >= TAIL_KEEPALIVE {
+            publisher.send(false, Vec::new()).await;
+            last_sent = Instant::now();
+        }
+    }
+    
+    CommandResult::new(true, String::new(), None, CommandType::Internal)
+}
+
+/// Send everything appended to a followed file since it was last read
+async fn read_appended(publisher: &mut ChunkPublisher<'_>, tailed: &mut TailedFile) -> std::io::Result<()> {
+    tailed.file.seek(std::io::SeekFrom::Start(tailed.position)).await?;
+    let mut chunk = vec![0; STREAM_CHUNK_BYTES];
+    loop {
+        let n = tailed.file.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        tailed.position += n as u64;
+        publisher.send(false, chunk[..n].to_vec()).await;
+    }
+}
+
+/// Send the last `lines` lines of `path`, or of the client's own log file, cut short
+/// to `max_bytes`
+async fn get_log_tail(
+    path: Option<&str>,
+    lines: usize,
+    log_file: Option<&Path>,
+    file_root: Option<&Path>,
+    max_bytes: u64,
+) -> CommandResult {
+    let failed = |error: String| CommandResult::new(false, String::new(), Some(error), CommandType::Internal);
+    let target = match (path, log_file) {
+        (Some(path), _) => match resolve_file_path(path, file_root) {
+            Ok(target) => target,
+            Err(e) => return failed(e),
+        },
+        (None, Some(log_file)) => log_file.to_path_buf(),
+        (None, None) => return failed("This client doesn't log to a file, give a path".to_string()),
+    };
+    
+    match read_last_lines(&target, lines, max_bytes).await {
+        Ok((tail, truncated)) => {
+            let mut result = CommandResult::new(true, String::from_utf8_lossy(&tail).into_owned(), None, CommandType::Internal);
+            result.truncated = truncated;
+            result
+        },
+        Err(e) => failed(format!("Failed to read {}: {}", target.display(), e)),
+    }
+}
+
+/// The last `lines` lines of the file at `path`, read backwards from the end a block
+/// at a time until enough are found or `max_bytes` have been read, and whether that
+/// limit left out some of the lines
+async fn read_last_lines(path: &Path, lines: usize, max_bytes: u64) -> std::io::Result<(Vec<u8>, bool)> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut start = file.metadata().await?.len();
+    let mut tail = Vec::new();
+    let mut enough = lines == 0;
+    
+    while !enough && start > 0 && (tail.len() as u64) < max_bytes {
+        let block = start.min(TAIL_BACKLOG_BYTES).min(max_bytes - tail.len() as u64);
+        start -= block;
+        let mut chunk = vec![0; block as usize];
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        file.read_exact(&mut chunk).await?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        // One more line break than lines wanted, or as many if the first is cut off,
+        // not counting the one ending the file
+        let breaks = tail.strip_suffix(b"\n").unwrap_or(&tail).iter().filter(|&&b| b == b'\n').count();
+        enough = breaks >= lines;
+    }
+    
+    Ok((last_lines(&tail, lines, start > 0).to_vec(), !enough && start > 0))
+}
+
+/// The last `lines` lines of `data`, leaving out its first line if `cut_off` says
+/// `data` may start partway through one
+fn last_lines(data: &[u8], lines: usize, cut_off: bool) -> &[u8] {
+    let data = match data.iter().position(|&b| b == b'\n') {
+        Some(end) if cut_off => &data[end + 1..],
+        _ => data,
+    };
+    if lines == 0 {
+        return &[];
+    }
+    let body = data.strip_suffix(b"\n").unwrap_or(data);
+    let start = body.iter().enumerate().rev()
+        .filter(|(_, &b)| b == b'\n')
+        .nth(lines - 1)
+        .map_or(0, |(i, _)| i + 1);
+    &data[start..]
+}
+
+/// Why a shell command was killed before it exited
+enum Interrupted {
+    TimedOut,
+    Cancelled,
+}
+
+/// The program and arguments that run `cmd` with `shell`, with the program resolved
+/// to a path, or why it can't run here
+fn shell_argv(cmd: &str, shell: Shell, cwd: Option<&str>) -> Result<Vec<String>, CommandFailure> {
+    let mut argv = match shell.program() {
+        Some(program) => {
+            let mut argv = vec![program.to_string()];
+            argv.extend(shell.args().iter().map(|arg| arg.to_string()));
+            argv.push(cmd.to_string());
+            argv
+        },
+        None => shlex::split(cmd)
+            .ok_or_else(|| CommandFailure::InvalidCommand("Could not split command into words, check its quoting".to_string()))?,
+    };
+    if argv.is_empty() {
+        return Err(CommandFailure::InvalidCommand("Command is empty".to_string()));
+    }
+    
+    argv[0] = match find_program(&argv[0], cwd) {
+        Some(path) => path.to_string_lossy().into_owned(),
+        None if shell == Shell::Direct => return Err(CommandFailure::NotFound { what: format!("Program {}", argv[0]) }),
+        None => return Err(CommandFailure::NotFound { what: format!("Shell {}", shell) }),
+    };
+    Ok(argv)
+}
+
+/// Where `program` is: relative to `cwd` if it is a path, otherwise in a `PATH` directory.
+/// Windows also tries the usual executable extensions.
+fn find_program(program: &str, cwd: Option<&str>) -> Option<PathBuf> {
+    let extensions: &[&str] = if cfg!(windows) { &["", ".exe", ".cmd", ".bat", ".com"] } else { &[""] };
+    let path = Path::new(program);
+    let candidates: Vec<PathBuf> = if path.components().count() > 1 {
+        vec![Path::new(cwd.unwrap_or("")).join(path)]
+    } else {
+        std::env::split_paths(&std::env::var_os("PATH")?).map(|dir| dir.join(path)).collect()
+    };
+    
+    candidates.iter()
+        .flat_map(|candidate| extensions.iter().map(move |ext| {
+            let mut with_ext = candidate.clone().into_os_string();
+            with_ext.push(ext);
+            PathBuf::from(with_ext)
+        }))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Run a shell command, feeding it `stdin` if given, and collect its output, up to
+/// `max_output_bytes` each of stdout and stderr. With a `sink`, output is forwarded there as `(is_stderr, bytes)`
+/// pieces as it arrives instead of being collected. The command is killed if it runs
+/// longer than `timeout` or `cancel` fires.
+#[allow(clippy::too_many_arguments)]
+async fn execute_command(
+    cmd: &str,
+    shell: Shell,
+    cwd: Option<&str>,
+    env: Option<&HashMap<String, String>>,
+    stdin: Option<String>,
+    timeout: Duration,
+    policy: &CommandPolicy,
+    max_output_bytes: u64,
+    sink: Option<mpsc::Sender<(bool, Vec<u8>)>>,
+    mut cancel: oneshot::Receiver<()>,
+) -> CommandResult {
+    if let Err(reason) = policy.check(cmd).and_then(|()| policy.check_context(cwd, env)) {
+        warn!("Refusing command '{}': {}", cmd, reason);
+        // The policy's reason stays in the client's log rather than telling the server
+        // how to get around it
+        let mut result = CommandResult::new(
+            false,
+            String::new(),
+            Some("command not permitted".to_string()),
+            CommandType::Shell,
+        );
+        result.failure = Some(CommandFailure::PermissionDenied("command not permitted".to_string()));
+        return result;
+    }
+    
+    if let Some(cwd) = cwd.filter(|cwd| !Path::new(cwd).is_dir()) {
+        return CommandResult::failed(
+            CommandFailure::NotFound { what: format!("Working directory {}", cwd) },
+            CommandType::Shell,
+        );
+    }
+    let argv = match shell_argv(cmd, shell, cwd) {
+        Ok(argv) => argv,
+        Err(failure) => return CommandResult::failed(failure, CommandType::Shell),
+    };
+    
+    let mut process = AsyncProcessCommand::new(&argv[0]);
+    process.args(&argv[1..]);
+    if let Some(cwd) = cwd {
+        process.current_dir(cwd);
+    }
+    if let Some(env) = env {
+        process.envs(env);
+    }
+    // In a process group of its own, so everything it starts can be killed with it
+    #[cfg(unix)]
+    process.process_group(0);
+    
+    let started = Instant::now();
+    let started_at = SystemTime::now();
+    let mut child = match process
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => return CommandResult::failed(spawn_failure(&argv[0], &e), CommandType::Shell),
+    };
+    
+    // Write the input alongside draining the output, so a command that answers before
+    // reading all of its input can't fill its stdout pipe and stall both sides
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        tokio::spawn(async move {
+            // Dropping the pipe afterwards closes it, signalling end of input
+            if let Err(e) = pipe.write_all(input.as_bytes()).await {
+                debug!("Command stopped reading its input: {}", e);
+            }
+        });
+    }
+    
+    // Drain the pipes in the background so partial output survives a kill
+    let stdout_output = PipeOutput::default();
+    let stderr_output = PipeOutput::default();
+    let stdout_reader = tokio::spawn(read_pipe(child.stdout.take(), false, max_output_bytes, sink.clone(), stdout_output.clone()));
+    let stderr_reader = tokio::spawn(read_pipe(child.stderr.take(), true, max_output_bytes, sink, stderr_output.clone()));
+    
+    let status = tokio::select! {
+        status = child.wait() => Ok(status),
+        _ = sleep(timeout) => Err(Interrupted::TimedOut),
+        Ok(()) = &mut cancel => Err(Interrupted::Cancelled),
+    };
+    if let Err(interrupted) = &status {
+        match interrupted {
+            Interrupted::TimedOut => warn!("Command exceeded {:?}, killing process: {}", timeout, cmd),
+            Interrupted::Cancelled => info!("Command cancelled, killing process: {}", cmd),
+        }
+        if let Err(e) = kill_process_tree(&mut child).await {
+            error!("Failed to kill command: {}", e);
+        }
+    }
+    
+    let (stdout, stdout_bytes) = collect_pipe(stdout_reader, &stdout_output).await;
+    let (stderr, stderr_bytes) = collect_pipe(stderr_reader, &stderr_output).await;
+    let truncated = stdout_bytes > max_output_bytes || stderr_bytes > max_output_bytes;
+    if truncated {
+        warn!("Output of command cut off at {} bytes ({} written): {}",
+            max_output_bytes, stdout_bytes + stderr_bytes, cmd);
+    }
+    
+    let exit_code = match &status {
+        Ok(Ok(status)) => status.code(),
+        _ => None,
+    };
+    // Success depends only on the exit status; stderr is passed back separately
+    let mut result = match status {
+        Ok(Ok(status)) if status.success() => {
+            CommandResult::new(true, stdout.clone(), None, CommandType::Shell)
+        },
+        Ok(Ok(status)) => {
+            let reason = match status.code() {
+                Some(code) => format!("command exited with status {}", code),
+                None => "command was terminated by a signal".to_string(),
+            };
+            CommandResult::new(false, stdout.clone(), Some(reason), CommandType::Shell)
+        },
+        Ok(Err(e)) => {
+            CommandResult::new(
+                false,
+                stdout.clone(),
+                Some(format!("Failed to wait for command: {}", e)),
+                CommandType::Shell,
+            )
+        },
+        Err(Interrupted::TimedOut) => {
+            CommandResult::failed(CommandFailure::Timeout { secs: timeout.as_secs() }, CommandType::Shell)
+        },
+        Err(Interrupted::Cancelled) => CommandResult::failed(CommandFailure::Cancelled, CommandType::Shell),
+    };
+    result.output = stdout.clone();
+    result.stdout = stdout;
+    result.stderr = stderr;
+    result.duration_ms = started.elapsed().as_millis() as u64;
+    result.started_at = Some(started_at);
+    result.exit_code = exit_code;
+    result.truncated = truncated;
+    result.output_bytes = stdout_bytes + stderr_bytes;
+    result
+}
+
+/// Kill a command and whatever it started, which shares its process group on Unix.
+/// On Windows, taskkill finds the processes it started by their parent.
+async fn kill_process_tree(child: &mut tokio::process::Child) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Some(pid) = child.id().and_then(|pid| libc::pid_t::try_from(pid).ok()) {
+        // SAFETY: killpg() has no memory safety requirements, it only takes integers
+        if unsafe { libc::killpg(pid, libc::SIGKILL) } != 0 {
+            debug!("Failed to kill process group {}: {}", pid, std::io::Error::last_os_error());
+        }
+    }
+    #[cfg(windows)]
+    if let Some(pid) = child.id() {
+        let killed = AsyncProcessCommand::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid.to_string()])
+            .output()
+            .await;
+        if let Err(e) = killed {
+            debug!("Failed to run taskkill: {}", e);
+        }
+    }
+    child.kill().await
+}
+
+/// Sort an error from starting `program` into the matching failure
+fn spawn_failure(program: &str, e: &std::io::Error) -> CommandFailure {
+    // ENOEXEC and ERROR_BAD_EXE_FORMAT: the file exists but isn't something this OS can run
+    let bad_format = if cfg!(windows) { 193 } else { 8 };
+    if e.raw_os_error() == Some(bad_format) {
+        return CommandFailure::Unsupported(format!("{} is not a valid executable", program));
+    }
+    match e.kind() {
+        std::io::ErrorKind::NotFound => CommandFailure::NotFound { what: format!("Program {}", program) },
+        std::io::ErrorKind::PermissionDenied => CommandFailure::PermissionDenied(format!("{} is not executable", program)),
+        std::io::ErrorKind::Unsupported => CommandFailure::Unsupported(e.to_string()),
+        _ => CommandFailure::SpawnFailed { program: program.to_string(), reason: e.to_string() },
+    }
+}
+
+/// What `read_pipe` has read so far: the first bytes up to its limit, and how many
+/// were read in total
+type PipeOutput = Arc<Mutex<(Vec<u8>, u64)>>;
+
+/// Read a pipe to its end into `output`, keeping the first `limit` bytes. Everything
+/// past `limit` is read and dropped so the command doesn't block on a full pipe. With
+/// a `sink`, everything is sent there and only counted.
+async fn read_pipe<R: AsyncRead + Unpin>(
+    pipe: Option<R>,
+    is_stderr: bool,
+    limit: u64,
+    sink: Option<mpsc::Sender<(bool, Vec<u8>)>>,
+    output: PipeOutput,
+) {
+    let Some(mut pipe) = pipe else {
+        return;
+    };
+    
+    let mut chunk = vec![0; STREAM_CHUNK_BYTES];
+    loop {
+        let n = match pipe.read(&mut chunk).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        {
+            let (buf, total) = &mut *output.lock().unwrap();
+            *total += n as u64;
+            if sink.is_none() {
+                let room = limit.saturating_sub(buf.len() as u64).min(n as u64) as usize;
+                buf.extend_from_slice(&chunk[..room]);
+            }
+        }
+        if let Some(sink) = &sink {
+            if sink.send((is_stderr, chunk[..n].to_vec())).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+// Something the command left running in the background can keep the pipe open, so
+// don't wait forever. Abort the reader in that case so it releases any stream sink it
+// holds, and keep what it had read.
+async fn collect_pipe(mut reader: JoinHandle<()>, output: &PipeOutput) -> (String, u64) {
+    if tokio::time::timeout(PIPE_DRAIN_TIMEOUT, &mut reader).await.is_err() {
+        reader.abort();
+    }
+    let (buf, total) = &*output.lock().unwrap();
+    let mut text = String::from_utf8_lossy(buf).to_string();
+    if *total > buf.len() as u64 {
+        text.push_str(&format!("\n...[output truncated, {} total]", format_bytes(*total)));
+    }
+    (text, *total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn windows_version_from_registry_values() {
+        assert_eq!(
+            format_windows_version("Windows 10 Pro", Some("23H2"), Some("22631")),
+            "Windows 11 Pro 23H2",
+        );
+        assert_eq!(
+            format_windows_version("Windows 10 Enterprise", Some("22H2"), Some("19045")),
+            "Windows 10 Enterprise 22H2",
+        );
+        assert_eq!(
+            format_windows_version("Windows Server 2022 Standard", Some("21H2"), Some("20348")),
+            "Windows Server 2022 Standard 21H2",
+        );
+        // Older builds have no DisplayVersion, and a missing build number isn't guessed at
+        assert_eq!(format_windows_version("Windows 10 Pro", None, None), "Windows 10 Pro");
+        assert_eq!(format_windows_version("Windows 10 Pro ", Some(""), Some("bogus")), "Windows 10 Pro");
+    }
+    
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn timeout_kills_what_the_command_started() {
+        let (_cancel_tx, cancel_rx) = oneshot::channel();
+        let started = Instant::now();
+        let result = execute_command(
+            "echo partial; sleep 30; echo never", Shell::Sh, None, None, None,
+            Duration::from_secs(1), &CommandPolicy::default(), u64::MAX, None, cancel_rx,
+        ).await;
+        assert!(matches!(result.failure, Some(CommandFailure::Timeout { .. })), "{:?}", result);
+        assert_eq!(result.stdout.trim(), "partial");
+        // Neither the sleep nor the drain of its pipes was waited for
+        assert!(started.elapsed() < PIPE_DRAIN_TIMEOUT + Duration::from_secs(1), "{:?}", started.elapsed());
+    }
+    
+    #[tokio::test]
+    async fn policy_refuses_environment_and_working_directory() {
+        let policy = CommandPolicy { allow: vec!["uptime".to_string()], deny: Vec::new() };
+        let env = HashMap::from([("BASH_ENV".to_string(), "/tmp/payload".to_string())]);
+        let handlers = CommandHandlers::default();
+        
+        for (cwd, env) in [(None, Some(env.clone())), (Some("/".to_string()), None)] {
+            let command = Command::Execute {
+                command: "uptime".to_string(),
+                cwd: cwd.clone(),
+                env: env.clone(),
+                stream: false,
+                stdin: None,
+                shell: None,
+            };
+            let plan = dry_run(&command, &policy, true, None, Shell::default(), &handlers);
+            assert!(!plan.success, "{:?}", plan);
+            
+            let (_cancel_tx, cancel_rx) = oneshot::channel();
+            let result = execute_command(
+                "uptime", Shell::default(), cwd.as_deref(), env.as_ref(), None,
+                Duration::from_secs(5), &policy, u64::MAX, None, cancel_rx,
+            ).await;
+            assert!(matches!(result.failure, Some(CommandFailure::PermissionDenied(_))), "{:?}", result);
+        }
+        
+        // Without a policy they're left to the server
+        let command = Command::Execute {
+            command: "uptime".to_string(),
+            cwd: Some("/".to_string()),
+            env: Some(env),
+            stream: false,
+            stdin: None,
+            shell: None,
+        };
+        assert!(dry_run(&command, &CommandPolicy::default(), true, None, Shell::default(), &handlers).success);
+    }
+}
@@ -0,0 +1,75 @@
+//! Durable command delivery through JetStream, so commands sent while a client is
+//! offline are delivered when it comes back.
+//!
+//! Commands for every client are stored in one work-queue stream named
+//! `<prefix>_commands` (with characters not allowed in stream names replaced by
+//! `-`), capturing the command subjects of every client. Each client reads its own subject through
+//! a durable pull consumer named after its client ID. A command is acknowledged
+//! once its result has been published, so delivery is at-least-once: a client
+//! that dies while running a command gets it again after restarting.
+
+use crate::{sanitize_client_id, RsNatsError, SubjectLayout};
+use async_nats::jetstream::{self, consumer, stream};
+use async_nats::Client;
+use std::time::Duration;
+
+/// How long undelivered commands are kept before they are discarded
+pub const COMMAND_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Name of the stream holding commands for `prefix`
+pub fn stream_name(prefix: &str) -> String {
+    format!("{}_commands", sanitize_client_id(prefix))
+}
+
+/// Create the command stream for `prefix` unless it already exists
+pub async fn ensure_command_stream(nats: &Client, subjects: &SubjectLayout, prefix: &str) -> Result<stream::Stream, RsNatsError> {
+    let name = stream_name(prefix);
+    jetstream::new(nats.clone())
+        .get_or_create_stream(stream::Config {
+            name: name.clone(),
+            subjects: vec![subjects.commands(prefix)],
+            retention: stream::RetentionPolicy::WorkQueue,
+            max_age: COMMAND_MAX_AGE,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| RsNatsError::ConnectionError(format!("Failed to set up JetStream stream {}: {}", name, e)))
+}
+
+/// Publish a command payload and wait for the stream to confirm it was stored
+pub async fn publish_command(nats: &Client, subject: String, payload: Vec<u8>) -> Result<(), RsNatsError> {
+    let stored = jetstream::new(nats.clone())
+        .publish(subject.clone(), payload.into())
+        .await
+        .map_err(|e| RsNatsError::ConnectionError(format!("Failed to publish to {}: {}", subject, e)))?;
+    stored
+        .await
+        .map_err(|e| RsNatsError::ConnectionError(format!("JetStream did not store command for {}: {}", subject, e)))?;
+    Ok(())
+}
+
+/// Open the durable consumer for `client_id`'s commands and start pulling from it.
+/// Commands not acknowledged within `ack_wait` are delivered again.
+pub async fn consume_commands(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    client_id: &str,
+    ack_wait: Duration,
+) -> Result<consumer::pull::Stream, RsNatsError> {
+    let stream = ensure_command_stream(nats, subjects, prefix).await?;
+    let consumer = stream
+        .get_or_create_consumer(client_id, consumer::pull::Config {
+            durable_name: Some(client_id.to_string()),
+            filter_subject: subjects.command(prefix, client_id),
+            ack_policy: consumer::AckPolicy::Explicit,
+            ack_wait,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| RsNatsError::ConnectionError(format!("Failed to set up JetStream consumer {}: {}", client_id, e)))?;
+    consumer
+        .messages()
+        .await
+        .map_err(|e| RsNatsError::ConnectionError(format!("Failed to consume commands: {}", e)))
+}
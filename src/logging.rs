@@ -0,0 +1,255 @@
+//! Global logger whose level can be changed while running
+
+use crate::{LogLevel, RsNatsError};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock};
+use std::time::SystemTime;
+
+/// Filter used when `RUST_LOG` isn't set, in `RUST_LOG` syntax
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
+/// Rotated log files kept unless told otherwise; older ones are deleted
+pub const DEFAULT_LOG_KEEP: usize = 5;
+
+/// How log records are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line with timestamp, level, target, and message fields
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = RsNatsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(RsNatsError::ConfigError(format!(
+                "Unknown log format {}, expected text or json", other
+            ))),
+        }
+    }
+}
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+static LOG_FILE: OnceLock<LogFile> = OnceLock::new();
+
+/// Formats records with env_logger but filters them with a filter that can be swapped
+struct ReloadableLogger {
+    inner: env_logger::Logger,
+    filter: RwLock<env_filter::Filter>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.filter.read().unwrap_or_else(PoisonError::into_inner).enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.filter.read().unwrap_or_else(PoisonError::into_inner).matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install `builder` as the global logger, filtered by `RUST_LOG` or `DEFAULT_LOG_FILTER`.
+/// Filters configured on `builder` itself are ignored; use `set_log_level` to change
+/// the level afterwards.
+pub fn init(mut builder: env_logger::Builder) -> Result<(), RsNatsError> {
+    let spec = std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_string());
+    let filter = env_filter::Builder::new().parse(&spec).build();
+    let max_level = filter.filter();
+
+    let logger = ReloadableLogger {
+        inner: builder.filter_level(LevelFilter::Trace).build(),
+        filter: RwLock::new(filter),
+    };
+    if LOGGER.set(logger).is_err() {
+        return Err(RsNatsError::ConfigError("logger is already initialized".to_string()));
+    }
+
+    let logger = LOGGER.get().expect("logger was just set");
+    log::set_logger(logger)
+        .map_err(|e| RsNatsError::ConfigError(format!("Failed to install logger: {}", e)))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Append log lines to the file at `path` instead of stderr, starting a new file once
+/// it reaches `max_bytes` if given. The file can then be rotated with `rotate_log_file`.
+/// Only the `keep` most recently rotated files are kept.
+pub fn log_to_file(builder: &mut env_logger::Builder, path: &Path, max_bytes: Option<u64>, keep: usize) -> Result<(), RsNatsError> {
+    let file = LogFile::open(path, max_bytes, keep).map_err(|e| {
+        RsNatsError::ConfigError(format!("failed to open log file {}: {}", path.display(), e))
+    })?;
+    if LOG_FILE.set(file.clone()).is_err() {
+        return Err(RsNatsError::ConfigError("log file is already set".to_string()));
+    }
+    builder.target(env_logger::Target::Pipe(Box::new(file)));
+    Ok(())
+}
+
+/// Rename the log file set with `log_to_file` to its name plus the current time and
+/// continue in a fresh file, returning the path the old one was renamed to
+pub fn rotate_log_file() -> Result<PathBuf, RsNatsError> {
+    let file = LOG_FILE.get().ok_or_else(|| {
+        RsNatsError::ConfigError("log is not written to a file set with logging::log_to_file".to_string())
+    })?;
+    file.rotate()
+        .map_err(|e| RsNatsError::ConfigError(format!("failed to rotate log file: {}", e)))
+}
+
+/// Log file that can be renamed away and replaced while the logger writes to it.
+/// Clones write to the same file.
+#[derive(Clone)]
+struct LogFile(Arc<Mutex<LogFileState>>);
+
+struct LogFileState {
+    path: PathBuf,
+    /// Only unset while rotating
+    file: Option<File>,
+    /// Size of the current file
+    written: u64,
+    max_bytes: Option<u64>,
+    /// How many rotated files to keep
+    keep: usize,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_bytes: Option<u64>, keep: usize) -> io::Result<Self> {
+        let file = open_append(path)?;
+        let written = file.metadata()?.len();
+        Ok(LogFile(Arc::new(Mutex::new(LogFileState {
+            path: path.to_path_buf(),
+            file: Some(file),
+            written,
+            max_bytes,
+            keep,
+        }))))
+    }
+    
+    fn rotate(&self) -> io::Result<PathBuf> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).rotate()
+    }
+}
+
+impl LogFileState {
+    fn rotate(&mut self) -> io::Result<PathBuf> {
+        // Colons aren't allowed in Windows file names
+        let stamp: String = humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+            .chars().filter(|c| !matches!(c, '-' | ':')).collect();
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", stamp));
+        let rotated = PathBuf::from(rotated);
+        
+        // Windows can't rename a file that is still open
+        drop(self.file.take());
+        let renamed = std::fs::rename(&self.path, &rotated);
+        let file = open_append(&self.path)?;
+        self.written = file.metadata()?.len();
+        self.file = Some(file);
+        renamed?;
+        
+        if let Err(e) = self.remove_old() {
+            eprintln!("Failed to remove old log files of {}: {}", self.path.display(), e);
+        }
+        Ok(rotated)
+    }
+    
+    /// Delete all but the `keep` newest rotated files. Their names end in the time
+    /// they were rotated, which sorts in the order they were.
+    fn remove_old(&self) -> io::Result<()> {
+        let (Some(dir), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        let prefix = format!("{}.", name.to_string_lossy());
+        let mut rotated: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_str().and_then(|name| name.strip_prefix(&prefix))
+                .is_some_and(is_rotation_stamp))
+            .map(|entry| entry.path())
+            .collect();
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.keep);
+        for path in &rotated[..excess] {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `suffix` is a time as `LogFileState::rotate` puts it in file names, like
+/// `20240309T101542.318Z`, so other files next to the log are left alone
+fn is_rotation_stamp(suffix: &str) -> bool {
+    suffix.len() == 20 && suffix.ends_with('Z') && suffix.as_bytes()[8] == b'T'
+        && suffix[..19].chars().all(|c| c.is_ascii_digit() || c == 'T' || c == '.')
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.max_bytes.is_some_and(|max| state.written > 0 && state.written + buf.len() as u64 > max) {
+            // Logging the failure would come straight back here
+            if let Err(e) = state.rotate() {
+                eprintln!("Failed to rotate log file {}: {}", state.path.display(), e);
+                // Try again after another `max_bytes` rather than on every line
+                state.written = 0;
+            }
+        }
+        
+        let file = state.file.as_mut().ok_or_else(|| io::Error::other("log file is closed"))?;
+        let written = file.write(buf)?;
+        state.written += written as u64;
+        Ok(written)
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0.lock().unwrap_or_else(PoisonError::into_inner).file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Log everything at `level` and above from every target, replacing the filter
+/// from `RUST_LOG`. Returns the now active level. Only works once `init` has run.
+pub fn set_log_level(level: LogLevel) -> Result<LevelFilter, RsNatsError> {
+    let logger = LOGGER.get().ok_or_else(|| {
+        RsNatsError::ConfigError("logger was not installed with logging::init".to_string())
+    })?;
+
+    let filter = env_filter::Builder::new().filter_level(level.into()).build();
+    let active = filter.filter();
+    *logger.filter.write().unwrap_or_else(PoisonError::into_inner) = filter;
+    log::set_max_level(active);
+    Ok(active)
+}
@@ -1,23 +1,108 @@
 use clap::{Parser, Subcommand};
 use env_logger::Env;
 use log::info;
-use anyhow::Result;
-
-// Import local modules
-mod client;
-mod server;
+use anyhow::{bail, ensure, Result};
+use rs_nats_lib::logging::{LogFormat, DEFAULT_LOG_KEEP};
+use rs_nats_lib::service::DEFAULT_SERVICE_NAME;
+use rs_nats_lib::{
+    client, logging, server, service, tui, Codec, Compression, Config, Encoding, CommandPolicy, ConnectionOptions, DEFAULT_COMMAND_TIMEOUT_SECS, DEFAULT_HEARTBEAT_SECS,
+    DEFAULT_APPROVAL_TIMEOUT_SECS, DEFAULT_HISTORY_SIZE, DEFAULT_MAX_CONCURRENT_COMMANDS, DEFAULT_MAX_DECOMPRESSED_BYTES, DEFAULT_MAX_DOWNLOAD_BYTES, DEFAULT_MAX_OUTPUT_BYTES, DEFAULT_NATS_URL, DEFAULT_RATE_LIMIT, DEFAULT_RESPONSE_TIMEOUT_SECS, DEFAULT_STALE_HEARTBEATS, Command, Shell,
+    ApprovalRequest, DEFAULT_SUBJECT_PREFIX, connect, format_bytes, health_check, parse_tag, redact_url,
+};
+use std::ffi::OsString;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// Read defaults from this TOML or YAML file; flags override it [default: rs-nats/config.toml in the user config directory, if present]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    
     /// NATS server URL
     #[arg(short, long, value_name = "URL")]
     nats_url: Option<String>,
     
-    /// Subject prefix for NATS messages
+    /// Subject prefix for NATS messages. The server can be given several to serve
+    /// clients in each of these namespaces [default: rs-support]
     #[arg(short, long, value_name = "PREFIX")]
-    subject_prefix: Option<String>,
+    subject_prefix: Vec<String>,
+    
+    /// Message encoding, must match on server and clients (json or msgpack) [default: json]
+    #[arg(long, value_name = "CODEC")]
+    codec: Option<Encoding>,
+    
+    /// Compress payloads over 1 KiB with gzip or zstd before sending them. Receivers
+    /// decompress automatically [default: none]
+    #[arg(long, value_name = "ALGORITHM")]
+    compress: Option<Compression>,
+    
+    /// Refuse compressed payloads that decompress to more than this many bytes
+    /// [default: 64 MiB]
+    #[arg(long, value_name = "BYTES")]
+    max_decompressed_bytes: Option<usize>,
+    
+    /// Encrypt message payloads with a key derived from this passphrase, must match on server and clients
+    #[arg(long, value_name = "PASSPHRASE", env = "RS_NATS_ENCRYPTION_KEY", hide_env_values = true)]
+    encryption_key: Option<String>,
+    
+    /// Authenticate to NATS with this token, for servers set up with token authorization
+    #[arg(long, value_name = "TOKEN", env = "RS_NATS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    
+    /// Authenticate to NATS with this user name, together with --password
+    #[arg(long, value_name = "USER", env = "RS_NATS_USER", requires = "password")]
+    user: Option<String>,
+    
+    /// Password for --user
+    #[arg(long, value_name = "PASSWORD", env = "RS_NATS_PASSWORD", hide_env_values = true, requires = "user")]
+    password: Option<String>,
+    
+    /// Seconds between client heartbeats, must match on server and clients [default: 30]
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat_secs: Option<u64>,
+    
+    /// Give up after this many consecutive failed NATS reconnects [default: retry forever]
+    #[arg(long, value_name = "COUNT")]
+    max_reconnects: Option<usize>,
+    
+    /// Fixed delay between NATS reconnect attempts [default: exponential backoff]
+    #[arg(long, value_name = "MS")]
+    reconnect_delay_ms: Option<u64>,
+    
+    /// Seconds between pings to the NATS server [default: 60]
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval_secs: Option<u64>,
+    
+    /// Log output format, text or json [default: text]
+    #[arg(long, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+    
+    /// Append log lines to this file instead of writing them to stderr. A client can
+    /// send the end of it to the server with `logtail`
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    
+    /// Rename the log file to its name plus the time and start a new one once it
+    /// reaches this many bytes [default: never]
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    log_max_bytes: Option<u64>,
+    
+    /// Keep this many rotated log files, deleting older ones [default: 5]
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    log_keep: Option<u32>,
+    
+    /// Deliver commands through JetStream so offline clients receive them later, must match on server and clients
+    #[arg(long)]
+    jetstream: bool,
+    
+    /// Run under the Windows service manager as this service, set by install-service
+    #[arg(long, value_name = "NAME", hide = true)]
+    windows_service: Option<String>,
     
     #[command(subcommand)]
     command: Commands,
@@ -26,44 +111,612 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Run in server mode (support provider)
-    Server {},
+    Server {
+        /// Drop clients after this many heartbeat intervals without a heartbeat [default: 3]
+        #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+        stale_heartbeats: Option<u32>,
+        
+        /// Save known clients to this JSON file and restore them on startup
+        #[arg(long, value_name = "FILE")]
+        registry_path: Option<PathBuf>,
+        
+        /// Share registrations and responses with other servers in this NATS queue group
+        #[arg(long, value_name = "NAME")]
+        queue_group: Option<String>,
+        
+        /// Serve Prometheus metrics at http://ADDR/metrics, e.g. 127.0.0.1:9100
+        #[arg(long, value_name = "ADDR")]
+        metrics_addr: Option<SocketAddr>,
+        
+        /// Commands and results to remember per client for `history` [default: 100]
+        #[arg(long, value_name = "COUNT")]
+        history_size: Option<usize>,
+        
+        /// Append every command sent and result received to this file as JSON lines
+        #[arg(long, value_name = "FILE")]
+        audit_log: Option<PathBuf>,
+        
+        /// Also append console output and the commands typed to this file, as shown
+        /// but without colors
+        #[arg(long, value_name = "FILE")]
+        transcript: Option<PathBuf>,
+        
+        /// Relay commands other services request on <prefix>.api.execute to clients and
+        /// reply with the results
+        #[arg(long)]
+        api: bool,
+        
+        /// Use a full-screen dashboard instead of the line-based console
+        #[arg(long)]
+        tui: bool,
+        
+        /// Print console output without colors. Colors are also left out when NO_COLOR
+        /// is set or stdout is not a terminal
+        #[arg(long)]
+        no_color: bool,
+        
+        /// Print clients, system information, and results as text or as one JSON value per line [default: text]
+        #[arg(long, value_name = "FORMAT")]
+        output: Option<server::OutputFormat>,
+        
+        /// Hold dangerous console commands (reboot, power off, uploads, kills, restarts, and
+        /// shell commands matching --dangerous-pattern) until they are approved with
+        /// `rs-nats approve`, from NATS credentials other than the server's
+        #[arg(long)]
+        require_approval: bool,
+        
+        /// Deny held commands nobody approved within this many seconds [default: 300]
+        #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        approval_timeout_secs: Option<u64>,
+        
+        /// Shell command pattern that needs approval, `*` matching anything; replaces the
+        /// built-in list (rm, dd, mkfs, shutdown, ...) [repeatable]
+        #[arg(long = "dangerous-pattern", value_name = "PATTERN")]
+        dangerous_patterns: Vec<String>,
+    },
+    
+    /// Send a single command to a client, print its result, and exit non-zero if it failed
+    #[command(group = clap::ArgGroup::new("action").required(true))]
+    Send {
+        /// Client to send the command to
+        #[arg(short, long, value_name = "ID")]
+        client_id: String,
+        
+        /// Run this shell command on the client
+        #[arg(long, value_name = "CMD", group = "action")]
+        exec: Option<String>,
+        
+        /// Check that the client is responsive
+        #[arg(long, group = "action")]
+        ping: bool,
+        
+        /// Print the client's system information as JSON
+        #[arg(long, group = "action")]
+        sysinfo: bool,
+        
+        /// Seconds to wait for the result
+        #[arg(long, value_name = "SECS", default_value_t = DEFAULT_RESPONSE_TIMEOUT_SECS,
+            value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: u64,
+    },
+    
+    /// Approve a command held by a server started with --require-approval, which then sends it
+    Approve {
+        /// Id the command is held as, shown by the server's console
+        request_id: String,
+        
+        /// Seconds to wait for the server holding it to answer
+        #[arg(long, value_name = "SECS", default_value_t = DEFAULT_RESPONSE_TIMEOUT_SECS,
+            value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: u64,
+    },
+    
+    /// Deny a command held by a server started with --require-approval, which then drops it
+    Deny {
+        /// Id the command is held as, shown by the server's console
+        request_id: String,
+        
+        /// Seconds to wait for the server holding it to answer
+        #[arg(long, value_name = "SECS", default_value_t = DEFAULT_RESPONSE_TIMEOUT_SECS,
+            value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: u64,
+    },
+    
+    /// Check that the NATS server is reachable and passes messages, exiting non-zero if not
+    Health {
+        /// Seconds to wait for the connection, and then for the message to come back
+        #[arg(long, value_name = "SECS", default_value_t = 5,
+            value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: u64,
+    },
     
     /// Run in client mode (support recipient)
     Client {
         /// Override the auto-generated client ID
         #[arg(short, long, value_name = "ID")]
         client_id: Option<String>,
+        
+        /// Kill shell commands that run longer than this many seconds [default: 30]
+        #[arg(long, value_name = "SECS")]
+        command_timeout: Option<u64>,
+        
+        /// Only allow file transfers inside this directory
+        #[arg(long, value_name = "DIR")]
+        file_root: Option<PathBuf>,
+        
+        /// Refuse to send back files larger than this many bytes [default: 524288]
+        #[arg(long, value_name = "BYTES")]
+        max_download_bytes: Option<u64>,
+        
+        /// Cut off stdout and stderr of shell commands after this many bytes each [default: 1048576]
+        #[arg(long, value_name = "BYTES")]
+        max_output_bytes: Option<u64>,
+        
+        /// Only run shell commands starting with this prefix (repeatable)
+        #[arg(long = "allow-command", value_name = "PREFIX")]
+        allow_commands: Vec<String>,
+        
+        /// Refuse shell commands containing this text (repeatable)
+        #[arg(long = "deny-command", value_name = "PATTERN")]
+        deny_commands: Vec<String>,
+        
+        /// Load allow/deny rules from a file, one "allow <prefix>" or "deny <pattern>" per line
+        #[arg(long, value_name = "FILE")]
+        command_policy: Option<PathBuf>,
+        
+        /// Label this client for grouping on the server, e.g. role=web (repeatable)
+        #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+        tags: Vec<(String, String)>,
+        
+        /// Refuse requests to reboot or power off this machine
+        #[arg(long)]
+        disable_power_commands: bool,
+        
+        /// Hide values of environment variables matching this pattern, with `*` as a
+        /// wildcard (repeatable, replaces the default list of *SECRET*, *TOKEN*, *PASSWORD*, ...)
+        #[arg(long, value_name = "PATTERN")]
+        redact_env: Vec<String>,
+        
+        /// Seconds to wait before retrying registration while no server answers [default: 2]
+        #[arg(long, value_name = "SECS")]
+        retry_initial_secs: Option<f64>,
+        
+        /// Longest wait between registration retries, in seconds [default: 60]
+        #[arg(long, value_name = "SECS")]
+        retry_max_secs: Option<f64>,
+        
+        /// Factor the wait between retries grows by [default: 1.5]
+        #[arg(long, value_name = "FACTOR")]
+        retry_multiplier: Option<f64>,
+        
+        /// Exit after this many failed registration attempts [default: retry forever]
+        #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+        retry_max_attempts: Option<u32>,
+        
+        /// Make each wait between retries randomly up to this fraction shorter or longer,
+        /// so clients don't all retry at once after an outage, 0 for none [default: 0.2]
+        #[arg(long, value_name = "FRACTION")]
+        retry_jitter: Option<f64>,
+        
+        /// Refuse commands arriving faster than this many a second on average, 0 for no limit [default: 10]
+        #[arg(long, value_name = "PER_SEC")]
+        rate_limit: Option<f64>,
+        
+        /// Run at most this many shell commands and file transfers at once, queueing the rest [default: 4]
+        #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+        max_concurrent_commands: Option<u32>,
+        
+        /// Remember the client id in this directory and resume it after a restart when --client-id isn't given
+        #[arg(long, value_name = "DIR")]
+        state_dir: Option<PathBuf>,
+        
+        /// Run commands that don't name a shell with sh, bash, pwsh, cmd, or directly without
+        /// a shell [default: cmd on Windows, sh elsewhere]
+        #[arg(long, value_name = "SHELL")]
+        shell: Option<Shell>,
+    },
+    
+    /// Install and start the client as a systemd service (Linux) or Windows service. It runs
+    /// `client` with the global options given before install-service and the client options after `--`
+    InstallService {
+        /// Name of the service
+        #[arg(long, value_name = "NAME", default_value = DEFAULT_SERVICE_NAME)]
+        name: String,
+        
+        /// Options for the client subcommand, e.g. -- --tag role=web
+        #[arg(last = true, value_name = "CLIENT_OPTIONS")]
+        client_args: Vec<OsString>,
+    },
+    
+    /// Stop and remove a service added with install-service
+    UninstallService {
+        /// Name of the service
+        #[arg(long, value_name = "NAME", default_value = DEFAULT_SERVICE_NAME)]
+        name: String,
     },
 }
 
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    
+    #[cfg(windows)]
+    if let Some(name) = cli.windows_service.clone() {
+        return service::run_windows_service(&name, move || run(cli));
+    }
+    ensure!(cli.windows_service.is_none(), "--windows-service only works on Windows");
+    run(cli)
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn run(cli: Cli) -> Result<()> {
+    let config = Config::load(cli.config.as_deref())?;
+    let windows_service = cli.windows_service.is_some();
+    
+    // Flags win over the config file, which wins over the built-in defaults
+    let nats_url = cli.nats_url.or(config.nats_url);
+    let subject_prefixes = if cli.subject_prefix.is_empty() { config.subject_prefix } else { cli.subject_prefix };
+    let subject_prefixes: Vec<&str> = subject_prefixes.iter().map(String::as_str).collect();
+    // Only a server can listen in several namespaces
+    if !matches!(cli.command, Commands::Server { .. }) {
+        ensure!(subject_prefixes.len() <= 1, "only the server accepts more than one subject prefix");
+    }
+    let subject_prefix = subject_prefixes.first().copied();
+    let heartbeat_secs = cli.heartbeat_secs.or(config.heartbeat_secs).unwrap_or(DEFAULT_HEARTBEAT_SECS);
+    let ping_interval_secs = cli.ping_interval_secs.or(config.ping_interval_secs).unwrap_or(60);
+    let jetstream = cli.jetstream || config.jetstream.unwrap_or(false);
+    let subjects = config.subjects;
+    ensure!(heartbeat_secs > 0, "heartbeat_secs must be at least 1");
+    ensure!(ping_interval_secs > 0, "ping_interval_secs must be at least 1");
+    let user_and_password = match (cli.user.or(config.user), cli.password.or(config.password)) {
+        (Some(user), Some(password)) => Some((user, password)),
+        (None, None) => None,
+        _ => bail!("user and password must be given together"),
+    };
+    
     // Initialize logger
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    // The filter comes from RUST_LOG inside logging::init so it can be changed at runtime
+    let mut logger = env_logger::Builder::from_env(Env::new().write_style("RUST_LOG_STYLE"));
+    if let LogFormat::Json = cli.log_format.or(config.log_format).unwrap_or_default() {
+        logger.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    let log_file = cli.log_file.clone().or(config.log_file.clone());
+    let log_max_bytes = cli.log_max_bytes.or(config.log_max_bytes);
+    let log_keep = cli.log_keep.map(|keep| keep as usize).or(config.log_keep);
+    // Otherwise a rotation would delete the file it just renamed
+    ensure!(log_keep != Some(0), "log_keep must be at least 1");
+    match &log_file {
+        Some(path) => logging::log_to_file(&mut logger, path, log_max_bytes, log_keep.unwrap_or(DEFAULT_LOG_KEEP))?,
+        None if log_max_bytes.is_some() => bail!("log_max_bytes needs a log file"),
+        None if log_keep.is_some() => bail!("log_keep needs a log file"),
+        None => {},
+    }
+    // Log lines would be drawn over the dashboard, so it shows them in its own pane
+    if let Commands::Server { tui: true, .. } = cli.command {
+        logger.target(env_logger::Target::Pipe(Box::new(tui::LogWriter::default())));
+    }
+    logging::init(logger)?;
     
-    let cli = Cli::parse();
+    let connection = ConnectionOptions {
+        max_reconnects: cli.max_reconnects.or(config.max_reconnects),
+        reconnect_delay: cli.reconnect_delay_ms.or(config.reconnect_delay_ms).map(Duration::from_millis),
+        ping_interval: Duration::from_secs(ping_interval_secs),
+        token: cli.token.or(config.token),
+        user_and_password,
+        ..ConnectionOptions::default()
+    };
+    
+    let mut codec = Codec::from(cli.codec.or(config.codec).unwrap_or_default())
+        .with_compression(cli.compress.or(config.compress).unwrap_or_default())
+        .with_decompression_limit(cli.max_decompressed_bytes.or(config.max_decompressed_bytes).unwrap_or(DEFAULT_MAX_DECOMPRESSED_BYTES));
+    if let Some(passphrase) = cli.encryption_key.as_ref().or(config.encryption_key.as_ref()) {
+        codec = codec.with_passphrase(passphrase)?;
+        info!("Payload encryption enabled");
+    }
     
     match &cli.command {
-        Commands::Server {} => {
+        Commands::Server {
+            stale_heartbeats, registry_path, queue_group, metrics_addr, history_size, audit_log, transcript, api, tui, no_color, output,
+            require_approval, approval_timeout_secs, dangerous_patterns,
+        } => {
             info!("Starting in server mode");
+            let config = config.server;
+            if *no_color || config.no_color.unwrap_or(false) {
+                colored::control::set_override(false);
+            }
+            let stale_heartbeats = stale_heartbeats.or(config.stale_heartbeats).unwrap_or(DEFAULT_STALE_HEARTBEATS);
+            ensure!(stale_heartbeats > 0, "stale_heartbeats must be at least 1");
+            let mut builder = server::Server::builder()
+                .codec(codec)
+                .heartbeat_interval(Duration::from_secs(heartbeat_secs))
+                .stale_heartbeats(stale_heartbeats)
+                .connection(connection)
+                .history_size(history_size.or(config.history_size).unwrap_or(DEFAULT_HISTORY_SIZE))
+                .output(output.or(config.output).unwrap_or_default())
+                .api(*api || config.api.unwrap_or(false))
+                .jetstream(jetstream);
+            if let Some(url) = &nats_url {
+                builder = builder.nats_url(url);
+            }
+            for prefix in &subject_prefixes {
+                builder = builder.subject_prefix(*prefix);
+            }
+            builder = builder.subject_layout(subjects);
+            if let Some(path) = registry_path.clone().or(config.registry_path) {
+                builder = builder.registry_path(path);
+            }
+            if let Some(group) = queue_group.clone().or(config.queue_group) {
+                builder = builder.queue_group(group);
+            }
+            if let Some(addr) = metrics_addr.or(config.metrics_addr) {
+                builder = builder.metrics_addr(addr);
+            }
+            if let Some(path) = audit_log.clone().or(config.audit_log) {
+                builder = builder.audit_log(path);
+            }
+            if let Some(path) = transcript.clone().or(config.transcript) {
+                ensure!(!*tui, "--transcript only works with the line-based console, not --tui");
+                builder = builder.transcript(path);
+            }
+            if *require_approval || config.require_approval.unwrap_or(false) {
+                // The dashboard sends commands straight away
+                ensure!(!*tui, "--require-approval only works with the line-based console, not --tui");
+                let timeout = approval_timeout_secs.or(config.approval_timeout_secs).unwrap_or(DEFAULT_APPROVAL_TIMEOUT_SECS);
+                ensure!(timeout > 0, "approval_timeout_secs must be at least 1");
+                builder = builder.require_approval(Duration::from_secs(timeout));
+            }
+            if !dangerous_patterns.is_empty() {
+                builder = builder.dangerous_patterns(dangerous_patterns.clone());
+            } else if let Some(patterns) = config.dangerous_patterns {
+                builder = builder.dangerous_patterns(patterns);
+            }
+            let server = builder.build().await?;
+            
+            if *tui {
+                server.run_tui().await?;
+            } else {
+                server.run().await?;
+            }
+        },
+        Commands::Send { client_id, exec, ping, sysinfo, timeout } => {
+            let cmd = match (exec, ping, sysinfo) {
+                (Some(command), _, _) => Command::Execute {
+                    command: command.clone(),
+                    cwd: None,
+                    env: None,
+                    stream: false,
+                    stdin: None,
+                    shell: None,
+                },
+                (_, true, _) => Command::Ping,
+                (_, _, true) => Command::GetSystemInfo,
+                _ => unreachable!("clap requires one action"),
+            };
+            
             let server = server::Server::new(
-                cli.nats_url.as_deref(),
-                cli.subject_prefix.as_deref(),
+                nats_url.as_deref(),
+                &subject_prefixes,
+                Some(codec),
+                None,
+                None,
+                Some(connection),
+                None,
+                None,
+                None,
+                jetstream,
             ).await?;
-            
-            server.run().await?;
+            let result = server.send_command_with_timeout(client_id, cmd, Duration::from_secs(*timeout)).await?;
+            print!("{}", result.output);
+            if !result.output.is_empty() && !result.output.ends_with('\n') {
+                println!();
+            }
+            eprint!("{}", result.stderr);
+            if !result.stderr.is_empty() && !result.stderr.ends_with('\n') {
+                eprintln!();
+            }
+            if let Some(err) = &result.error {
+                eprintln!("{}", err);
+            }
+            if !result.success {
+                std::process::exit(1);
+            }
         },
-        Commands::Client { client_id } => {
+        Commands::Approve { request_id, timeout } | Commands::Deny { request_id, timeout } => {
+            let approve = matches!(cli.command, Commands::Approve { .. });
+            let url = nats_url.as_deref().unwrap_or(DEFAULT_NATS_URL);
+            let nats = connect(url, &connection).await?;
+            let hostname = whoami::fallible::hostname().unwrap_or_else(|_| "unknown-host".to_string());
+            let request = ApprovalRequest {
+                request_id: request_id.clone(),
+                approve,
+                approver: format!("{}@{}", whoami::username(), hostname),
+            };
+            let prefix = subject_prefixes.first().copied().unwrap_or(DEFAULT_SUBJECT_PREFIX);
+            let description = server::send_decision(&nats, &subjects, prefix, codec, &request, Duration::from_secs(*timeout)).await?;
+            println!("{} {}", if approve { "Approved" } else { "Denied" }, description);
+        },
+        Commands::Health { timeout } => {
+            let url = nats_url.as_deref().unwrap_or(DEFAULT_NATS_URL);
+            let report = health_check(url, &connection, Duration::from_secs(*timeout)).await?;
+            println!("NATS server:  {} ({}, version {})", redact_url(url), report.server_name, report.server_version);
+            println!("Max payload:  {}", format_bytes(report.max_payload as u64));
+            println!("Connect time: {:.1} ms", report.connect_time.as_secs_f64() * 1000.0);
+            println!("Round trip:   {:.1} ms", report.round_trip.as_secs_f64() * 1000.0);
+            println!("OK");
+        },
+        Commands::Client {
+            client_id,
+            command_timeout,
+            file_root,
+            max_download_bytes,
+            max_output_bytes,
+            allow_commands,
+            deny_commands,
+            command_policy,
+            tags,
+            disable_power_commands,
+            redact_env,
+            retry_initial_secs,
+            retry_max_secs,
+            retry_multiplier,
+            retry_max_attempts,
+            retry_jitter,
+            rate_limit,
+            max_concurrent_commands,
+            state_dir,
+            shell,
+        } => {
             info!("Starting in client mode");
-            let client = client::SupportClient::new(
-                cli.nats_url.as_deref(),
-                cli.subject_prefix.as_deref(),
-                client_id.as_deref(),
-            ).await?;
+            let mut config = config.client;
+            let mut policy = match command_policy.as_ref().or(config.command_policy.as_ref()) {
+                Some(path) => CommandPolicy::from_file(path)?,
+                None => CommandPolicy::default(),
+            };
+            // Rules given as flags replace the config file's rather than adding to them
+            if !allow_commands.is_empty() {
+                config.allow_commands = allow_commands.clone();
+            }
+            if !deny_commands.is_empty() {
+                config.deny_commands = deny_commands.clone();
+            }
+            policy.allow.extend(config.allow_commands);
+            policy.deny.extend(config.deny_commands);
+            if !policy.is_empty() {
+                info!("Command policy: {} allow rules, {} deny rules", policy.allow.len(), policy.deny.len());
+            }
+            
+            // Tags from flags are added to the config file's, replacing any with the same key
+            config.tags.extend(tags.iter().cloned());
+            
+            let mut retry = client::RetryConfig::default();
+            if let Some(secs) = retry_initial_secs.or(config.retry_initial_secs) {
+                retry.initial_backoff = secs_duration(secs, "retry_initial_secs")?;
+            }
+            if let Some(secs) = retry_max_secs.or(config.retry_max_secs) {
+                retry.max_backoff = secs_duration(secs, "retry_max_secs")?;
+            }
+            if let Some(multiplier) = retry_multiplier.or(config.retry_multiplier) {
+                retry.multiplier = multiplier;
+            }
+            retry.max_attempts = retry_max_attempts.or(config.retry_max_attempts);
+            if let Some(jitter) = retry_jitter.or(config.retry_jitter) {
+                retry.jitter = jitter;
+            }
+            
+            let mut builder = client::SupportClient::builder()
+                .command_timeout(Duration::from_secs(command_timeout.or(config.command_timeout).unwrap_or(DEFAULT_COMMAND_TIMEOUT_SECS)))
+                .max_download_bytes(max_download_bytes.or(config.max_download_bytes).unwrap_or(DEFAULT_MAX_DOWNLOAD_BYTES))
+                .max_output_bytes(max_output_bytes.or(config.max_output_bytes).unwrap_or(DEFAULT_MAX_OUTPUT_BYTES))
+                .codec(codec)
+                .heartbeat_interval(Duration::from_secs(heartbeat_secs))
+                .connection(connection)
+                .policy(policy)
+                .tags(config.tags)
+                .jetstream(jetstream)
+                .power_commands(!(*disable_power_commands || config.disable_power_commands.unwrap_or(false)))
+                .retry(retry)
+                .rate_limit(rate_limit.or(config.rate_limit).unwrap_or(DEFAULT_RATE_LIMIT))
+                .max_concurrent_commands(max_concurrent_commands.or(config.max_concurrent_commands).unwrap_or(DEFAULT_MAX_CONCURRENT_COMMANDS) as usize)
+                .shell(shell.or(config.shell).unwrap_or_default())
+                .windows_service(windows_service);
+            if let Some(url) = &nats_url {
+                builder = builder.nats_url(url);
+            }
+            if let Some(prefix) = subject_prefix {
+                builder = builder.subject_prefix(prefix);
+            }
+            builder = builder.subject_layout(subjects);
+            if let Some(id) = client_id.clone().or(config.client_id) {
+                builder = builder.client_id(id);
+            }
+            if let Some(root) = file_root.clone().or(config.file_root) {
+                builder = builder.file_root(root);
+            }
+            if let Some(dir) = state_dir.clone().or(config.state_dir) {
+                builder = builder.state_dir(dir);
+            }
+            if let Some(path) = &log_file {
+                builder = builder.log_file(path);
+            }
+            if let Some(patterns) = if redact_env.is_empty() { config.redact_env } else { Some(redact_env.clone()) } {
+                builder = builder.redact_env(patterns);
+            }
+            let client = builder.build().await?;
             
             client.run().await?;
-        }
+        },
+        Commands::InstallService { name, client_args } => {
+            let args = service_args(client_args)?;
+            service::install(name, &args)?;
+        },
+        Commands::UninstallService { name } => {
+            service::uninstall(name)?;
+        },
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Options that take a secret, which would be readable by anyone on the machine in
+/// the unit file or the service's command line
+const SECRET_OPTIONS: [&str; 3] = ["--encryption-key", "--token", "--password"];
+
+/// Arguments that run the client with the global options this process was started
+/// with and `client_args`. A relative `--config` path is made absolute, since
+/// services don't start in the current directory. Secrets have to come from the
+/// config file instead of the command line.
+fn service_args(client_args: &[OsString]) -> Result<Vec<OsString>> {
+    let mut args: Vec<OsString> = std::env::args_os().skip(1)
+        .take_while(|arg| arg != "install-service")
+        .collect();
+    for (i, arg) in args.iter().enumerate() {
+        let arg = arg.to_string_lossy();
+        if let Some(option) = SECRET_OPTIONS.iter().find(|option| arg == **option || arg.starts_with(&format!("{}=", option))) {
+            bail!("{} would be readable by any user in the service's command line, set it in the --config file instead", option);
+        }
+        let url = match arg.strip_prefix("--nats-url=").or_else(|| arg.strip_prefix("-n")) {
+            Some(url) if !url.is_empty() => url.to_string(),
+            _ if arg == "--nats-url" || arg == "-n" => args.get(i + 1).map(|url| url.to_string_lossy().into_owned()).unwrap_or_default(),
+            _ => continue,
+        };
+        // Whatever redact_url would hide is a user name and password or a token
+        if redact_url(&url) != url {
+            bail!("The credentials in --nats-url would be readable by any user in the service's command line, set nats_url in the --config file instead");
+        }
+    }
+    for i in 0..args.len() {
+        let absolute = match args[i].to_str() {
+            Some(path) if i > 0 && args[i - 1] == "--config" => std::path::absolute(path)?.into_os_string(),
+            Some(arg) if arg.starts_with("--config=") => {
+                let path = std::path::absolute(&arg["--config=".len()..])?;
+                let mut arg = OsString::from("--config=");
+                arg.push(path);
+                arg
+            },
+            _ => continue,
+        };
+        args[i] = absolute;
+    }
+    args.push("client".into());
+    args.extend(client_args.iter().cloned());
+    
+    // Catch mistakes now rather than when the service fails to start
+    let program = std::env::args_os().next().unwrap_or_default();
+    match Cli::try_parse_from(std::iter::once(program).chain(args.iter().cloned())) {
+        Ok(Cli { command: Commands::Client { .. }, .. }) => Ok(args),
+        Ok(_) => bail!("install-service only installs the client"),
+        Err(e) => bail!("Invalid options for the service:\n{}", e),
+    }
+}
+
+/// A duration given in (possibly fractional) seconds by the flag or config key `name`
+fn secs_duration(secs: f64, name: &str) -> Result<Duration> {
+    Duration::try_from_secs_f64(secs).map_err(|_| anyhow::anyhow!("{} must be a non-negative number of seconds, got {}", name, secs))
+}
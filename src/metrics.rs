@@ -0,0 +1,102 @@
+//! Prometheus metrics for the server
+
+use axum::routing::get;
+use axum::Router;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Upper bounds in seconds of the response latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Counters for the server. They are process wide, like a Prometheus default registry.
+pub static SERVER_METRICS: ServerMetrics = ServerMetrics::new();
+
+pub struct ServerMetrics {
+    connected_clients: AtomicU64,
+    commands_sent: AtomicU64,
+    responses_received: AtomicU64,
+    heartbeat_evictions: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64,
+    latency_sum_micros: AtomicU64,
+}
+
+impl ServerMetrics {
+    const fn new() -> Self {
+        Self {
+            connected_clients: AtomicU64::new(0),
+            commands_sent: AtomicU64::new(0),
+            responses_received: AtomicU64::new(0),
+            heartbeat_evictions: AtomicU64::new(0),
+            latency_buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            latency_count: AtomicU64::new(0),
+            latency_sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_connected_clients(&self, count: usize) {
+        self.connected_clients.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn command_sent(&self) {
+        self.commands_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn response_received(&self) {
+        self.responses_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn clients_evicted(&self, count: usize) {
+        self.heartbeat_evictions.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Record how long a client took to answer a command
+    pub fn observe_latency(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("rs_nats_connected_clients", "gauge", "Clients currently registered", &self.connected_clients),
+            ("rs_nats_commands_sent_total", "counter", "Commands published to clients", &self.commands_sent),
+            ("rs_nats_responses_received_total", "counter", "Results received from clients", &self.responses_received),
+            ("rs_nats_heartbeat_evictions_total", "counter", "Clients dropped for missing heartbeats", &self.heartbeat_evictions),
+        ];
+        for (name, kind, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let name = "rs_nats_response_latency_seconds";
+        let _ = writeln!(out, "# HELP {} Time from sending a command to receiving its result", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket.load(Ordering::Relaxed));
+        }
+        let count = self.latency_count.load(Ordering::Relaxed);
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+        out
+    }
+}
+
+/// Serve `SERVER_METRICS` at `/metrics` on `listener` until `shutdown` completes
+pub async fn serve(listener: TcpListener, shutdown: impl Future<Output = ()> + Send + 'static) -> std::io::Result<()> {
+    let app = Router::new().route("/metrics", get(|| async { SERVER_METRICS.render() }));
+    axum::serve(listener, app).with_graceful_shutdown(shutdown).await
+}
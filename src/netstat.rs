@@ -0,0 +1,406 @@
+//! Listing the open TCP and UDP sockets of the machine and the processes owning them,
+//! for `Command::ListConnections`.
+//!
+//! Linux reads `/proc/net` and maps sockets to processes through `/proc/<pid>/fd`,
+//! Windows runs `netstat -ano`, and other Unix systems run `lsof`. Seeing other
+//! users' processes needs root or Administrator; without it their sockets are still
+//! listed on Linux and Windows, just without an owner, and left out by `lsof`.
+
+use crate::ConnectionInfo;
+use std::io;
+
+/// The sockets found, and why the list may be incomplete
+#[derive(Debug, Default)]
+pub struct Listing {
+    pub connections: Vec<ConnectionInfo>,
+    /// Set when sockets or their owners were hidden for lack of privileges
+    pub note: Option<String>,
+}
+
+/// List the machine's open sockets, sorted by protocol and local address.
+/// Blocks while reading the system's tables.
+pub fn list_connections() -> io::Result<Listing> {
+    let mut listing = platform::list_connections()?;
+    listing.connections.sort_by(|a, b| {
+        (&a.protocol, &a.local_addr, &a.remote_addr).cmp(&(&b.protocol, &b.local_addr, &b.remote_addr))
+    });
+    Ok(listing)
+}
+
+/// Name TCP states reported by `netstat` and `lsof` the way Linux does
+#[cfg(any(windows, all(unix, not(target_os = "linux"))))]
+fn normalize_state(state: &str) -> String {
+    match state {
+        "LISTENING" => "LISTEN".to_string(),
+        "SYN_RECEIVED" => "SYN_RECV".to_string(),
+        "FIN_WAIT_1" => "FIN_WAIT1".to_string(),
+        "FIN_WAIT_2" => "FIN_WAIT2".to_string(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::Listing;
+    use crate::ConnectionInfo;
+    use std::collections::HashMap;
+    use std::io;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    /// Socket tables under `/proc/net` and the protocol each lists
+    const TABLES: [(&str, &str); 4] = [("tcp", "tcp"), ("tcp6", "tcp6"), ("udp", "udp"), ("udp6", "udp6")];
+
+    pub fn list_connections() -> io::Result<Listing> {
+        let (owners, hidden_processes) = socket_owners()?;
+        let mut listing = Listing::default();
+        let mut unowned = 0;
+
+        for (table, protocol) in TABLES {
+            let contents = match std::fs::read_to_string(format!("/proc/net/{}", table)) {
+                Ok(contents) => contents,
+                // No IPv6 on this machine
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for line in contents.lines().skip(1) {
+                let Some(entry) = parse_line(line, protocol) else {
+                    continue;
+                };
+                let (mut connection, inode) = entry;
+                match owners.get(&inode) {
+                    Some((pid, process)) => {
+                        connection.pid = Some(*pid);
+                        connection.process = Some(process.clone());
+                    },
+                    // Sockets without an inode, like those in TIME_WAIT, have no owner
+                    None if inode != 0 => unowned += 1,
+                    None => {},
+                }
+                listing.connections.push(connection);
+            }
+        }
+
+        if hidden_processes && unowned > 0 {
+            listing.note = Some(format!(
+                "owner of {} sockets unknown, the client needs to run as root to see other users' processes", unowned
+            ));
+        }
+        Ok(listing)
+    }
+
+    /// Pid and name of the process holding each socket, by inode
+    type Owners = HashMap<u64, (u32, String)>;
+
+    /// Map socket inodes to the process holding them, and tell whether any process's
+    /// file descriptors couldn't be read
+    fn socket_owners() -> io::Result<(Owners, bool)> {
+        let mut owners = HashMap::new();
+        let mut hidden = false;
+        for entry in std::fs::read_dir("/proc")?.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+                continue;
+            };
+            let fds = match std::fs::read_dir(entry.path().join("fd")) {
+                Ok(fds) => fds,
+                Err(e) => {
+                    // Processes that exit while we look are gone, not hidden
+                    hidden |= e.kind() == io::ErrorKind::PermissionDenied;
+                    continue;
+                }
+            };
+            let process = std::fs::read_to_string(entry.path().join("comm"))
+                .map(|comm| comm.trim_end().to_string())
+                .unwrap_or_default();
+            for fd in fds.flatten() {
+                let Ok(target) = std::fs::read_link(fd.path()) else {
+                    continue;
+                };
+                let inode = target.to_str()
+                    .and_then(|target| target.strip_prefix("socket:["))
+                    .and_then(|target| target.strip_suffix(']'))
+                    .and_then(|inode| inode.parse::<u64>().ok());
+                if let Some(inode) = inode {
+                    owners.entry(inode).or_insert_with(|| (pid, process.clone()));
+                }
+            }
+        }
+        Ok((owners, hidden))
+    }
+
+    /// Parse a socket table line such as
+    /// `0: 0100007F:0035 00000000:0000 0A 00000000:00000000 00:00000000 00000000 0 0 12345 ...`
+    /// into the connection and its socket inode
+    fn parse_line(line: &str, protocol: &str) -> Option<(ConnectionInfo, u64)> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 {
+            return None;
+        }
+        let local = parse_address(fields[1])?;
+        let remote = parse_address(fields[2])?;
+        let state = u8::from_str_radix(fields[3], 16).ok()?;
+        let inode = fields[9].parse::<u64>().ok()?;
+
+        let connection = ConnectionInfo {
+            protocol: protocol.to_string(),
+            local_addr: local.to_string(),
+            remote_addr: (!remote.ip().is_unspecified() || remote.port() != 0).then(|| remote.to_string()),
+            // UDP sockets have no state worth showing
+            state: protocol.starts_with("tcp").then(|| tcp_state(state).to_string()),
+            pid: None,
+            process: None,
+        };
+        Some((connection, inode))
+    }
+
+    /// Parse an address such as `0100007F:0035`. The address is printed as 32-bit
+    /// words in the machine's byte order, the port as a plain number.
+    fn parse_address(field: &str) -> Option<SocketAddr> {
+        let (address, port) = field.split_once(':')?;
+        let port = u16::from_str_radix(port, 16).ok()?;
+        let mut bytes = Vec::with_capacity(16);
+        for word in address.as_bytes().chunks(8) {
+            let word = u32::from_str_radix(std::str::from_utf8(word).ok()?, 16).ok()?;
+            bytes.extend_from_slice(&word.to_ne_bytes());
+        }
+        let ip = match bytes.len() {
+            4 => Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?).into(),
+            16 => Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).into(),
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// Names of the kernel's TCP states (`include/net/tcp_states.h`)
+    fn tcp_state(state: u8) -> &'static str {
+        match state {
+            0x01 => "ESTABLISHED",
+            0x02 => "SYN_SENT",
+            0x03 => "SYN_RECV",
+            0x04 => "FIN_WAIT1",
+            0x05 => "FIN_WAIT2",
+            0x06 => "TIME_WAIT",
+            0x07 => "CLOSE",
+            0x08 => "CLOSE_WAIT",
+            0x09 => "LAST_ACK",
+            0x0A => "LISTEN",
+            0x0B => "CLOSING",
+            _ => "UNKNOWN",
+        }
+    }
+
+    // The fixtures print addresses the way a little-endian machine does
+    #[cfg(all(test, target_endian = "little"))]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parse_address_reads_ipv4_and_ipv6() {
+            let cases = [
+                ("0100007F:0035", Some("127.0.0.1:53")),
+                ("00000000:1F90", Some("0.0.0.0:8080")),
+                ("00000000000000000000000001000000:0016", Some("[::1]:22")),
+                ("000080FE00000000000000000100000A:01BB", Some("[fe80::a00:1]:443")),
+                ("0000000000000000FFFF00000100007F:01BB", Some("[::ffff:127.0.0.1]:443")),
+                // No port, a bad port, a bad address, and one that is neither 4 nor 16 bytes long
+                ("0100007F", None),
+                ("0100007F:XYZ", None),
+                ("0100007G:0035", None),
+                ("0100007F01:0035", None),
+                ("", None),
+            ];
+            for (field, expected) in cases {
+                assert_eq!(parse_address(field).map(|address| address.to_string()).as_deref(), expected, "{}", field);
+            }
+        }
+
+        #[test]
+        fn parse_line_reads_sockets() {
+            let cases = [
+                (
+                    "   0: 0100007F:0035 00000000:0000 0A 00000000:00000000 00:00000000 00000000   101        0 12345 1 0000000000000000 100 0 0 10 0",
+                    "tcp",
+                    Some(("127.0.0.1:53", None, Some("LISTEN"), 12345)),
+                ),
+                (
+                    "   1: 0201A8C0:D431 0A01A8C0:0016 01 00000000:00000000 02:000A1B2C 00000000  1000        0 67890 2 0000000000000000 20 4 30 10 -1",
+                    "tcp",
+                    Some(("192.168.1.2:54321", Some("192.168.1.10:22"), Some("ESTABLISHED"), 67890)),
+                ),
+                (
+                    "   2: 00000000000000000000000001000000:1F90 00000000000000000000000001000000:C350 06 00000000:00000000 03:00001770 00000000     0        0 0 3 0000000000000000",
+                    "tcp6",
+                    Some(("[::1]:8080", Some("[::1]:50000"), Some("TIME_WAIT"), 0)),
+                ),
+                (
+                    "  3: 00000000:0044 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 23456 2 0000000000000000 0",
+                    "udp",
+                    Some(("0.0.0.0:68", None, None, 23456)),
+                ),
+                // Cut short, and with a bad address or inode
+                ("   0: 0100007F:0035 00000000:0000 0A 00000000:00000000", "tcp", None),
+                ("   0: 0100007F 00000000:0000 0A 00000000:00000000 00:00000000 00000000 0 0 12345", "tcp", None),
+                ("   0: 0100007F:0035 00000000:0000 0A 00000000:00000000 00:00000000 00000000 0 0 inode", "tcp", None),
+                ("", "tcp", None),
+            ];
+            for (line, protocol, expected) in cases {
+                let parsed = parse_line(line, protocol).map(|(connection, inode)| {
+                    assert_eq!(connection.protocol, protocol);
+                    (connection.local_addr, connection.remote_addr, connection.state, inode)
+                });
+                let expected = expected.map(|(local, remote, state, inode): (&str, Option<&str>, Option<&str>, u64)| {
+                    (local.to_string(), remote.map(str::to_string), state.map(str::to_string), inode)
+                });
+                assert_eq!(parsed, expected, "{}", line);
+            }
+        }
+
+        #[test]
+        fn tcp_state_names_kernel_states() {
+            let cases = [(0x01, "ESTABLISHED"), (0x06, "TIME_WAIT"), (0x0A, "LISTEN"), (0x0B, "CLOSING"), (0x00, "UNKNOWN"), (0x0C, "UNKNOWN")];
+            for (state, expected) in cases {
+                assert_eq!(tcp_state(state), expected, "{:#04x}", state);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::{normalize_state, Listing};
+    use crate::ConnectionInfo;
+    use std::collections::HashMap;
+    use std::io;
+    use std::process::Command;
+    use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System};
+
+    pub fn list_connections() -> io::Result<Listing> {
+        let output = Command::new("netstat").arg("-ano").output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "netstat failed: {}", String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let mut system = System::new();
+        system.refresh_processes_specifics(ProcessesToUpdate::All, true, ProcessRefreshKind::nothing());
+        let names: HashMap<u32, String> = system.processes().iter()
+            .map(|(pid, process)| (pid.as_u32(), process.name().to_string_lossy().to_string()))
+            .collect();
+
+        let mut listing = Listing::default();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            // TCP lines have a state before the pid, UDP lines don't:
+            // `TCP    0.0.0.0:135    0.0.0.0:0    LISTENING    1234`
+            // `UDP    0.0.0.0:500    *:*                       4567`
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (protocol, local, remote, state, pid) = match fields.as_slice() {
+                ["TCP", local, remote, state, pid] => ("tcp", *local, *remote, Some(normalize_state(state)), *pid),
+                ["UDP", local, remote, pid] => ("udp", *local, *remote, None, *pid),
+                _ => continue,
+            };
+            let pid = pid.parse::<u32>().ok().filter(|pid| *pid != 0);
+            listing.connections.push(ConnectionInfo {
+                protocol: format!("{}{}", protocol, if local.starts_with('[') { "6" } else { "" }),
+                local_addr: local.to_string(),
+                remote_addr: (!matches!(remote, "*:*" | "0.0.0.0:0" | "[::]:0")).then(|| remote.to_string()),
+                state,
+                pid,
+                process: pid.and_then(|pid| names.get(&pid).cloned()),
+            });
+        }
+
+        // Only Administrators can see the processes of other users and services
+        if listing.connections.iter().any(|connection| connection.pid.is_some_and(|pid| !names.contains_key(&pid))) {
+            listing.note = Some("some process names are unknown, run the client as Administrator to see them".to_string());
+        }
+        Ok(listing)
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+mod platform {
+    use super::{normalize_state, Listing};
+    use crate::ConnectionInfo;
+    use std::io;
+    use std::process::Command;
+
+    pub fn list_connections() -> io::Result<Listing> {
+        // Field output: a `p<pid>` and `c<command>` line per process, then per socket an
+        // `f<fd>` line followed by `t<IPv4|IPv6>`, `P<protocol>`, `n<addresses>`, and
+        // for TCP `TST=<state>`, in whatever order lsof prints them
+        let output = Command::new("lsof").args(["-nP", "-iTCP", "-iUDP", "-FpcftPnT"]).output()?;
+        // lsof exits with 1 when it finds nothing
+        if !output.status.success() && !output.stderr.is_empty() {
+            return Err(io::Error::other(format!(
+                "lsof failed: {}", String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let mut sockets: Vec<(ConnectionInfo, bool)> = Vec::new();
+        let mut pid = None;
+        let mut process = None;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let (field, value) = line.split_at(line.len().min(1));
+            if field == "p" {
+                pid = value.parse::<u32>().ok();
+            } else if field == "c" {
+                process = Some(value.to_string());
+            } else if field == "f" {
+                sockets.push((ConnectionInfo {
+                    protocol: String::new(),
+                    local_addr: String::new(),
+                    remote_addr: None,
+                    state: None,
+                    pid,
+                    process: process.clone(),
+                }, false));
+            } else if let Some((socket, ipv6)) = sockets.last_mut() {
+                match field {
+                    "t" => *ipv6 = value == "IPv6",
+                    "P" => socket.protocol = value.to_ascii_lowercase(),
+                    "n" => {
+                        let (local, remote) = match value.split_once("->") {
+                            Some((local, remote)) => (local, Some(remote.to_string())),
+                            None => (value, None),
+                        };
+                        socket.local_addr = local.to_string();
+                        socket.remote_addr = remote;
+                    },
+                    "T" => if let Some(state) = value.strip_prefix("ST=") {
+                        socket.state = Some(normalize_state(state));
+                    },
+                    _ => {},
+                }
+            }
+        }
+
+        let mut listing = Listing::default();
+        listing.connections = sockets.into_iter()
+            .map(|(mut socket, ipv6)| {
+                if ipv6 {
+                    socket.protocol.push('6');
+                }
+                socket
+            })
+            .collect();
+        if !is_root() {
+            listing.note = Some("only sockets of the client's user are listed, run the client as root to see all".to_string());
+        }
+        Ok(listing)
+    }
+
+    fn is_root() -> bool {
+        // SAFETY: geteuid has no preconditions and can't fail
+        unsafe { libc::geteuid() == 0 }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use super::Listing;
+    use std::io;
+
+    pub fn list_connections() -> io::Result<Listing> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "listing connections is not supported on this platform"))
+    }
+}
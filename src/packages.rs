@@ -0,0 +1,207 @@
+//! Listing the packages installed on the machine, for `Command::ListPackages`.
+//!
+//! Linux asks `dpkg-query`, falling back to `rpm` where dpkg isn't installed. Windows
+//! reads the uninstall entries in the registry through PowerShell, the same entries
+//! Apps & features and `winget list` show, without needing winget to be installed.
+//! macOS lists Homebrew packages, and the applications `system_profiler` finds where
+//! Homebrew isn't installed. Some of these take a while, so callers should limit how
+//! long they wait; the package manager is killed when the listing is dropped.
+
+use crate::PackageInfo;
+use std::io;
+use tokio::process::Command;
+
+/// Uninstall entries of machine-wide 64-bit and 32-bit programs, and of the current
+/// user's, leaving out updates and components that Apps & features doesn't show
+const WINDOWS_UNINSTALL_SCRIPT: &str = r#"
+$keys = 'HKLM:\Software\Microsoft\Windows\CurrentVersion\Uninstall\*',
+    'HKLM:\Software\WOW6432Node\Microsoft\Windows\CurrentVersion\Uninstall\*',
+    'HKCU:\Software\Microsoft\Windows\CurrentVersion\Uninstall\*'
+Get-ItemProperty $keys -ErrorAction SilentlyContinue |
+    Where-Object { $_.DisplayName -and -not $_.SystemComponent -and -not $_.ParentKeyName } |
+    ForEach-Object { "$($_.DisplayName)`t$($_.DisplayVersion)" }
+"#;
+
+/// The packages found and the package manager they came from
+#[derive(Debug, Default)]
+pub struct Listing {
+    pub manager: String,
+    pub packages: Vec<PackageInfo>,
+}
+
+/// List the installed packages, sorted by name and without duplicates
+pub async fn list_packages() -> Result<Listing, String> {
+    let mut listing = if cfg!(target_os = "linux") {
+        linux().await?
+    } else if cfg!(target_os = "windows") {
+        let output = run("powershell", &["-NoProfile", "-NonInteractive", "-Command", WINDOWS_UNINSTALL_SCRIPT]).await
+            .map_err(|e| format!("Failed to read the registry with PowerShell: {}", e))?;
+        Listing { manager: "registry".to_string(), packages: parse_tab_separated(&output) }
+    } else if cfg!(target_os = "macos") {
+        macos().await?
+    } else {
+        return Err("Listing packages is not supported on this platform".to_string());
+    };
+    listing.packages.sort();
+    listing.packages.dedup();
+    Ok(listing)
+}
+
+async fn linux() -> Result<Listing, String> {
+    let format = "${db:Status-Abbrev}\t${Package}\t${Version}\t${Architecture}\n";
+    match run("dpkg-query", &["--show", "--showformat", format]).await {
+        Ok(output) => return Ok(Listing { manager: "dpkg".to_string(), packages: parse_dpkg(&output) }),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(format!("Failed to run dpkg-query: {}", e)),
+        Err(_) => {},
+    }
+    match run("rpm", &["--query", "--all", "--queryformat", "%{NAME}\t%{VERSION}-%{RELEASE}\t%{ARCH}\n"]).await {
+        Ok(output) => Ok(Listing { manager: "rpm".to_string(), packages: parse_tab_separated(&output) }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err("Neither dpkg nor rpm is installed".to_string()),
+        Err(e) => Err(format!("Failed to run rpm: {}", e)),
+    }
+}
+
+async fn macos() -> Result<Listing, String> {
+    match run("brew", &["list", "--versions"]).await {
+        Ok(output) => return Ok(Listing { manager: "brew".to_string(), packages: parse_brew(&output) }),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(format!("Failed to run brew: {}", e)),
+        Err(_) => {},
+    }
+    let output = run("system_profiler", &["-json", "-detailLevel", "mini", "SPApplicationsDataType"]).await
+        .map_err(|e| format!("Failed to run system_profiler: {}", e))?;
+    let packages = parse_system_profiler(&output)
+        .map_err(|e| format!("Failed to parse system_profiler output: {}", e))?;
+    Ok(Listing { manager: "system_profiler".to_string(), packages })
+}
+
+/// Run `program` and return what it printed, failing with its stderr if it fails
+async fn run(program: &str, args: &[&str]) -> io::Result<String> {
+    let output = Command::new(program).args(args).kill_on_drop(true).output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("{} ({})", stderr.trim(), output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Lines of status, name, version and architecture, keeping only installed packages.
+/// The status is `ii` for those, with a third letter if the package needs attention.
+fn parse_dpkg(output: &str) -> Vec<PackageInfo> {
+    output.lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(status, _)| status.starts_with("ii"))
+        .flat_map(|(_, package)| parse_tab_separated(package))
+        .collect()
+}
+
+/// Lines of name, version and optionally architecture, separated by tabs. rpm reports
+/// `(none)` for packages without an architecture, such as its GPG keys.
+fn parse_tab_separated(output: &str) -> Vec<PackageInfo> {
+    output.lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t').map(str::trim);
+            let name = fields.next().filter(|name| !name.is_empty())?;
+            Some(PackageInfo {
+                name: name.to_string(),
+                version: fields.next().unwrap_or_default().to_string(),
+                arch: fields.next().filter(|arch| !arch.is_empty() && *arch != "(none)").map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Lines of a name followed by every installed version, each version becoming a package
+fn parse_brew(output: &str) -> Vec<PackageInfo> {
+    output.lines()
+        .flat_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next().unwrap_or_default();
+            fields.map(move |version| PackageInfo { name: name.to_string(), version: version.to_string(), arch: None })
+        })
+        .collect()
+}
+
+/// `{"SPApplicationsDataType": [{"_name": ..., "version": ...}, ...]}`
+fn parse_system_profiler(output: &str) -> serde_json::Result<Vec<PackageInfo>> {
+    #[derive(serde::Deserialize)]
+    struct Report {
+        #[serde(rename = "SPApplicationsDataType", default)]
+        applications: Vec<Application>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Application {
+        #[serde(rename = "_name")]
+        name: String,
+        #[serde(default)]
+        version: String,
+    }
+
+    let report: Report = serde_json::from_str(output)?;
+    Ok(report.applications.into_iter()
+        .map(|app| PackageInfo { name: app.name, version: app.version, arch: None })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, version: &str, arch: Option<&str>) -> PackageInfo {
+        PackageInfo { name: name.to_string(), version: version.to_string(), arch: arch.map(str::to_string) }
+    }
+
+    #[test]
+    fn parse_dpkg_keeps_installed_packages() {
+        let output = "ii \tcurl\t8.5.0-2ubuntu10.1\tamd64\n\
+                      rc \told-lib\t1.0-1\tamd64\n\
+                      iU \thalf-done\t2.0\tall\n\
+                      iiR\tneeds-reinstall\t3.1\tarm64\n\
+                      ii \tno-version\n\
+                      ii curl-without-tabs 8.5.0\n";
+        assert_eq!(parse_dpkg(output), vec![
+            package("curl", "8.5.0-2ubuntu10.1", Some("amd64")),
+            package("needs-reinstall", "3.1", Some("arm64")),
+            package("no-version", "", None),
+        ]);
+        assert!(parse_dpkg("").is_empty());
+    }
+
+    #[test]
+    fn parse_tab_separated_skips_missing_architectures() {
+        let output = "bash\t5.2.26-3.fc40\tx86_64\ngpg-pubkey\t8d1a1b9b-6507a5dd\t(none)\nProgram Files App\t\n\t1.0\n";
+        assert_eq!(parse_tab_separated(output), vec![
+            package("bash", "5.2.26-3.fc40", Some("x86_64")),
+            package("gpg-pubkey", "8d1a1b9b-6507a5dd", None),
+            package("Program Files App", "", None),
+        ]);
+        assert!(parse_tab_separated("").is_empty());
+    }
+
+    #[test]
+    fn parse_brew_lists_every_version() {
+        let output = "git 2.45.2\npython@3.12 3.12.4 3.12.5\nwget\n\n";
+        assert_eq!(parse_brew(output), vec![
+            package("git", "2.45.2", None),
+            package("python@3.12", "3.12.4", None),
+            package("python@3.12", "3.12.5", None),
+        ]);
+        assert!(parse_brew("").is_empty());
+    }
+
+    #[test]
+    fn parse_system_profiler_reads_applications() {
+        let output = r#"{"SPApplicationsDataType": [
+            {"_name": "Safari", "version": "17.5", "path": "/Applications/Safari.app"},
+            {"_name": "Unversioned"}
+        ]}"#;
+        assert_eq!(parse_system_profiler(output).unwrap(), vec![
+            package("Safari", "17.5", None),
+            package("Unversioned", "", None),
+        ]);
+        assert!(parse_system_profiler("{}").unwrap().is_empty());
+        // Cut off mid-report, or nothing printed at all
+        assert!(parse_system_profiler(r#"{"SPApplicationsDataType": [{"_name": "Saf"#).is_err());
+        assert!(parse_system_profiler("").is_err());
+    }
+}
@@ -1,55 +1,525 @@
-use rs_nats_lib::{Command, CommandResult, DEFAULT_NATS_URL, DEFAULT_SUBJECT_PREFIX, RsNatsError, SystemInfo};
+use crate::audit::AuditLog;
+use crate::metrics::{self, SERVER_METRICS};
+use crate::transcript::{self, console};
+use crate::{check_payload_size, connect, redact_url, ApiRequest, ApiResponse, ApprovalRequest, ApprovalResponse, Codec, AgentInfo, Command, Shell, CommandAck, ConnectionOptions, TlsOptions, CommandRequest, CommandResult, CommandType, ClientTime, ConnectionInfo, DiskInfo, Event, FileRange, LoadInfo, PackageList, ProbeOutcome, ProbeResult, ServiceInfo, ServiceState, ShellInput, LogLevel, OutputChunk, ScheduledJob, DEFAULT_DANGEROUS_PATTERNS, DEFAULT_HEARTBEAT_SECS, DEFAULT_HISTORY_SIZE, DEFAULT_NATS_URL, DEFAULT_PROBE_TIMEOUT_MS, DEFAULT_COMMAND_TIMEOUT_SECS, PACKAGE_PAGE_SIZE, DEFAULT_RESPONSE_TIMEOUT_SECS, DEFAULT_STALE_HEARTBEATS, DEFAULT_STREAM_IDLE_SECS, DEFAULT_SUBJECT_PREFIX, PROTOCOL_VERSION, RsNatsError, SystemInfo, format_bytes, format_unix_time, get_client_id, parse_tag, shutdown_signal, SubjectLayout, validate_client_id, wildcard_match};
 use anyhow::Result;
-use async_nats::Client;
-use log::{error, info, warn};
+use async_nats::{Client, Request, RequestErrorKind};
+use colored::{ColoredString, Colorize};
+use log::{debug, error, info, warn};
+use futures_util::future::join_all;
 use futures_util::stream::StreamExt;
-use serde_json::{from_slice, to_string};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use tokio::sync::mpsc;
-use tokio::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::from_slice;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio::time::{Duration, Instant};
 
+/// A registered client and when the server last heard from it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClientEntry {
+    info: SystemInfo,
+    last_seen: SystemTime,
+    /// Subject prefix the client registered under. Empty in registry files from
+    /// before servers could serve several prefixes, meaning the first one.
+    #[serde(default)]
+    subject_prefix: String,
+    /// Restored from the registry file and not heard from since
+    #[serde(skip)]
+    stale: bool,
+}
+
+impl ClientEntry {
+    fn new(info: SystemInfo, subject_prefix: String) -> Self {
+        Self {
+            info,
+            last_seen: SystemTime::now(),
+            subject_prefix,
+            stale: false,
+        }
+    }
+    
+    fn idle_for(&self) -> Duration {
+        self.last_seen.elapsed().unwrap_or_default()
+    }
+}
+
+/// A registered client as seen by the server
+#[derive(Debug, Clone)]
+pub struct ClientStatus {
+    pub client_id: String,
+    /// Subject prefix (namespace) the client registered under
+    pub subject_prefix: String,
+    pub info: SystemInfo,
+    /// When the client last registered or sent a heartbeat
+    pub last_seen: SystemTime,
+    /// Restored from the registry file and not heard from since
+    pub stale: bool,
+}
+
+/// A command the server sent to a client and the result it got back. Commands that
+/// got no answer have a failed result carrying the error.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub sent_at: SystemTime,
+    pub command: Command,
+    pub result: CommandResult,
+}
+
+/// What the server keeps about the commands it sends: the most recent ones for each
+/// client in memory, oldest first, and optionally every one in an audit log. The
+/// in-memory history is kept after a client goes away so what was done to it can
+/// still be reviewed.
+struct CommandHistory {
+    /// Entries kept per client; 0 turns history off
+    capacity: usize,
+    entries: Mutex<HashMap<String, VecDeque<HistoryEntry>>>,
+    audit: Option<AuditLog>,
+}
+
+impl CommandHistory {
+    fn new(capacity: usize, audit: Option<AuditLog>) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+            audit,
+        }
+    }
+    
+    /// Audit a command about to be sent. An error means it must not be sent.
+    fn dispatched(&self, client_id: &str, request: &CommandRequest) -> Result<()> {
+        if let Some(audit) = &self.audit {
+            audit.command_dispatched(client_id, &request.request_id, &request.command)?;
+        }
+        Ok(())
+    }
+    
+    /// Audit a result received from a client
+    fn result_received(&self, client_id: &str, result: &CommandResult) {
+        if let Some(audit) = &self.audit {
+            if let Err(e) = audit.result_received(client_id, result) {
+                error!("{}", e);
+            }
+        }
+    }
+    
+    /// Remember `command` and how it ended, evicting the client's oldest entry if full
+    fn record(&self, client_id: &str, sent_at: SystemTime, command: Command, outcome: &Result<CommandResult>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let result = match outcome {
+            Ok(result) => {
+                // Downloaded file contents would make the history as big as the files
+                let mut result = result.clone();
+                result.data = Vec::new();
+                result
+            },
+            Err(e) => {
+                let command_type = match command {
+                    Command::Execute { .. } => CommandType::Shell,
+                    _ => CommandType::Internal,
+                };
+                CommandResult::new(false, String::new(), Some(e.to_string()), command_type)
+            },
+        };
+        
+        let mut entries = self.entries.lock().unwrap();
+        let client_entries = entries.entry(client_id.to_string()).or_default();
+        if client_entries.len() == self.capacity {
+            client_entries.pop_front();
+        }
+        client_entries.push_back(HistoryEntry { sent_at, command, result });
+    }
+    
+    fn get(&self, client_id: &str) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap()
+            .get(client_id)
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Handle on the metrics endpoint task, to stop it on shutdown
+type MetricsServer = Option<(tokio::task::JoinHandle<std::io::Result<()>>, oneshot::Sender<()>)>;
+
+/// In-flight requests awaiting a result, keyed by request id. Clones share the same
+/// requests, so the response dispatcher can complete what a console command registered.
+#[derive(Clone, Default)]
+struct PendingRequests(Arc<Mutex<HashMap<String, oneshot::Sender<CommandResult>>>>);
+
+impl PendingRequests {
+    /// Wait for the result of `request_id`, replacing anyone who waited for it before
+    fn register(&self, request_id: &str) -> oneshot::Receiver<CommandResult> {
+        let (tx, rx) = oneshot::channel();
+        self.0.lock().unwrap().insert(request_id.to_string(), tx);
+        rx
+    }
+    
+    /// Hand `result` to whoever waits for its request id. Gives it back if nobody
+    /// does, including when the waiter has gone away.
+    fn complete(&self, result: CommandResult) -> Option<CommandResult> {
+        let waiter = result.request_id.as_ref().and_then(|id| self.0.lock().unwrap().remove(id));
+        match waiter {
+            Some(tx) => tx.send(result).err(),
+            None => Some(result),
+        }
+    }
+    
+    /// Stop waiting for `request_id`, after a timeout or error
+    fn cancel(&self, request_id: &str) {
+        self.0.lock().unwrap().remove(request_id);
+    }
+    
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+/// The subscriptions the server keeps for as long as it runs, a few per prefix, for the
+/// `subs` console command. Those made for a single command's acknowledgement or
+/// streamed output end with the command and aren't listed.
+#[derive(Clone, Default)]
+struct Subscriptions(Arc<Mutex<Vec<Arc<SubscriptionStats>>>>);
+
+struct SubscriptionStats {
+    subject: String,
+    /// What the messages are, e.g. heartbeats
+    purpose: &'static str,
+    queue_group: Option<String>,
+    since: SystemTime,
+    received: AtomicU64,
+    open: AtomicBool,
+}
+
+impl Subscriptions {
+    /// Record a subscription to `subject`, returning the handle for the task reading it
+    fn add(&self, subject: &str, purpose: &'static str, queue_group: Option<&str>) -> SubscriptionHandle {
+        let stats = Arc::new(SubscriptionStats {
+            subject: subject.to_string(),
+            purpose,
+            queue_group: queue_group.map(str::to_string),
+            since: SystemTime::now(),
+            received: AtomicU64::new(0),
+            open: AtomicBool::new(true),
+        });
+        self.0.lock().unwrap().push(stats.clone());
+        SubscriptionHandle(stats)
+    }
+    
+    fn list(&self) -> Vec<Arc<SubscriptionStats>> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Counts the messages of a subscription, which is shown as closed once the task
+/// reading it ends and drops this
+struct SubscriptionHandle(Arc<SubscriptionStats>);
+
+impl SubscriptionHandle {
+    fn received(&self) {
+        self.0.received.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        self.0.open.store(false, Ordering::Relaxed);
+    }
+}
+
+/// A dangerous command waiting in the console for a second operator to approve it
+struct HeldCommand {
+    /// Client id, or the tag for a broadcast
+    target: String,
+    /// The command as shown in the console
+    command: String,
+    held_at: Instant,
+    expires_at: Instant,
+    /// Send `true` to approve, `false` to deny
+    decision: oneshot::Sender<bool>,
+}
+
+/// Commands held for approval, keyed by request id
+type HeldCommands = Arc<Mutex<HashMap<String, HeldCommand>>>;
+
+/// Support server that tracks registered clients and dispatches commands to them
 pub struct Server {
     nats_client: Client,
-    subject_prefix: String,
-    connected_clients: Arc<RwLock<HashMap<String, SystemInfo>>>,
+    subjects: Arc<SubjectLayout>,
+    /// Prefixes (namespaces) served, never empty. The first is used for clients
+    /// that haven't registered with this server.
+    subject_prefixes: Vec<String>,
+    connected_clients: Arc<RwLock<HashMap<String, ClientEntry>>>,
+    pending_requests: PendingRequests,
+    subscriptions: Subscriptions,
+    codec: Codec,
+    heartbeat_interval: Duration,
+    stale_after: Duration,
+    registry_path: Option<PathBuf>,
+    queue_group: Option<String>,
+    metrics_addr: Option<SocketAddr>,
+    jetstream: bool,
+    history: Arc<CommandHistory>,
+    output: OutputFormat,
+    /// How long dangerous console commands wait for approval, if they need it
+    approval_timeout: Option<Duration>,
+    dangerous_patterns: Vec<String>,
+    /// Commands waiting for approval, from the console or the API
+    held: HeldCommands,
 }
 
 impl Server {
-    pub async fn new(nats_url: Option<&str>, subject_prefix: Option<&str>) -> Result<Self> {
-        let url = nats_url.unwrap_or(DEFAULT_NATS_URL);
-        let prefix = subject_prefix.unwrap_or(DEFAULT_SUBJECT_PREFIX).to_string();
-        
-        info!("Connecting to NATS server at {}", url);
-        let nats_client = async_nats::connect(url).await.map_err(|e| {
-            RsNatsError::ConnectionError(format!("Failed to connect to NATS: {}", e))
-        })?;
-        
-        Ok(Self {
-            nats_client,
-            subject_prefix: prefix,
-            connected_clients: Arc::new(RwLock::new(HashMap::new())),
-        })
+    /// Start building a server; see `ServerBuilder` for the settings
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
     }
     
-    pub async fn run(&self) -> Result<()> {
-        let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<bool>(1);
+    /// Same as the matching `ServerBuilder` settings, with `None` for the defaults
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        nats_url: Option<&str>,
+        subject_prefixes: &[&str],
+        codec: Option<Codec>,
+        heartbeat_interval: Option<Duration>,
+        stale_heartbeats: Option<u32>,
+        connection: Option<ConnectionOptions>,
+        registry_path: Option<PathBuf>,
+        queue_group: Option<String>,
+        metrics_addr: Option<SocketAddr>,
+        jetstream: bool,
+    ) -> Result<Self> {
+        ServerBuilder {
+            nats_url: nats_url.map(str::to_string),
+            subject_prefixes: subject_prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+            codec,
+            heartbeat_interval,
+            stale_heartbeats,
+            connection,
+            registry_path,
+            queue_group,
+            metrics_addr,
+            jetstream,
+            ..ServerBuilder::default()
+        }.build().await
+    }
+    
+    /// Publish a single command to a connected client without waiting for its result.
+    /// The result is delivered on the client's response subject like any other.
+    /// Returns the id the command was sent with.
+    pub async fn dispatch_command(&self, client_id: &str, cmd: &Command) -> Result<String> {
+        let request = CommandRequest::new(cmd.clone());
+        let prefix = self.prefix_for(client_id).await;
+        publish_command(&self.nats_client, &self.subjects, &prefix, self.codec, self.jetstream, &self.connected_clients, &self.history, client_id, &request).await?;
+        Ok(request.request_id)
+    }
+    
+    /// Send a command to a client and wait for its result, giving up after
+    /// `DEFAULT_RESPONSE_TIMEOUT_SECS`. Requires `start()` to have been called
+    /// so responses are being received.
+    pub async fn send_command(&self, client_id: &str, cmd: Command) -> Result<CommandResult> {
+        self.send_command_with_timeout(
+            client_id,
+            cmd,
+            Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS),
+        ).await
+    }
+    
+    /// Send a command to a client and wait up to `timeout` for its result. On timeout
+    /// the error is `RsNatsError::StillRunning` if the client acknowledged receiving
+    /// the command, and `RsNatsError::Timeout` if it didn't.
+    pub async fn send_command_with_timeout(
+        &self,
+        client_id: &str,
+        cmd: Command,
+        timeout: Duration,
+    ) -> Result<CommandResult> {
+        send_and_wait(
+            &self.nats_client,
+            &self.subjects,
+            &self.prefix_for(client_id).await,
+            self.codec,
+            self.jetstream,
+            &self.connected_clients,
+            &self.pending_requests,
+            &self.history,
+            client_id,
+            cmd,
+            timeout,
+        ).await
+    }
+    
+    /// Run a shell command on a client in streaming mode, calling `on_chunk` with
+    /// each piece of output in order as it arrives. Returns the command's result
+    /// once the client reports completion.
+    pub async fn stream_command<F>(&self, client_id: &str, cmd: Command, on_chunk: F) -> Result<CommandResult>
+    where
+        F: FnMut(&OutputChunk),
+    {
+        stream_command(
+            &self.nats_client,
+            &self.subjects,
+            &self.prefix_for(client_id).await,
+            self.codec,
+            self.jetstream,
+            &self.connected_clients,
+            &self.pending_requests,
+            &self.history,
+            client_id,
+            CommandRequest::new(cmd),
+            Duration::from_secs(DEFAULT_STREAM_IDLE_SECS),
+            on_chunk,
+        ).await
+    }
+    
+    /// Ping a client and return the round-trip time, giving up after
+    /// `DEFAULT_RESPONSE_TIMEOUT_SECS`
+    pub async fn ping(&self, client_id: &str) -> Result<Duration> {
+        ping(
+            &self.nats_client,
+            &self.subjects,
+            &self.prefix_for(client_id).await,
+            self.codec,
+            self.jetstream,
+            &self.connected_clients,
+            &self.pending_requests,
+            &self.history,
+            client_id,
+            Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS),
+        ).await
+    }
+    
+    /// Ask `client_id` to kill the shell command it started for `request_id`, the id
+    /// returned by `dispatch_command` or reported in `RsNatsError::StillRunning`
+    pub async fn cancel_command(&self, client_id: &str, request_id: &str) -> Result<CommandResult> {
+        self.send_command(client_id, Command::CancelRequest { request_id: request_id.to_string() }).await
+    }
+    
+    /// Commands sent to `client_id` with `send_command` or `stream_command`, or from
+    /// the console, and their results, oldest first
+    pub fn history(&self, client_id: &str) -> Vec<HistoryEntry> {
+        self.history.get(client_id)
+    }
+    
+    /// The clients currently registered, sorted by id
+    pub async fn clients(&self) -> Vec<ClientStatus> {
+        let clients_map = self.connected_clients.read().await;
+        let mut clients: Vec<_> = clients_map.iter()
+            .map(|(client_id, entry)| ClientStatus {
+                client_id: client_id.clone(),
+                subject_prefix: entry.subject_prefix.clone(),
+                info: entry.info.clone(),
+                last_seen: entry.last_seen,
+                stale: entry.stale,
+            })
+            .collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        clients
+    }
+    
+    /// The prefix commands for `client_id` are sent under: the one it registered
+    /// with, or the first served prefix for clients this server hasn't seen
+    async fn prefix_for(&self, client_id: &str) -> String {
+        client_prefix(&self.connected_clients, client_id).await
+            .unwrap_or_else(|| self.subject_prefixes[0].clone())
+    }
+    
+    /// Only checks that `client_id` is valid: results from every client under a served
+    /// prefix are received from the moment the server is built, registered or not
+    #[deprecated(note = "results from every client are received without tracking it")]
+    pub async fn track_client(&self, client_id: &str) -> Result<()> {
+        validate_client_id(client_id)?;
+        Ok(())
+    }
+    
+    /// Start handling client registrations and responses in the background.
+    /// `run()` calls this before starting the interactive console.
+    pub async fn start(&self) -> Result<()> {
+        if let Some(path) = &self.registry_path {
+            let restored = load_registry(path).await?;
+            info!("Restored {} clients from {}", restored.len(), path.display());
+            for (client_id, mut entry) in restored {
+                if entry.subject_prefix.is_empty() {
+                    entry.subject_prefix = self.subject_prefixes[0].clone();
+                }
+                let mut clients_map = self.connected_clients.write().await;
+                clients_map.entry(client_id).or_insert(entry);
+                SERVER_METRICS.set_connected_clients(clients_map.len());
+            }
+        }
+        
+        for prefix in &self.subject_prefixes {
+            self.subscribe_prefix(prefix).await?;
+        }
+        info!("Server started, waiting for client connections on {}", self.subject_prefixes.join(", "));
+        
+        let clients = self.connected_clients.clone();
+        let nats = self.nats_client.clone();
+        let subjects = self.subjects.clone();
+        let heartbeat_interval = self.heartbeat_interval;
+        let stale_after = self.stale_after;
+        let registry_path = self.registry_path.clone();
         
-        // Subscribe to client registration
-        let reg_subject = format!("{}.register", self.subject_prefix);
-        let registration_subscription = self.nats_client.subscribe(reg_subject).await?;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(heartbeat_interval);
+            loop {
+                ticker.tick().await;
+                
+                let mut timed_out = Vec::new();
+                {
+                    let mut clients_map = clients.write().await;
+                    let before = clients_map.len();
+                    clients_map.retain(|client_id, entry| {
+                        let alive = entry.idle_for() <= stale_after;
+                        if !alive {
+                            warn!("Client {} timed out after {:?} without a heartbeat", client_id, stale_after);
+                            timed_out.push((client_id.clone(), entry.subject_prefix.clone()));
+                        }
+                        alive
+                    });
+                    SERVER_METRICS.clients_evicted(before - clients_map.len());
+                    SERVER_METRICS.set_connected_clients(clients_map.len());
+                }
+                for (client_id, prefix) in timed_out {
+                    publish_event(&nats, &subjects, &prefix, &Event::ClientTimedOut { client_id, timestamp: now_rfc3339() }).await;
+                }
+                
+                if let Some(path) = &registry_path {
+                    if let Err(e) = save_registry(path, &clients).await {
+                        warn!("Failed to save client registry to {}: {}", path.display(), e);
+                    }
+                }
+            }
+        });
         
-        info!("Server started, waiting for client connections");
+        Ok(())
+    }
+    
+    /// Handle registrations, deregistrations, and heartbeats of clients under `prefix`
+    async fn subscribe_prefix(&self, prefix: &str) -> Result<()> {
+        let reg_subject = self.subjects.register(prefix);
+        let registrations = self.subscriptions.add(&reg_subject, "registrations", self.queue_group.as_deref());
+        let registration_subscription = subscribe(&self.nats_client, reg_subject, self.queue_group.as_deref()).await?;
         
         // Handle client registrations
         let clients = self.connected_clients.clone();
         let nats = self.nats_client.clone();
-        let prefix = self.subject_prefix.clone();
+        let subjects = self.subjects.clone();
+        let prefix_owned = prefix.to_string();
+        let codec = self.codec;
         
         tokio::spawn(async move {
+            let prefix = prefix_owned;
             let mut reg_stream = registration_subscription;
             while let Some(msg) = reg_stream.next().await {
-                match from_slice::<SystemInfo>(&msg.payload) {
+                registrations.received();
+                match codec.decode::<SystemInfo>(&msg.payload) {
                     Ok(system_info) => {
                         // Get client ID from header if available, otherwise use inbox ID
                         let client_id = match &msg.headers {
@@ -57,67 +527,49 @@ impl Server {
                                 if let Some(values) = headers.get("client_id") {
                                     values.to_string()
                                 } else {
-                                    msg.reply.clone().unwrap_or_default()
+                                    msg.reply.as_ref().map(|r| r.to_string()).unwrap_or_default()
                                 }
                             },
-                            None => msg.reply.clone().unwrap_or_default()
+                            None => msg.reply.as_ref().map(|r| r.to_string()).unwrap_or_default()
                         };
                         
+                        // The id becomes part of this client's subjects, so refuse anything
+                        // that could cross into other subjects
+                        if let Err(e) = validate_client_id(&client_id) {
+                            warn!("Rejecting registration: {}", e);
+                            if let Some(reply) = msg.reply {
+                                let _ = nats.publish(reply, e.to_string().into()).await;
+                            }
+                            continue;
+                        }
+                        
                         info!("New client connected: {} ({})", client_id, system_info.hostname);
+                        let connected = Event::ClientConnected {
+                            client_id: client_id.clone(),
+                            hostname: system_info.hostname.clone(),
+                            timestamp: now_rfc3339(),
+                        };
+                        if system_info.capabilities.is_empty() {
+                            warn!("Client {} doesn't report what it supports; commands it doesn't know will go unanswered", client_id);
+                        } else if system_info.protocol_version < PROTOCOL_VERSION {
+                            warn!("Client {} uses protocol version {}, older than this server's {}; commands it doesn't know will be refused",
+                                client_id, system_info.protocol_version, PROTOCOL_VERSION);
+                        }
                         
-                        // Store client info
                         {
-                            let mut clients_map = clients.write().unwrap();
-                            clients_map.insert(client_id.clone(), system_info.clone());
+                            let mut clients_map = clients.write().await;
+                            let previous = clients_map.insert(client_id.clone(), ClientEntry::new(system_info, prefix.clone()));
+                            if let Some(previous) = previous.filter(|previous| previous.subject_prefix != prefix) {
+                                warn!("Client {} moved from {} to {}", client_id, previous.subject_prefix, prefix);
+                            }
+                            SERVER_METRICS.set_connected_clients(clients_map.len());
                         }
                         
                         // Reply to client with acknowledgment
                         if let Some(reply) = msg.reply {
                             let _ = nats.publish(reply, "ACK".into()).await;
                         }
-                        
-                        // Subscribe to client response channel
-                        let response_subject = format!("{}.response.{}", prefix, client_id);
-                        info!("Subscribing to responses on {}", response_subject);
-                        
-                        match nats.subscribe(response_subject).await {
-                            Ok(subscription) => {
-                                let client_id_clone = client_id.clone();
-                                tokio::spawn(async move {
-                                    let mut msg_stream = subscription;
-                                    info!("Response handler started for {}", client_id_clone);
-                                    
-                                    while let Some(msg) = msg_stream.next().await {
-                                        let payload_str = String::from_utf8_lossy(&msg.payload);
-                                        info!("Response received from {}: {}", client_id_clone, payload_str);
-                                        
-                                        match from_slice::<CommandResult>(&msg.payload) {
-                                            Ok(result) => {
-                                                println!("\n----- COMMAND RESULT -----");
-                                                println!("Client: {}", client_id_clone);
-                                                println!("Status: {}", if result.success { "Success" } else { "Failed" });
-                                                println!("Output:\n{}", result.output);
-                                                if let Some(err) = result.error {
-                                                    println!("Error: {}", err);
-                                                }
-                                                println!("--------------------------\n");
-                                                
-                                                // Ensure output is displayed immediately
-                                                std::io::Write::flush(&mut std::io::stdout()).unwrap();
-                                            },
-                                            Err(e) => {
-                                                error!("Failed to parse response: {}", e);
-                                                println!("\nReceived unparseable response from {}", client_id_clone);
-                                                println!("Raw payload: {}", payload_str);
-                                            }
-                                        }
-                                    }
-                                });
-                            },
-                            Err(e) => {
-                                error!("Failed to subscribe to response channel: {}", e);
-                            }
-                        }
+                        publish_event(&nats, &subjects, &prefix, &connected).await;
                     },
                     Err(e) => {
                         warn!("Failed to parse client registration: {}", e);
@@ -126,24 +578,166 @@ impl Server {
             }
         });
         
+        // Drop clients that announce they're shutting down. This and heartbeats are
+        // never queue subscribed, so every server in a group sees them.
+        let dereg_subject = self.subjects.deregister(prefix);
+        let deregistrations = self.subscriptions.add(&dereg_subject, "deregistrations", None);
+        let deregistration_subscription = self.nats_client.subscribe(dereg_subject).await?;
+        let clients = self.connected_clients.clone();
+        let nats = self.nats_client.clone();
+        let subjects = self.subjects.clone();
+        let dereg_prefix = prefix.to_string();
+        
+        tokio::spawn(async move {
+            let mut dereg_stream = deregistration_subscription;
+            while let Some(msg) = dereg_stream.next().await {
+                deregistrations.received();
+                let client_id = String::from_utf8_lossy(&msg.payload).to_string();
+                let removed = {
+                    let mut clients_map = clients.write().await;
+                    // Only the client registered under this prefix is leaving
+                    let removed = match clients_map.get(&client_id) {
+                        Some(entry) if entry.subject_prefix == dereg_prefix => clients_map.remove(&client_id),
+                        _ => None,
+                    };
+                    SERVER_METRICS.set_connected_clients(clients_map.len());
+                    removed
+                };
+                match removed {
+                    Some(entry) => {
+                        info!("Client disconnected: {} ({})", client_id, entry.info.hostname);
+                        let disconnected = Event::ClientDisconnected { client_id, timestamp: now_rfc3339() };
+                        publish_event(&nats, &subjects, &dereg_prefix, &disconnected).await;
+                    },
+                    None => warn!("Deregistration from unknown client {}", client_id),
+                }
+            }
+        });
+        
+        // Track heartbeats so silent clients can be evicted
+        let heartbeat_subject = self.subjects.heartbeat(prefix);
+        let heartbeats = self.subscriptions.add(&heartbeat_subject, "heartbeats", None);
+        let heartbeat_subscription = self.nats_client.subscribe(heartbeat_subject).await?;
+        let clients = self.connected_clients.clone();
+        let heartbeat_prefix = prefix.to_string();
+        
+        tokio::spawn(async move {
+            let mut heartbeat_stream = heartbeat_subscription;
+            while let Some(msg) = heartbeat_stream.next().await {
+                heartbeats.received();
+                let client_id = String::from_utf8_lossy(&msg.payload).to_string();
+                match clients.write().await.get_mut(&client_id) {
+                    Some(entry) if entry.subject_prefix == heartbeat_prefix => {
+                        if entry.stale {
+                            info!("Restored client {} is back online", client_id);
+                        }
+                        entry.last_seen = SystemTime::now();
+                        entry.stale = false;
+                    },
+                    _ => debug!("Heartbeat from unregistered client {}", client_id),
+                }
+            }
+        });
+        
+        Ok(())
+    }
+    
+    /// Start the server and run the interactive console until `exit` or a termination signal
+    pub async fn run(&self) -> Result<()> {
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<bool>(1);
+        
+        self.start().await?;
+        let metrics_server = self.start_metrics().await?;
+        
         // Handle interactive console
         let clients = self.connected_clients.clone();
         let nats = self.nats_client.clone();
-        let prefix = self.subject_prefix.clone();
+        let subjects = self.subjects.clone();
+        let pending = self.pending_requests.clone();
+        let subscriptions = self.subscriptions.clone();
+        let history = self.history.clone();
+        let codec = self.codec;
+        let jetstream = self.jetstream;
+        let output = self.output;
+        let show_prefix = self.subject_prefixes.len() > 1;
+        let approval_timeout = self.approval_timeout;
+        let dangerous_patterns = self.dangerous_patterns.clone();
+        let held = self.held.clone();
+        let approval_subjects: Vec<String> = self.subject_prefixes.iter().map(|prefix| subjects.approval(prefix)).collect();
         let shutdown_tx_clone = shutdown_tx.clone();
+        // While a console command such as `tail` runs until interrupted, Ctrl-C stops it
+        // instead of the server
+        let interrupt: Arc<Mutex<Option<oneshot::Sender<()>>>> = Arc::new(Mutex::new(None));
+        let console_interrupt = interrupt.clone();
+        
+        // Read stdin on its own thread; a blocking read inside the runtime would
+        // keep it from shutting down
+        let (input_tx, mut input_rx) = mpsc::channel::<String>(1);
+        std::thread::spawn(move || {
+            let stdin = std::io::stdin();
+            let mut input = String::new();
+            while let Ok(n) = stdin.read_line(&mut input) {
+                if n == 0 || input_tx.blocking_send(std::mem::take(&mut input)).is_err() {
+                    break;
+                }
+            }
+        });
         
         tokio::spawn(async move {
             loop {
-                println!("\nAvailable commands:");
-                println!("  list                - List connected clients");
-                println!("  execute <id> <cmd>  - Execute command on client");
-                println!("  sysinfo <id>        - Get system info from client");
-                println!("  ping <id>           - Ping client");
-                println!("  exit                - Exit server");
+                console!("\nAvailable commands:");
+                console!("  list [os|host|user=PATTERN]... [--tag K=V]... - List connected clients");
+                console!("  broadcast-tag [--dry-run] <K=V> <cmd> - Execute command on tagged clients");
+                console!("  execute [--stream] [--dry-run] [--timeout <secs>] [--cwd <dir>] [--env K=V]... [--stdin-file <path>] [--shell <shell>] <id> <cmd>");
+                console!("                      - Execute command on client");
+                console!("  script [--continue-on-error] [--dry-run] [--timeout <secs>] [--cwd <dir>] [--env K=V]... [--shell <shell>] <id> <file>");
+                console!("                      - Execute each line of a file on client in turn");
+                console!("  watch [--timeout <secs>] [--cwd <dir>] [--env K=V]... [--shell <shell>] <id> <interval> <cmd>");
+                console!("                      - Execute command on client every interval until Ctrl-C");
+                console!("  sysinfo <id> [--all] - Get system info from client");
+                console!("  agentinfo <id>      - Show client version and settings");
+                console!("  ping <id> [--count N] - Ping client and show round-trip time");
+                console!("  upload <id> <local> <remote> [mode] - Upload file to client");
+                console!("  download <id> <remote> <local>      - Download file from client");
+                console!("  readrange <id> <remote> <offset|-N> <length> [local] - Read part of a file on client");
+                console!("  kill <id> <pid> [--force]           - Kill a process on client");
+                console!("  cancel <id> <request_id>            - Cancel a running command on client");
+                console!("  schedule <id> <time|+delay> <cmd>   - Run command on client later");
+                console!("  scheduled <id>                      - List commands scheduled on client");
+                console!("  unschedule <id> <job_id>            - Cancel a scheduled command");
+                console!("  loglevel <id> <level>               - Change client log level");
+                console!("  restart-agent <id>                  - Restart the client process");
+                console!("  reboot [--dry-run] <id> [delay_secs] - Reboot the client machine");
+                console!("  poweroff [--dry-run] <id>           - Power off the client machine");
+                console!("  env <id> [KEY]...                   - Show client environment variables");
+                console!("  disk <id>           - Show client disk usage");
+                console!("  load <id>           - Show client CPU and memory load");
+                console!("  time <id>           - Show client clock and how far it is off");
+                console!("  netstat <id>        - Show client network connections");
+                console!("  packages <id> [filter] - List packages installed on client");
+                console!("  service <id> <name> - Show the state of a service on client");
+                console!("  probe <id> <host> <port> [timeout_ms] - Test a TCP connection from client");
+                console!("  custom <id> <name> [json] - Run a custom command on client");
+                console!("  tail <id> <path> [lines]            - Follow a file on client until Ctrl-C");
+                console!("  shell [--shell <shell>] <id>        - Open an interactive shell on client");
+                console!("  logtail <id> [path] [lines]         - Show the end of client's log or a file");
+                console!("  rotatelog <id>                      - Start a new log file on client");
+                console!("  history <id>        - Show commands sent to client and their results");
+                console!("  subs                - List the server's NATS subscriptions");
+                if approval_timeout.is_some() {
+                    console!("  pending             - List commands waiting for approval");
+                    console!("  deny <request_id>   - Drop a held command; others approve it with rs-nats approve");
+                }
+                console!("  exit                - Exit server");
+                console!("<id> is a client id, or host:<hostname> for the client on that host");
+                console!("execute also takes several, separated by commas, e.g. web-01,web-02,host:db");
                 
-                let mut input = String::new();
-                std::io::stdin().read_line(&mut input).unwrap();
+                let Some(input) = input_rx.recv().await else {
+                    info!("Console input closed, server keeps running until terminated");
+                    break;
+                };
                 let input = input.trim();
+                transcript::input(input);
                 
                 let parts: Vec<&str> = input.split_whitespace().collect();
                 if parts.is_empty() {
@@ -152,136 +746,3562 @@ impl Server {
                 
                 match parts[0] {
                     "list" => {
-                        let clients_map = clients.read().unwrap();
-                        if clients_map.is_empty() {
-                            println!("No clients connected");
+                        let filter = match parse_list_filter(&parts[1..]) {
+                            Ok(filter) => filter,
+                            Err(e) => {
+                                console!("{}", e);
+                                console!("Usage: list [os=PATTERN] [host=PATTERN] [user=PATTERN] [--tag KEY=VALUE]...");
+                                continue;
+                            }
+                        };
+                        
+                        let clients_map = clients.read().await;
+                        let mut matching: Vec<_> = clients_map.iter()
+                            .filter(|(_, entry)| filter.matches(&entry.info))
+                            .collect();
+                        matching.sort_by_key(|(id, _)| *id);
+                        if output == OutputFormat::Json {
+                            let listed: Vec<_> = matching.iter()
+                                .map(|(id, entry)| ClientSummary::new(id, entry))
+                                .collect();
+                            print_json(&listed);
+                        } else if matching.is_empty() {
+                            console!("{}", if filter.is_empty() { "No clients connected" } else { "No matching clients" });
                         } else {
-                            println!("Connected clients:");
-                            for (id, info) in clients_map.iter() {
-                                println!("  {} - {} ({} / {})", 
-                                    id, info.hostname, info.username, info.os_type);
+                            console!("Connected clients:");
+                            let count = matching.len();
+                            for (id, entry) in matching {
+                                let info = &entry.info;
+                                let namespace = if show_prefix { format!(" [{}]", entry.subject_prefix) } else { String::new() };
+                                console!("  {}{} - {} ({} / {} {}, {} CPUs, {} RAM){}", 
+                                    id, namespace, info.hostname, info.username, info.os_type, info.arch,
+                                    info.cpu_count, format_bytes(info.total_memory_bytes),
+                                    if entry.stale { " [stale]" } else { "" });
+                            }
+                            if filter.is_empty() {
+                                console!("{} clients", count);
+                            } else {
+                                console!("{} of {} clients", count, clients_map.len());
                             }
                         }
                     },
                     "execute" => {
-                        if parts.len() < 3 {
-                            println!("Usage: execute <client_id> <command>");
+                        let (options, rest) = match parse_execute_options(&parts[1..]) {
+                            Ok(parsed) => parsed,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        if rest.len() < 2 {
+                            console!("Usage: execute [--stream] [--dry-run] [--timeout <secs>] [--cwd <dir>] [--env KEY=VALUE]... [--stdin-file <path>] [--shell <shell>] <client_id> <command>");
                             continue;
                         }
                         
-                        let client_id = parts[1];
-                        let command = parts[2..].join(" ");
+                        if options.continue_on_error {
+                            console!("--continue-on-error only applies to script");
+                            continue;
+                        }
                         
-                        {
-                            let clients_map = clients.read().unwrap();
-                            if !clients_map.contains_key(client_id) {
-                                println!("Client {} not found", client_id);
+                        let client_id = rest[0];
+                        let command = rest[1..].join(" ");
+                        
+                        if client_id.contains(',') && options.stream {
+                            console!("--stream only works with a single client");
+                            continue;
+                        }
+                        let batch = if client_id.contains(',') {
+                            let (targets, errors) = resolve_clients(&clients, client_id).await;
+                            for e in errors {
+                                console!("{}", e);
+                            }
+                            if targets.is_empty() {
                                 continue;
                             }
-                        }
-                        
-                        let command_subject = format!("{}.command.{}", prefix, client_id);
-                        let cmd = Command::Execute(command.clone());
+                            Some(targets)
+                        } else {
+                            None
+                        };
                         
-                        match to_string(&cmd) {
-                            Ok(json) => {
-                                println!("Executing command on {}: {}", client_id, command);
-                                match nats.publish(command_subject, json.into()).await {
-                                    Ok(_) => info!("Command sent successfully to {}", client_id),
-                                    Err(e) => error!("Failed to send command: {}", e)
+                        let stdin = match &options.stdin_file {
+                            Some(path) => match tokio::fs::read_to_string(path).await {
+                                Ok(contents) => Some(contents),
+                                Err(e) => {
+                                    console!("Failed to read {}: {}", path, e);
+                                    continue;
                                 }
-                                // Give the client time to process and respond
-                                tokio::time::sleep(Duration::from_millis(100)).await;
                             },
+                            None => None,
+                        };
+                        
+                        let cmd = Command::Execute {
+                            command: command.clone(),
+                            cwd: options.cwd,
+                            env: if options.env.is_empty() { None } else { Some(options.env) },
+                            stream: options.stream && !options.dry_run,
+                            stdin,
+                            shell: options.shell,
+                        };
+                        
+                        // Results are printed per client as they all come in, like broadcast-tag
+                        if let Some(targets) = batch {
+                            let cmd = if options.dry_run { Command::DryRun { command: Box::new(cmd) } } else { cmd };
+                            let timeout = options.timeout.unwrap_or(Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS));
+                            let description = format!("{} clients ({})", targets.len(),
+                                targets.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>().join(", "));
+                            if let Some(approval) = approval_timeout.filter(|_| needs_approval(&cmd, &dangerous_patterns)) {
+                                let send = broadcast(nats.clone(), subjects.clone(), codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, targets, cmd.clone(), timeout);
+                                hold_for_approval(&held, uuid::Uuid::new_v4().to_string(), &description, cmd.to_string(), approval, send);
+                                continue;
+                            }
+                            console!("{} command on {}: {}", if options.dry_run { "Checking" } else { "Executing" }, description, command);
+                            broadcast(nats.clone(), subjects.clone(), codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, targets, cmd, timeout).await;
+                            continue;
+                        }
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
                             Err(e) => {
-                                error!("Failed to serialize command: {}", e);
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        if options.dry_run {
+                            console!("Checking command on {}: {}", client_id, command);
+                            let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                            let cmd = Command::DryRun { command: Box::new(cmd) };
+                            match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                                Ok(result) => print_result(output, client_id, &result),
+                                Err(e) => console!("{}", e),
+                            }
+                            continue;
+                        }
+                        // How long to wait for output when streaming, or for the result otherwise
+                        let timeout = options.timeout.unwrap_or(Duration::from_secs(
+                            if options.stream { DEFAULT_STREAM_IDLE_SECS } else { DEFAULT_RESPONSE_TIMEOUT_SECS }
+                        ));
+                        if let Some(approval) = approval_timeout.filter(|_| needs_approval(&cmd, &dangerous_patterns)) {
+                            let request = CommandRequest::new(cmd);
+                            let send = send_approved(nats.clone(), subjects.clone(), prefix, codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, client_id.to_string(), request.clone(), timeout);
+                            hold_for_approval(&held, request.request_id, client_id, request.command.to_string(), approval, send);
+                            continue;
+                        }
+                        console!("Executing command on {}: {}", client_id, command);
+                        if options.stream {
+                            match stream_command(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, CommandRequest::new(cmd), timeout, print_chunk).await {
+                                Ok(result) => print_result(output, client_id, &result),
+                                Err(e) => console!("{}", e),
                             }
+                            continue;
+                        }
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
                         }
                     },
-                    "sysinfo" => {
-                        if parts.len() < 2 {
-                            println!("Usage: sysinfo <client_id>");
+                    "script" => {
+                        let (options, rest) = match parse_execute_options(&parts[1..]) {
+                            Ok(parsed) => parsed,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        if rest.len() != 2 || options.stream || options.stdin_file.is_some() {
+                            console!("Usage: script [--continue-on-error] [--dry-run] [--timeout <secs>] [--cwd <dir>] [--env KEY=VALUE]... [--shell <shell>] <client_id> <file>");
                             continue;
                         }
                         
-                        let client_id = parts[1];
-                        
-                        {
-                            let clients_map = clients.read().unwrap();
-                            if !clients_map.contains_key(client_id) {
-                                println!("Client {} not found", client_id);
+                        let path = rest[1];
+                        let commands = match tokio::fs::read_to_string(path).await {
+                            Ok(contents) => script_commands(&contents),
+                            Err(e) => {
+                                console!("Failed to read {}: {}", path, e);
                                 continue;
                             }
+                        };
+                        if commands.is_empty() {
+                            console!("No commands in {}", path);
+                            continue;
                         }
                         
-                        let command_subject = format!("{}.command.{}", prefix, client_id);
-                        let cmd = Command::GetSystemInfo;
-                        
-                        match to_string(&cmd) {
-                            Ok(json) => {
-                                println!("Requesting system info from {}", client_id);
-                                match nats.publish(command_subject, json.into()).await {
-                                    Ok(_) => info!("System info request sent to {}", client_id),
-                                    Err(e) => error!("Failed to send request: {}", e)
-                                }
-                                // Give the client time to process and respond
-                                tokio::time::sleep(Duration::from_millis(100)).await;
-                            },
+                        let client_id = rest[0];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
                             Err(e) => {
-                                error!("Failed to serialize command: {}", e);
+                                console!("{}", e);
+                                continue;
                             }
+                        };
+                        
+                        // Held as a whole, so it can't stop halfway waiting for approval
+                        let dangerous = !options.dry_run && commands.iter().any(|command| is_dangerous(command, &dangerous_patterns));
+                        let description = format!("script {} ({} commands)", path, commands.len());
+                        let script = run_script(nats.clone(), subjects.clone(), prefix, codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, client_id.clone(), commands, options);
+                        if let Some(approval) = approval_timeout.filter(|_| dangerous) {
+                            hold_for_approval(&held, uuid::Uuid::new_v4().to_string(), &client_id, description, approval, script);
+                            continue;
+                        }
+                        
+                        let (stop_tx, stop_rx) = oneshot::channel();
+                        *console_interrupt.lock().unwrap() = Some(stop_tx);
+                        console!("Running {} on {}, press Ctrl-C to stop", description, client_id);
+                        tokio::select! {
+                            _ = script => {},
+                            Ok(()) = stop_rx => console!("Script stopped, the command in progress may still be running on {}", client_id),
                         }
+                        console_interrupt.lock().unwrap().take();
                     },
-                    "ping" => {
-                        if parts.len() < 2 {
-                            println!("Usage: ping <client_id>");
+                    "watch" => {
+                        let (options, rest) = match parse_execute_options(&parts[1..]) {
+                            Ok(parsed) => parsed,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        if rest.len() < 3 || options.stream || options.dry_run || options.stdin_file.is_some() || options.continue_on_error {
+                            console!("Usage: watch [--timeout <secs>] [--cwd <dir>] [--env KEY=VALUE]... [--shell <shell>] <client_id> <interval> <command>");
                             continue;
                         }
                         
-                        let client_id = parts[1];
-                        
-                        {
-                            let clients_map = clients.read().unwrap();
-                            if !clients_map.contains_key(client_id) {
-                                println!("Client {} not found", client_id);
+                        let every = match parse_interval(rest[1]) {
+                            Ok(every) => every,
+                            Err(e) => {
+                                console!("{}", e);
                                 continue;
                             }
+                        };
+                        let command = rest[2..].join(" ");
+                        // Approving every run would defeat the point of watching
+                        if approval_timeout.is_some() && is_dangerous(&command, &dangerous_patterns) {
+                            console!("{} needs approval, which watch can't wait for; use execute instead", command);
+                            continue;
                         }
                         
-                        let command_subject = format!("{}.command.{}", prefix, client_id);
-                        let cmd = Command::Ping;
-                        
-                        match to_string(&cmd) {
-                            Ok(json) => {
-                                println!("Pinging client {}", client_id);
-                                match nats.publish(command_subject, json.into()).await {
-                                    Ok(_) => info!("Ping sent successfully to {}", client_id),
-                                    Err(e) => error!("Failed to send ping: {}", e)
-                                }
-                                // Give the client time to process and respond
-                                tokio::time::sleep(Duration::from_millis(100)).await;
-                            },
+                        let client_id = rest[0];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
                             Err(e) => {
-                                error!("Failed to serialize command: {}", e);
+                                console!("{}", e);
+                                continue;
                             }
+                        };
+                        
+                        let (stop_tx, stop_rx) = oneshot::channel();
+                        *console_interrupt.lock().unwrap() = Some(stop_tx);
+                        let watch = run_watch(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, output, &client_id, &command, every, options);
+                        tokio::select! {
+                            _ = watch => {},
+                            Ok(()) = stop_rx => console!("Stopped watching {} on {}", command, client_id),
                         }
+                        console_interrupt.lock().unwrap().take();
                     },
-                    "exit" => {
-                        println!("Shutting down server...");
-                        let _ = shutdown_tx_clone.send(true).await;
-                        break;
+                    "broadcast-tag" => {
+                        let dry_run = parts.get(1) == Some(&"--dry-run");
+                        let parts = if dry_run { &parts[1..] } else { &parts[..] };
+                        if parts.len() < 3 {
+                            console!("Usage: broadcast-tag [--dry-run] <key=value> <command>");
+                            continue;
+                        }
+                        
+                        let tag = match parse_tag(parts[1]) {
+                            Ok(tag) => tag,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let command = parts[2..].join(" ");
+                        
+                        let targets: Vec<(String, String)> = clients.read().await.iter()
+                            .filter(|(_, entry)| entry.info.has_tags(std::slice::from_ref(&tag)))
+                            .map(|(id, entry)| (id.clone(), entry.subject_prefix.clone()))
+                            .collect();
+                        if targets.is_empty() {
+                            console!("No clients tagged {}={}", tag.0, tag.1);
+                            continue;
+                        }
+                        
+                        let mut cmd = Command::Execute {
+                            command: command.clone(),
+                            cwd: None,
+                            env: None,
+                            stream: false,
+                            stdin: None,
+                            shell: None,
+                        };
+                        if dry_run {
+                            cmd = Command::DryRun { command: Box::new(cmd) };
+                        }
+                        let description = format!("{} clients tagged {}={}", targets.len(), tag.0, tag.1);
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        if let Some(approval) = approval_timeout.filter(|_| needs_approval(&cmd, &dangerous_patterns)) {
+                            let send = broadcast(nats.clone(), subjects.clone(), codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, targets, cmd.clone(), timeout);
+                            hold_for_approval(&held, uuid::Uuid::new_v4().to_string(), &description, cmd.to_string(), approval, send);
+                            continue;
+                        }
+                        
+                        console!("{} command on {}: {}", if dry_run { "Checking" } else { "Executing" }, description, command);
+                        broadcast(nats.clone(), subjects.clone(), codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, targets, cmd, timeout).await;
+                    },
+                    "sysinfo" => {
+                        if parts.len() < 2 {
+                            console!("Usage: sysinfo <client_id> [--all]");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let all_interfaces = parts[2..].contains(&"--all");
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        console!("Requesting system info from {}", client_id);
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::GetSystemInfo, timeout).await {
+                            Ok(result) if result.success => {
+                                match from_slice::<SystemInfo>(result.output.as_bytes()) {
+                                    Ok(info) => print_system_info(output, client_id, &info, all_interfaces),
+                                    Err(_) => print_result(output, client_id, &result),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("System info request failed: {}", e),
+                        }
+                    },
+                    "agentinfo" => {
+                        if parts.len() < 2 {
+                            console!("Usage: agentinfo <client_id>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::GetAgentInfo, timeout).await {
+                            Ok(result) if result.success => {
+                                match from_slice::<AgentInfo>(result.output.as_bytes()) {
+                                    Ok(info) => print_agent_info(output, client_id, &info),
+                                    Err(_) => print_result(output, client_id, &result),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "disk" => {
+                        if parts.len() < 2 {
+                            console!("Usage: disk <client_id>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::GetDiskUsage, timeout).await {
+                            Ok(result) if result.success => {
+                                match from_slice::<Vec<DiskInfo>>(result.output.as_bytes()) {
+                                    Ok(disks) => print_disk_usage(client_id, &disks),
+                                    Err(_) => print_result(output, client_id, &result),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "netstat" => {
+                        if parts.len() < 2 {
+                            console!("Usage: netstat <client_id>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::ListConnections, timeout).await {
+                            Ok(result) if result.success => {
+                                match from_slice::<Vec<ConnectionInfo>>(result.output.as_bytes()) {
+                                    Ok(connections) => print_connections(client_id, &connections, &result.stderr),
+                                    Err(_) => print_result(output, client_id, &result),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "packages" => {
+                        if parts.len() > 3 || parts.len() < 2 {
+                            console!("Usage: packages <client_id> [filter]");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        // Leave the client its default command timeout to list them
+                        let timeout = Duration::from_secs(DEFAULT_COMMAND_TIMEOUT_SECS + DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        let filter = parts.get(2).map(|filter| filter.to_string());
+                        let mut list: Option<PackageList> = None;
+                        // Fetch a page at a time so a long list doesn't exceed the payload limit
+                        let listed = loop {
+                            let offset = list.as_ref().map_or(0, |list| list.packages.len());
+                            let cmd = Command::ListPackages { filter: filter.clone(), offset, limit: PACKAGE_PAGE_SIZE };
+                            let result = match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                                Ok(result) if result.success => result,
+                                Ok(result) => break Err(Some(result)),
+                                Err(e) => {
+                                    console!("{}", e);
+                                    break Err(None);
+                                },
+                            };
+                            let page = match from_slice::<PackageList>(result.output.as_bytes()) {
+                                Ok(page) => page,
+                                Err(_) => break Err(Some(result)),
+                            };
+                            let list = list.get_or_insert_with(|| PackageList {
+                                manager: page.manager.clone(),
+                                total: page.total,
+                                offset: 0,
+                                packages: Vec::new(),
+                            });
+                            let last_page = page.packages.is_empty();
+                            list.packages.extend(page.packages);
+                            list.total = page.total;
+                            if last_page || list.packages.len() >= list.total {
+                                break Ok(());
+                            }
+                            if output == OutputFormat::Text {
+                                console!("Fetched {} of {} packages", list.packages.len(), list.total);
+                            }
+                        };
+                        match (listed, list) {
+                            (Ok(()), Some(list)) => print_packages(output, client_id, &list),
+                            (Err(Some(result)), _) => print_result(output, client_id, &result),
+                            _ => {},
+                        }
+                    },
+                    "time" => {
+                        if parts.len() != 2 {
+                            console!("Usage: time <client_id>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        let sent = SystemTime::now();
+                        let outcome = send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::GetTime, timeout).await;
+                        let received = SystemTime::now();
+                        match outcome {
+                            Ok(result) if result.success => {
+                                match from_slice::<ClientTime>(result.output.as_bytes()) {
+                                    Ok(time) => print_time(output, client_id, &time, sent, received),
+                                    Err(_) => print_result(output, client_id, &result),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "load" => {
+                        if parts.len() < 2 {
+                            console!("Usage: load <client_id>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::GetLoad, timeout).await {
+                            Ok(result) if result.success => {
+                                match from_slice::<LoadInfo>(result.output.as_bytes()) {
+                                    Ok(load) => print_load(output, client_id, &load),
+                                    Err(_) => print_result(output, client_id, &result),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "logtail" => {
+                        if parts.len() < 2 || parts.len() > 4 {
+                            console!("Usage: logtail <client_id> [path] [lines]");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        // A lone number is a line count for the client's own log
+                        let (path, lines) = match &parts[2..] {
+                            [] => (None, None),
+                            [lines] if lines.parse::<usize>().is_ok() => (None, Some(*lines)),
+                            [path] => (Some(path.to_string()), None),
+                            [path, lines] => (Some(path.to_string()), Some(*lines)),
+                            _ => unreachable!(),
+                        };
+                        let lines = match lines.map(str::parse::<usize>) {
+                            Some(Ok(lines)) => lines,
+                            Some(Err(_)) => {
+                                console!("Expected a number of lines, got {}", parts[parts.len() - 1]);
+                                continue;
+                            },
+                            None => DEFAULT_TAIL_LINES,
+                        };
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        let cmd = Command::GetLogTail { path, lines };
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "custom" => {
+                        if parts.len() < 3 {
+                            console!("Usage: custom <client_id> <name> [json_args]");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let name = parts[2];
+                        let args = match parts.get(3..).filter(|rest| !rest.is_empty()) {
+                            Some(rest) => match serde_json::from_str(&rest.join(" ")) {
+                                Ok(args) => args,
+                                Err(e) => {
+                                    console!("Arguments must be JSON: {}", e);
+                                    continue;
+                                }
+                            },
+                            None => serde_json::Value::Null,
+                        };
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        let cmd = Command::Custom { name: name.to_string(), args };
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "service" => {
+                        if parts.len() < 3 {
+                            console!("Usage: service <client_id> <name>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let name = parts[2];
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        let cmd = Command::ServiceStatus { name: name.to_string() };
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) if result.success => {
+                                match from_slice::<ServiceInfo>(result.output.as_bytes()) {
+                                    Ok(info) => print_service(output, client_id, &info),
+                                    Err(_) => print_result(output, client_id, &result),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "probe" => {
+                        let port = parts.get(3).map(|port| port.parse::<u16>());
+                        let timeout_ms = parts.get(4).map_or(Ok(DEFAULT_PROBE_TIMEOUT_MS), |ms| ms.parse::<u64>());
+                        let (Some(Ok(port)), Ok(timeout_ms)) = (port, timeout_ms) else {
+                            console!("Usage: probe <client_id> <host> <port> [timeout_ms]");
+                            continue;
+                        };
+                        
+                        let client_id = parts[1];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        // Leave the client time to report a probe that used its whole timeout
+                        let timeout = Duration::from_millis(timeout_ms) + Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        let cmd = Command::TcpProbe { host: parts[2].to_string(), port, timeout_ms };
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) if result.success => {
+                                match from_slice::<ProbeResult>(result.output.as_bytes()) {
+                                    Ok(probe) => print_probe(output, client_id, &probe),
+                                    Err(_) => print_result(output, client_id, &result),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "schedule" => {
+                        if parts.len() < 4 {
+                            console!("Usage: schedule <client_id> <time|+delay> <command>, e.g. 2030-01-31T02:00:00Z or +30m");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let run_at = match parse_run_at(parts[2]) {
+                            Ok(run_at) => run_at,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let cmd = Command::ScheduleExecute { command: parts[3..].join(" "), run_at };
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        if let Some(approval) = approval_timeout.filter(|_| needs_approval(&cmd, &dangerous_patterns)) {
+                            let request = CommandRequest::new(cmd);
+                            let send = send_approved(nats.clone(), subjects.clone(), prefix, codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, client_id.to_string(), request.clone(), timeout);
+                            hold_for_approval(&held, request.request_id, client_id, request.command.to_string(), approval, send);
+                            continue;
+                        }
+                        console!("Sending {} to {}", cmd, client_id);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "scheduled" => {
+                        if parts.len() < 2 {
+                            console!("Usage: scheduled <client_id>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::ListScheduled, timeout).await {
+                            Ok(result) if result.success => {
+                                match from_slice::<Vec<ScheduledJob>>(result.output.as_bytes()) {
+                                    Ok(jobs) => print_scheduled(client_id, &jobs),
+                                    Err(_) => print_result(output, client_id, &result),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "unschedule" => {
+                        if parts.len() < 3 {
+                            console!("Usage: unschedule <client_id> <job_id>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let cmd = Command::CancelScheduled { job_id: parts[2].to_string() };
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "tail" => {
+                        let lines = parts.get(3).map_or(Ok(DEFAULT_TAIL_LINES), |lines| lines.parse::<usize>());
+                        let (true, Ok(lines)) = (parts.len() >= 3, lines) else {
+                            console!("Usage: tail <client_id> <path> [lines]");
+                            continue;
+                        };
+                        
+                        let client_id = parts[1];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let request = CommandRequest::new(Command::TailFile { path: parts[2].to_string(), lines });
+                        let request_id = request.request_id.clone();
+                        let (stop_tx, stop_rx) = oneshot::channel();
+                        *console_interrupt.lock().unwrap() = Some(stop_tx);
+                        
+                        console!("Following {} on {}, press Ctrl-C to stop", parts[2], client_id);
+                        let idle_timeout = Duration::from_secs(DEFAULT_STREAM_IDLE_SECS);
+                        let tail = stream_command(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, request, idle_timeout, print_chunk);
+                        tokio::pin!(tail);
+                        let outcome = tokio::select! {
+                            outcome = &mut tail => outcome,
+                            Ok(()) = stop_rx => {
+                                // The client ends the stream once it stops following the file
+                                let cancel = Command::CancelRequest { request_id };
+                                let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                                if let Err(e) = send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cancel, timeout).await {
+                                    console!("Failed to stop tail: {}", e);
+                                }
+                                tail.await
+                            },
+                        };
+                        console_interrupt.lock().unwrap().take();
+                        match outcome {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "shell" => {
+                        let (shell, target) = match parts[1..] {
+                            [target] => (None, target),
+                            ["--shell", shell, target] => match shell.parse::<Shell>() {
+                                Ok(Shell::Direct) | Err(_) => {
+                                    console!("Expected sh, bash, pwsh or cmd for --shell, got {}", shell);
+                                    continue;
+                                },
+                                Ok(shell) => (Some(shell), target),
+                            },
+                            _ => {
+                                console!("Usage: shell [--shell <shell>] <client_id>");
+                                continue;
+                            }
+                        };
+                        // Nobody could approve each line typed into it
+                        if approval_timeout.is_some() {
+                            console!("Shell sessions aren't available while commands need approval");
+                            continue;
+                        }
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, target).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let (cols, rows) = ratatui::crossterm::terminal::size().unwrap_or((80, 24));
+                        let request = CommandRequest::new(Command::StartShell { shell, rows, cols });
+                        let request_id = request.request_id.clone();
+                        let input_subject = subjects.shell_input(&prefix, &request_id);
+                        // Lines typed before the shell printed anything wait for it to be listening
+                        let started = Arc::new(AtomicBool::new(false));
+                        let on_chunk = {
+                            let started = started.clone();
+                            move |chunk: &OutputChunk| {
+                                started.store(true, Ordering::Relaxed);
+                                print_chunk(chunk);
+                            }
+                        };
+                        
+                        console!("Opening a shell on {}. Type exit to leave, or ~. on its own line to kill it.", client_id);
+                        console!("Ctrl-C interrupts the command running in it.");
+                        let idle_timeout = Duration::from_secs(DEFAULT_STREAM_IDLE_SECS);
+                        let session = stream_command(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, request, idle_timeout, on_chunk);
+                        tokio::pin!(session);
+                        let mut typed: VecDeque<Vec<u8>> = VecDeque::new();
+                        let mut kill = false;
+                        let outcome = loop {
+                            let (stop_tx, stop_rx) = oneshot::channel();
+                            *console_interrupt.lock().unwrap() = Some(stop_tx);
+                            tokio::select! {
+                                outcome = &mut session => break outcome,
+                                line = input_rx.recv() => match line {
+                                    Some(line) if line.trim() != "~." => {
+                                        transcript::input(line.trim_end());
+                                        // Enter sends a carriage return, which the terminal turns into a newline
+                                        typed.push_back(format!("{}\r", line.trim_end_matches(['\r', '\n'])).into_bytes());
+                                    },
+                                    // Killed on ~. and when the console's input closes
+                                    _ => kill = true,
+                                },
+                                Ok(()) = stop_rx => typed.push_back(vec![0x03]),
+                                _ = tokio::time::sleep(Duration::from_millis(100)), if !typed.is_empty() => {},
+                            }
+                            if kill {
+                                let cancel = Command::CancelRequest { request_id: request_id.clone() };
+                                let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                                if let Err(e) = send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cancel, timeout).await {
+                                    console!("Failed to kill shell: {}", e);
+                                }
+                                break session.await;
+                            }
+                            while started.load(Ordering::Relaxed) {
+                                let Some(data) = typed.pop_front() else {
+                                    break;
+                                };
+                                if let Err(e) = send_shell_input(&nats, &input_subject, codec, data).await {
+                                    console!("Failed to send input: {}", e);
+                                }
+                            }
+                        };
+                        console_interrupt.lock().unwrap().take();
+                        match outcome {
+                            Ok(result) if result.success => console!("\nShell on {} closed", client_id),
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "subs" => {
+                        print_subscriptions(&subscriptions.list(), pending.len());
+                    },
+                    "history" => {
+                        if parts.len() < 2 {
+                            console!("Usage: history <client_id>");
+                            continue;
+                        }
+                        
+                        // Kept for clients that have gone away too, which can only be
+                        // looked up by id
+                        let client_id = if parts[1].starts_with("host:") {
+                            match resolve_client(&clients, parts[1]).await {
+                                Ok((client_id, _)) => client_id,
+                                Err(e) => {
+                                    console!("{}", e);
+                                    continue;
+                                }
+                            }
+                        } else {
+                            parts[1].to_string()
+                        };
+                        print_history(&client_id, &history.get(&client_id));
+                    },
+                    "env" => {
+                        if parts.len() < 2 {
+                            console!("Usage: env <client_id> [KEY]...");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let keys = if parts.len() > 2 {
+                            Some(parts[2..].iter().map(|key| key.to_string()).collect())
+                        } else {
+                            None
+                        };
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::GetEnv { keys }, timeout).await {
+                            Ok(result) if result.success => {
+                                match from_slice::<BTreeMap<String, String>>(result.output.as_bytes()) {
+                                    Ok(vars) if vars.is_empty() => console!("No matching variables on {}", client_id),
+                                    Ok(vars) => {
+                                        for (key, value) in vars {
+                                            console!("{}={}", key, value);
+                                        }
+                                    },
+                                    Err(_) => print_result(output, client_id, &result),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "ping" => {
+                        let count = match parts.get(2..) {
+                            Some([]) => Some(1),
+                            Some(["--count", count]) => count.parse::<u32>().ok().filter(|count| *count > 0),
+                            _ => None,
+                        };
+                        let (Some(client_id), Some(count)) = (parts.get(1).copied(), count) else {
+                            console!("Usage: ping <client_id> [--count N]");
+                            continue;
+                        };
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        console!("Pinging client {}", client_id);
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        let mut round_trips = Vec::new();
+                        for seq in 1..=count {
+                            if seq > 1 {
+                                tokio::time::sleep(PING_INTERVAL).await;
+                            }
+                            match ping(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, timeout).await {
+                                Ok(rtt) => {
+                                    console!("Reply from {}: seq={} time={:.1}ms", client_id, seq, millis(rtt));
+                                    round_trips.push(rtt);
+                                },
+                                Err(e) => console!("seq={}: {}", seq, e),
+                            }
+                        }
+                        if count > 1 {
+                            print_ping_summary(client_id, count, &round_trips);
+                        }
+                    },
+                    "upload" => {
+                        if parts.len() < 4 {
+                            console!("Usage: upload <client_id> <local_path> <remote_path> [mode]");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let local_path = parts[2];
+                        let remote_path = parts[3];
+                        
+                        let mode = match parts.get(4).map(|m| u32::from_str_radix(m, 8)) {
+                            Some(Ok(mode)) => Some(mode),
+                            Some(Err(_)) => {
+                                console!("Invalid mode {}, expected octal like 755", parts[4]);
+                                continue;
+                            },
+                            None => None,
+                        };
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let contents = match tokio::fs::read(local_path).await {
+                            Ok(contents) => contents,
+                            Err(e) => {
+                                console!("Failed to read {}: {}", local_path, e);
+                                continue;
+                            }
+                        };
+                        
+                        let cmd = Command::UploadFile {
+                            path: remote_path.to_string(),
+                            contents,
+                            mode,
+                        };
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        if let Some(approval) = approval_timeout.filter(|_| needs_approval(&cmd, &dangerous_patterns)) {
+                            let request = CommandRequest::new(cmd);
+                            let send = send_approved(nats.clone(), subjects.clone(), prefix, codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, client_id.to_string(), request.clone(), timeout);
+                            hold_for_approval(&held, request.request_id, client_id, format!("Upload of {} to {}", local_path, remote_path), approval, send);
+                            continue;
+                        }
+                        console!("Uploading {} to {}:{}", local_path, client_id, remote_path);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "download" => {
+                        if parts.len() < 4 {
+                            console!("Usage: download <client_id> <remote_path> <local_path>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let remote_path = parts[2];
+                        let local_path = parts[3];
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        console!("Downloading {}:{} to {}", client_id, remote_path, local_path);
+                        let cmd = Command::DownloadFile { path: remote_path.to_string() };
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) if result.success => {
+                                match tokio::fs::write(local_path, &result.data).await {
+                                    Ok(_) => console!("Saved {} bytes to {}", result.data.len(), local_path),
+                                    Err(e) => console!("Failed to write {}: {}", local_path, e),
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("Download failed: {}", e),
+                        }
+                    },
+                    "readrange" => {
+                        if parts.len() < 5 {
+                            console!("Usage: readrange <client_id> <remote_path> <offset|-N> <length> [local_path], -N counting back from the end");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let remote_path = parts[2];
+                        let (from_end, offset) = match parts[3].strip_prefix('-') {
+                            Some(back) => (true, back.parse::<u64>()),
+                            None => (false, parts[3].parse::<u64>()),
+                        };
+                        let (offset, length) = match (offset, parts[4].parse::<u64>()) {
+                            (Ok(offset), Ok(length)) => (offset, length),
+                            _ => {
+                                console!("Offset and length must be numbers of bytes");
+                                continue;
+                            }
+                        };
+                        let local_path = parts.get(5);
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        let offset = if from_end {
+                            // Ask for nothing to learn the file's size
+                            let cmd = Command::ReadFileRange { path: remote_path.to_string(), offset: 0, length: 0 };
+                            match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                                Ok(result) if result.success => match from_slice::<FileRange>(result.output.as_bytes()) {
+                                    Ok(range) => range.total_size.saturating_sub(offset),
+                                    Err(_) => {
+                                        print_result(output, client_id, &result);
+                                        continue;
+                                    }
+                                },
+                                Ok(result) => {
+                                    print_result(output, client_id, &result);
+                                    continue;
+                                },
+                                Err(e) => {
+                                    console!("Read failed: {}", e);
+                                    continue;
+                                }
+                            }
+                        } else {
+                            offset
+                        };
+                        
+                        let cmd = Command::ReadFileRange { path: remote_path.to_string(), offset, length };
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) if result.success => {
+                                let range = match from_slice::<FileRange>(result.output.as_bytes()) {
+                                    Ok(range) => range,
+                                    Err(_) => {
+                                        print_result(output, client_id, &result);
+                                        continue;
+                                    }
+                                };
+                                let span = format!(
+                                    "bytes {}-{} of {}",
+                                    range.offset, range.offset + range.length, range.total_size
+                                );
+                                match local_path {
+                                    Some(local_path) => match tokio::fs::write(local_path, &result.data).await {
+                                        Ok(_) => console!("Saved {} to {}", span, local_path),
+                                        Err(e) => console!("Failed to write {}: {}", local_path, e),
+                                    },
+                                    None => {
+                                        print_header(client_id, &format!("{} {}", remote_path, span));
+                                        let text = String::from_utf8_lossy(&result.data);
+                                        print!("{}", text);
+                                        transcript::write(&text);
+                                        if !result.data.ends_with(b"\n") {
+                                            console!();
+                                        }
+                                        print_footer();
+                                    },
+                                }
+                            },
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("Read failed: {}", e),
+                        }
+                    },
+                    "kill" => {
+                        if parts.len() < 3 {
+                            console!("Usage: kill <client_id> <pid> [--force]");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let pid = match parts[2].parse::<u32>() {
+                            Ok(pid) => pid,
+                            Err(_) => {
+                                console!("Invalid PID: {}", parts[2]);
+                                continue;
+                            }
+                        };
+                        let force = parts[3..].contains(&"--force");
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let cmd = Command::KillProcess { pid, force };
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        if let Some(approval) = approval_timeout.filter(|_| needs_approval(&cmd, &dangerous_patterns)) {
+                            let request = CommandRequest::new(cmd);
+                            let send = send_approved(nats.clone(), subjects.clone(), prefix, codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, client_id.to_string(), request.clone(), timeout);
+                            hold_for_approval(&held, request.request_id, client_id, request.command.to_string(), approval, send);
+                            continue;
+                        }
+                        console!("Killing process {} on {}", pid, client_id);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "cancel" => {
+                        if parts.len() < 3 {
+                            console!("Usage: cancel <client_id> <request_id>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        console!("Cancelling {} on {}", parts[2], client_id);
+                        let cmd = Command::CancelRequest { request_id: parts[2].to_string() };
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "loglevel" => {
+                        if parts.len() < 3 {
+                            console!("Usage: loglevel <client_id> <debug|info|warning|error>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let level = match parts[2].parse::<LogLevel>() {
+                            Ok(level) => level,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        console!("Setting log level on {} to {}", client_id, level);
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::SetLogLevel(level), timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "rotatelog" => {
+                        if parts.len() != 2 {
+                            console!("Usage: rotatelog <client_id>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::RotateLog, timeout).await {
+                            Ok(result) if result.success => console!("Log of {} rotated, earlier lines are in {}", client_id, result.output),
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "restart-agent" => {
+                        if parts.len() != 2 {
+                            console!("Usage: restart-agent <client_id>");
+                            continue;
+                        }
+                        
+                        let client_id = parts[1];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        if let Some(approval) = approval_timeout {
+                            let request = CommandRequest::new(Command::RestartAgent);
+                            let send = send_approved(nats.clone(), subjects.clone(), prefix, codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, client_id.to_string(), request.clone(), timeout);
+                            hold_for_approval(&held, request.request_id, client_id, request.command.to_string(), approval, send);
+                            continue;
+                        }
+                        console!("Restarting client {}", client_id);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, Command::RestartAgent, timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "reboot" | "poweroff" => {
+                        let dry_run = parts.contains(&"--dry-run");
+                        let parts: Vec<&str> = parts.iter().copied().filter(|part| *part != "--dry-run").collect();
+                        if parts.len() < 2 {
+                            console!("Usage: reboot [--dry-run] <client_id> [delay_secs] | poweroff [--dry-run] <client_id>");
+                            continue;
+                        }
+                        
+                        let mut cmd = match (parts[0], parts.get(2)) {
+                            ("reboot", None) => Command::RebootMachine { delay_secs: None },
+                            ("reboot", Some(delay)) => match delay.parse::<u32>() {
+                                Ok(delay) => Command::RebootMachine { delay_secs: Some(delay) },
+                                Err(_) => {
+                                    console!("Invalid delay: {}", delay);
+                                    continue;
+                                }
+                            },
+                            _ => Command::PowerOff,
+                        };
+                        if dry_run {
+                            cmd = Command::DryRun { command: Box::new(cmd) };
+                        }
+                        
+                        let client_id = parts[1];
+                        let (client_id, prefix) = match resolve_client(&clients, client_id).await {
+                            Ok(found) => found,
+                            Err(e) => {
+                                console!("{}", e);
+                                continue;
+                            }
+                        };
+                        let client_id = client_id.as_str();
+                        
+                        let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                        if let Some(approval) = approval_timeout.filter(|_| needs_approval(&cmd, &dangerous_patterns)) {
+                            let request = CommandRequest::new(cmd);
+                            let send = send_approved(nats.clone(), subjects.clone(), prefix, codec, jetstream, clients.clone(), pending.clone(), history.clone(), output, client_id.to_string(), request.clone(), timeout);
+                            hold_for_approval(&held, request.request_id, client_id, request.command.to_string(), approval, send);
+                            continue;
+                        }
+                        console!("Sending {} to {}", cmd, client_id);
+                        match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd, timeout).await {
+                            Ok(result) => print_result(output, client_id, &result),
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "pending" => print_held(&held),
+                    // Whoever queued a command can withdraw it, but approving it takes someone else
+                    "approve" => {
+                        console!("Held commands are approved by another operator, with `rs-nats approve <request_id>` and NATS credentials allowed to publish on {}",
+                            approval_subjects.join(", "));
+                    },
+                    "deny" => {
+                        if parts.len() != 2 {
+                            console!("Usage: deny <request_id>");
+                            continue;
+                        }
+                        match decide(&held, parts[1], false) {
+                            Ok((target, command)) => {
+                                console!("Denied {} on {}, not sent", command, target);
+                                info!("Denied {} on {} ({})", command, target, parts[1]);
+                            },
+                            Err(e) => console!("{}", e),
+                        }
+                    },
+                    "exit" => {
+                        console!("Shutting down server...");
+                        let _ = shutdown_tx_clone.send(true).await;
+                        break;
                     },
                     _ => {
-                        println!("Unknown command: {}", parts[0]);
+                        console!("Unknown command: {}", parts[0]);
+                    }
+                }
+            }
+        });
+        
+        // Stop on SIGINT/SIGTERM the same way as on the exit command, unless a console
+        // command is waiting to be interrupted
+        let signal_tx = shutdown_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                shutdown_signal().await;
+                if let Some(stop) = interrupt.lock().unwrap().take() {
+                    let _ = stop.send(());
+                    continue;
+                }
+                info!("Received termination signal");
+                let _ = signal_tx.send(true).await;
+                break;
+            }
+        });
+        
+        // Wait for shutdown signal
+        let _ = shutdown_rx.recv().await;
+        self.finish(metrics_server).await;
+        
+        Ok(())
+    }
+    
+    /// Start the server and run the full-screen dashboard (see `crate::tui`) until
+    /// the operator quits or a termination signal arrives
+    pub async fn run_tui(&self) -> Result<()> {
+        self.start().await?;
+        let metrics_server = self.start_metrics().await?;
+        
+        let result = crate::tui::run(self).await;
+        self.finish(metrics_server).await;
+        result
+    }
+    
+    /// Serve Prometheus metrics if a `metrics_addr` is set
+    async fn start_metrics(&self) -> Result<MetricsServer> {
+        let Some(addr) = self.metrics_addr else {
+            return Ok(None);
+        };
+        
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        info!("Serving metrics on http://{}/metrics", addr);
+        let task = tokio::spawn(metrics::serve(listener, async {
+            let _ = shutdown_rx.await;
+        }));
+        Ok(Some((task, shutdown_tx)))
+    }
+    
+    /// Save the registry, stop the metrics endpoint, and flush pending messages
+    async fn finish(&self, metrics_server: MetricsServer) {
+        info!("Server shutting down");
+        
+        if let Some(path) = &self.registry_path {
+            match save_registry(path, &self.connected_clients).await {
+                Ok(_) => info!("Saved client registry to {}", path.display()),
+                Err(e) => warn!("Failed to save client registry to {}: {}", path.display(), e),
+            }
+        }
+        
+        if let Some((task, shutdown_tx)) = metrics_server {
+            let _ = shutdown_tx.send(());
+            match task.await {
+                Ok(Err(e)) => warn!("Metrics endpoint failed: {}", e),
+                Err(e) => warn!("Metrics endpoint task failed: {}", e),
+                Ok(Ok(())) => {},
+            }
+        }
+        
+        if let Err(e) = self.nats_client.flush().await {
+            warn!("Failed to flush NATS connection: {}", e);
+        }
+    }
+}
+
+/// Settings for a `Server`. Anything not set falls back to the built-in default.
+#[derive(Clone, Default)]
+pub struct ServerBuilder {
+    nats_url: Option<String>,
+    subject_prefixes: Vec<String>,
+    codec: Option<Codec>,
+    heartbeat_interval: Option<Duration>,
+    stale_heartbeats: Option<u32>,
+    connection: Option<ConnectionOptions>,
+    credentials: Option<PathBuf>,
+    token: Option<String>,
+    user_and_password: Option<(String, String)>,
+    tls: Option<TlsOptions>,
+    registry_path: Option<PathBuf>,
+    queue_group: Option<String>,
+    metrics_addr: Option<SocketAddr>,
+    jetstream: bool,
+    history_size: Option<usize>,
+    audit_log: Option<PathBuf>,
+    transcript: Option<PathBuf>,
+    api: bool,
+    subjects: Option<SubjectLayout>,
+    output: OutputFormat,
+    approval_timeout: Option<Duration>,
+    dangerous_patterns: Option<Vec<String>>,
+}
+
+impl ServerBuilder {
+    /// NATS server to connect to [default: `DEFAULT_NATS_URL`]
+    pub fn nats_url(mut self, url: impl Into<String>) -> Self {
+        self.nats_url = Some(url.into());
+        self
+    }
+    
+    /// Serve clients registering under `prefix`. Can be called more than once to serve
+    /// several namespaces; commands go out under the prefix the client registered
+    /// with, and the first one is used for clients the server hasn't seen
+    /// [default: `DEFAULT_SUBJECT_PREFIX`]
+    pub fn subject_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.subject_prefixes.push(prefix.into());
+        self
+    }
+    
+    /// Message encoding and encryption, which must match the clients'
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+    
+    /// How often clients send heartbeats, which must match the clients' interval
+    /// [default: `DEFAULT_HEARTBEAT_SECS`]
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+    
+    /// Drop a client after this many heartbeat intervals without one
+    /// [default: `DEFAULT_STALE_HEARTBEATS`]
+    pub fn stale_heartbeats(mut self, count: u32) -> Self {
+        self.stale_heartbeats = Some(count);
+        self
+    }
+    
+    /// Reconnect and ping tuning for the NATS connection
+    pub fn connection(mut self, options: ConnectionOptions) -> Self {
+        self.connection = Some(options);
+        self
+    }
+    
+    /// Authenticate to NATS with a credentials (`.creds`) file
+    pub fn credentials(mut self, path: impl Into<PathBuf>) -> Self {
+        self.credentials = Some(path.into());
+        self
+    }
+    
+    /// Authenticate to NATS with a token
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+    
+    /// Authenticate to NATS with a user name and password
+    pub fn user_and_password(mut self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.user_and_password = Some((user.into(), password.into()));
+        self
+    }
+    
+    /// Require TLS for the NATS connection
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+    
+    /// Save known clients to this file periodically and on shutdown, and restore them on start
+    pub fn registry_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.registry_path = Some(path.into());
+        self
+    }
+    
+    /// Split registrations and responses with other servers in the same queue group
+    pub fn queue_group(mut self, group: impl Into<String>) -> Self {
+        self.queue_group = Some(group.into());
+        self
+    }
+    
+    /// Serve Prometheus metrics at `/metrics` on this address while `run()` is running
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+    
+    /// Store commands in a JetStream stream until the client picks them up (see
+    /// `crate::jetstream`); clients must be started the same way
+    pub fn jetstream(mut self, enabled: bool) -> Self {
+        self.jetstream = enabled;
+        self
+    }
+    
+    /// Commands and results to remember per client, see `Server::history`; 0 turns
+    /// history off [default: `DEFAULT_HISTORY_SIZE`]
+    pub fn history_size(mut self, size: usize) -> Self {
+        self.history_size = Some(size);
+        self
+    }
+    
+    /// Append every command sent and every result received to this file, see `crate::audit`
+    pub fn audit_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.audit_log = Some(path.into());
+        self
+    }
+    
+    /// Also append everything the console prints, and the lines typed into it, to this
+    /// file, see `crate::transcript`. Only one server per process can keep a transcript.
+    pub fn transcript(mut self, path: impl Into<PathBuf>) -> Self {
+        self.transcript = Some(path.into());
+        self
+    }
+    
+    /// How `run()` prints clients, system information, and results [default: text]
+    pub fn output(mut self, output: OutputFormat) -> Self {
+        self.output = output;
+        self
+    }
+    
+    /// Relay commands requested on `{prefix}.api.execute` to clients and reply with their
+    /// results, see `ApiRequest`. Anyone who can publish there can command every client
+    /// under the prefix, so limit it with NATS permissions or an encryption key, which
+    /// requests need to be encrypted with like commands. [default: false]
+    pub fn api(mut self, enabled: bool) -> Self {
+        self.api = enabled;
+        self
+    }
+    
+    /// Subjects to talk to clients on, which have to match theirs [default: `SubjectLayout::default()`]
+    pub fn subject_layout(mut self, layout: SubjectLayout) -> Self {
+        self.subjects = Some(layout);
+        self
+    }
+    
+    /// Hold dangerous console commands until another operator approves them over NATS
+    /// (see `send_decision`), denying them if nobody does within `timeout`. Only the console asks for
+    /// approval; commands sent through the library API go out directly, and the
+    /// NATS command API refuses them.
+    pub fn require_approval(mut self, timeout: Duration) -> Self {
+        self.approval_timeout = Some(timeout);
+        self
+    }
+    
+    /// Shell commands that need approval, as patterns where `*` matches any run of
+    /// characters. Reboot and power off always do. [default: `DEFAULT_DANGEROUS_PATTERNS`]
+    pub fn dangerous_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.dangerous_patterns = Some(patterns);
+        self
+    }
+    
+    /// Connect to NATS and create the server
+    pub async fn build(self) -> Result<Server> {
+        let url = self.nats_url.as_deref().unwrap_or(DEFAULT_NATS_URL);
+        let mut prefixes: Vec<String> = Vec::new();
+        for prefix in self.subject_prefixes {
+            if !prefixes.contains(&prefix) {
+                prefixes.push(prefix);
+            }
+        }
+        if prefixes.is_empty() {
+            prefixes.push(DEFAULT_SUBJECT_PREFIX.to_string());
+        }
+        let heartbeat_interval = self.heartbeat_interval
+            .unwrap_or(Duration::from_secs(DEFAULT_HEARTBEAT_SECS));
+        let stale_heartbeats = self.stale_heartbeats.unwrap_or(DEFAULT_STALE_HEARTBEATS);
+        if heartbeat_interval.is_zero() || stale_heartbeats == 0 {
+            return Err(RsNatsError::ConfigError(
+                "Heartbeat interval and stale heartbeat count must be greater than zero".to_string()
+            ).into());
+        }
+        if self.approval_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(RsNatsError::ConfigError("Approval timeout must be greater than zero".to_string()).into());
+        }
+        let subjects = Arc::new(self.subjects.unwrap_or_default());
+        subjects.validate()?;
+        
+        let audit = match &self.audit_log {
+            Some(path) => {
+                info!("Writing audit log to {}", path.display());
+                Some(AuditLog::open(path)?)
+            },
+            None => None,
+        };
+        if let Some(path) = &self.transcript {
+            info!("Writing console transcript to {}", path.display());
+            transcript::start(path)?;
+        }
+        
+        let mut connection = self.connection.unwrap_or_default();
+        if self.credentials.is_some() {
+            connection.credentials = self.credentials;
+        }
+        if self.token.is_some() {
+            connection.token = self.token;
+        }
+        if self.user_and_password.is_some() {
+            connection.user_and_password = self.user_and_password;
+        }
+        if self.tls.is_some() {
+            connection.tls = self.tls;
+        }
+        connection.name.get_or_insert_with(|| format!("rs-nats-server {}", get_client_id()));
+        
+        info!("Connecting to NATS server at {}", redact_url(url));
+        let nats_client = connect(url, &connection).await?;
+        if self.jetstream {
+            for prefix in &prefixes {
+                crate::jetstream::ensure_command_stream(&nats_client, &subjects, prefix).await?;
+            }
+        }
+        
+        let codec = self.codec.unwrap_or_default();
+        let pending_requests = PendingRequests::default();
+        let subscriptions = Subscriptions::default();
+        let history = Arc::new(CommandHistory::new(self.history_size.unwrap_or(DEFAULT_HISTORY_SIZE), audit));
+        let (responses, received) = mpsc::channel(RESPONSE_QUEUE_CAPACITY);
+        spawn_response_dispatcher(received, codec, self.output, pending_requests.clone(), history.clone());
+        // Before any command can be sent, so no result is missed, whether or not its
+        // client registered with this server
+        for prefix in &prefixes {
+            spawn_response_handler(&nats_client, &subjects, prefix, self.queue_group.as_deref(), &responses, &subscriptions).await?;
+        }
+        
+        let connected_clients = Arc::new(RwLock::new(HashMap::new()));
+        let dangerous_patterns = self.dangerous_patterns
+            .unwrap_or_else(|| DEFAULT_DANGEROUS_PATTERNS.iter().map(|pattern| pattern.to_string()).collect());
+        // Shared by the console and the API, so `rs-nats approve` reaches either
+        let held: HeldCommands = Arc::new(Mutex::new(HashMap::new()));
+        if self.approval_timeout.is_some() {
+            spawn_approval_handler(&nats_client, &subjects, &prefixes, codec, held.clone(), &subscriptions).await?;
+        }
+        if self.api {
+            let gateway = Arc::new(ApiGateway {
+                nats: nats_client.clone(),
+                subjects: subjects.clone(),
+                codec,
+                jetstream: self.jetstream,
+                clients: connected_clients.clone(),
+                pending: pending_requests.clone(),
+                history: history.clone(),
+                approval_timeout: self.approval_timeout,
+                dangerous_patterns: dangerous_patterns.clone(),
+                held: held.clone(),
+            });
+            for prefix in &prefixes {
+                spawn_api_handler(gateway.clone(), prefix, self.queue_group.as_deref(), &subscriptions).await?;
+            }
+        }
+        
+        Ok(Server {
+            nats_client,
+            subjects,
+            subject_prefixes: prefixes,
+            connected_clients,
+            pending_requests,
+            subscriptions,
+            codec,
+            heartbeat_interval,
+            stale_after: heartbeat_interval * stale_heartbeats,
+            registry_path: self.registry_path,
+            queue_group: self.queue_group,
+            metrics_addr: self.metrics_addr,
+            jetstream: self.jetstream,
+            history,
+            output: self.output,
+            approval_timeout: self.approval_timeout,
+            dangerous_patterns,
+            held,
+        })
+    }
+}
+
+/// The prefix `client_id` registered under, if it is registered
+async fn client_prefix(clients: &RwLock<HashMap<String, ClientEntry>>, client_id: &str) -> Option<String> {
+    clients.read().await.get(client_id).map(|entry| entry.subject_prefix.clone())
+}
+
+/// Look up the client a console command is aimed at, given either its client id or
+/// `host:<hostname>`, and return its id and prefix. A hostname matches clients whose
+/// hostname is the same or starts with it followed by a domain, ignoring case, and
+/// must match exactly one.
+async fn resolve_client(clients: &RwLock<HashMap<String, ClientEntry>>, target: &str) -> Result<(String, String), String> {
+    let clients = clients.read().await;
+    let Some(hostname) = target.strip_prefix("host:") else {
+        return match clients.get(target) {
+            Some(entry) => Ok((target.to_string(), entry.subject_prefix.clone())),
+            None => Err(format!("Client {} not found", target)),
+        };
+    };
+    
+    let mut matching: Vec<_> = clients.iter()
+        .filter(|(_, entry)| hostname_matches(&entry.info.hostname, hostname))
+        .collect();
+    matching.sort_by_key(|(id, _)| *id);
+    match matching.as_slice() {
+        [] => Err(format!("No client on host {}", hostname)),
+        [(id, entry)] => Ok((id.to_string(), entry.subject_prefix.clone())),
+        several => Err(format!(
+            "Host {} matches several clients, use a client id: {}",
+            hostname, several.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>().join(", ")
+        )),
+    }
+}
+
+/// Look up each client in a comma-separated list like `web-01,web-02,host:db`, as
+/// `resolve_client` does. Returns the clients found, each once and in list order, and
+/// why the others weren't.
+async fn resolve_clients(clients: &RwLock<HashMap<String, ClientEntry>>, targets: &str) -> (Vec<(String, String)>, Vec<String>) {
+    let mut found: Vec<(String, String)> = Vec::new();
+    let mut errors = Vec::new();
+    for target in targets.split(',').filter(|target| !target.is_empty()) {
+        match resolve_client(clients, target).await {
+            Ok(client) if !found.contains(&client) => found.push(client),
+            Ok(_) => {},
+            Err(e) => errors.push(e),
+        }
+    }
+    (found, errors)
+}
+
+/// Whether `hostname` is `name`, or `name` followed by a domain such as `.example.com`
+fn hostname_matches(hostname: &str, name: &str) -> bool {
+    match hostname.get(..name.len()) {
+        Some(start) if start.eq_ignore_ascii_case(name) => {
+            matches!(hostname.as_bytes().get(name.len()), None | Some(b'.'))
+        },
+        _ => false,
+    }
+}
+
+/// Subscribe to `subject`, as a member of `queue_group` if given so each message
+/// goes to only one server in the group
+async fn subscribe(
+    nats: &Client,
+    subject: String,
+    queue_group: Option<&str>,
+) -> Result<async_nats::Subscriber, async_nats::SubscribeError> {
+    match queue_group {
+        Some(group) => nats.queue_subscribe(subject, group.to_string()).await,
+        None => nats.subscribe(subject).await,
+    }
+}
+
+/// Results arriving on response subjects that can wait to be handed out before the
+/// subscriptions delivering them are held back. Each result is small and handled
+/// quickly, so this only fills when the console can't print as fast as a client
+/// sends; NATS then buffers on its side, and drops messages for this server if that
+/// goes on (a slow consumer).
+const RESPONSE_QUEUE_CAPACITY: usize = 256;
+
+/// Results from response subjects, with the id of the client they came from
+type ResponseSender = mpsc::Sender<(String, async_nats::Message)>;
+
+/// Subscribe to the response subjects of every client under `prefix` and pass what
+/// arrives to the dispatcher, along with the client id taken from the subject, waiting
+/// while its queue is full
+async fn spawn_response_handler(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    queue_group: Option<&str>,
+    responses: &ResponseSender,
+    subscriptions: &Subscriptions,
+) -> Result<(), async_nats::SubscribeError> {
+    let response_subject = subjects.responses(prefix);
+    info!("Subscribing to responses on {}", response_subject);
+    let tracked = subscriptions.add(&response_subject, "responses", queue_group);
+    let mut msg_stream = subscribe(nats, response_subject, queue_group).await?;
+    
+    let subjects = subjects.clone();
+    let prefix = prefix.to_string();
+    let responses = responses.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = msg_stream.next().await {
+            tracked.received();
+            let client_id = match subjects.response_client_id(&prefix, &msg.subject) {
+                Some(client_id) if validate_client_id(client_id).is_ok() => client_id.to_string(),
+                _ => {
+                    warn!("Ignoring response on {}, which names no valid client", msg.subject);
+                    continue;
+                },
+            };
+            let response = match responses.try_send((client_id.clone(), msg)) {
+                Ok(()) => continue,
+                Err(mpsc::error::TrySendError::Full(response)) => response,
+                Err(mpsc::error::TrySendError::Closed(_)) => break,
+            };
+            debug!("Response queue full, holding back results from {}", client_id);
+            if responses.send(response).await.is_err() {
+                break;
+            }
+        }
+    });
+    
+    Ok(())
+}
+
+/// What the command API needs to relay requests to clients
+struct ApiGateway {
+    nats: Client,
+    subjects: Arc<SubjectLayout>,
+    codec: Codec,
+    jetstream: bool,
+    clients: Arc<RwLock<HashMap<String, ClientEntry>>>,
+    pending: PendingRequests,
+    history: Arc<CommandHistory>,
+    /// How long commands that need approval are held, if the server requires it
+    approval_timeout: Option<Duration>,
+    dangerous_patterns: Vec<String>,
+    held: HeldCommands,
+}
+
+/// Answer `ApiRequest`s on the API subject, each in its own task so a slow
+/// command doesn't hold up the rest
+async fn spawn_api_handler(
+    gateway: Arc<ApiGateway>,
+    prefix: &str,
+    queue_group: Option<&str>,
+    subscriptions: &Subscriptions,
+) -> Result<(), async_nats::SubscribeError> {
+    let api_subject = gateway.subjects.api(prefix);
+    info!("Accepting commands from the API on {}", api_subject);
+    let tracked = subscriptions.add(&api_subject, "API requests", queue_group);
+    let mut requests = subscribe(&gateway.nats, api_subject, queue_group).await?;
+    
+    let prefix = prefix.to_string();
+    tokio::spawn(async move {
+        while let Some(msg) = requests.next().await {
+            tracked.received();
+            let Some(reply) = msg.reply.clone() else {
+                warn!("Ignoring API request without a reply subject");
+                continue;
+            };
+            let gateway = gateway.clone();
+            let prefix = prefix.clone();
+            tokio::spawn(async move {
+                let response = match gateway.codec.decode::<ApiRequest>(&msg.payload) {
+                    Ok(request) => gateway.relay(&prefix, request).await,
+                    Err(e) => {
+                        warn!("Refusing API request: {}", e);
+                        ApiResponse { result: None, error: Some(format!("Invalid API request: {}", e)) }
+                    },
+                };
+                match gateway.codec.encode(&response) {
+                    Ok(payload) => {
+                        if let Err(e) = gateway.nats.publish(reply, payload.into()).await {
+                            warn!("Failed to answer API request: {}", e);
+                        }
+                    },
+                    Err(e) => warn!("Failed to serialize API response: {}", e),
+                }
+            });
+        }
+    });
+    
+    Ok(())
+}
+
+impl ApiGateway {
+    /// Send the requested command to a client under `prefix` and wait for its result.
+    /// Commands that need approval are held like the console's, and answered once
+    /// `rs-nats approve` lets them out, or with an error when denied or not approved in time.
+    async fn relay(self: &Arc<Self>, prefix: &str, request: ApiRequest) -> ApiResponse {
+        if let Err(e) = validate_client_id(&request.client_id) {
+            return ApiResponse { result: None, error: Some(e.to_string()) };
+        }
+        let timeout = Duration::from_secs(request.timeout_secs.unwrap_or(DEFAULT_RESPONSE_TIMEOUT_SECS));
+        let Some(approval) = self.approval_timeout.filter(|_| needs_approval(&request.command, &self.dangerous_patterns)) else {
+            info!("API request: {} on {}", request.command, request.client_id);
+            return self.send(prefix, &request.client_id, request.command, timeout).await;
+        };
+        // Each line typed would need approving, and nobody is there to type them
+        if matches!(request.command, Command::StartShell { .. }) {
+            warn!("Refusing API request for a shell on {}, it needs approval", request.client_id);
+            return ApiResponse { result: None, error: Some("shell sessions aren't offered when approval is required".to_string()) };
+        }
+        
+        info!("API request: {} on {}, held for approval", request.command, request.client_id);
+        let (result_tx, result_rx) = oneshot::channel();
+        let gateway = self.clone();
+        let prefix = prefix.to_string();
+        let client_id = request.client_id.clone();
+        let description = format!("{} (from the API)", request.command);
+        let send = async move {
+            let response = gateway.send(&prefix, &client_id, request.command, timeout).await;
+            let _ = result_tx.send(response);
+        };
+        hold_for_approval(&self.held, uuid::Uuid::new_v4().to_string(), &request.client_id, description, approval, send);
+        // The sender is dropped unsent when the command is denied or times out
+        result_rx.await.unwrap_or_else(|_| ApiResponse {
+            result: None,
+            error: Some("command needs approval with `rs-nats approve`, and was denied or not approved in time".to_string()),
+        })
+    }
+    
+    async fn send(&self, prefix: &str, client_id: &str, command: Command, timeout: Duration) -> ApiResponse {
+        match send_and_wait(&self.nats, &self.subjects, prefix, self.codec, self.jetstream, &self.clients, &self.pending, &self.history,
+            client_id, command, timeout).await
+        {
+            Ok(result) => ApiResponse { result: Some(result), error: None },
+            Err(e) => ApiResponse { result: None, error: Some(e.to_string()) },
+        }
+    }
+}
+
+/// Answer `ApprovalRequest`s for commands this server holds, from its console or API. Requests for
+/// commands held by another server are left to that one to answer.
+async fn spawn_approval_handler(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefixes: &[String],
+    codec: Codec,
+    held: HeldCommands,
+    subscriptions: &Subscriptions,
+) -> Result<(), async_nats::SubscribeError> {
+    for prefix in prefixes {
+        let approval_subject = subjects.approval(prefix);
+        info!("Accepting approvals on {}", approval_subject);
+        // Every server, since each holds its own commands
+        let tracked = subscriptions.add(&approval_subject, "approvals", None);
+        let mut requests = subscribe(nats, approval_subject, None).await?;
+        let nats = nats.clone();
+        let held = held.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = requests.next().await {
+                tracked.received();
+                let request = match codec.decode::<ApprovalRequest>(&msg.payload) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!("Ignoring approval request: {}", e);
+                        continue;
+                    }
+                };
+                if !held.lock().unwrap().contains_key(&request.request_id) {
+                    debug!("Ignoring approval of {}, not held here", request.request_id);
+                    continue;
+                }
+                
+                let response = match decide(&held, &request.request_id, request.approve) {
+                    Ok((target, command)) => {
+                        let decision = if request.approve { "Approved" } else { "Denied" };
+                        console!("{} {} on {} by {}", decision, command, target, request.approver);
+                        info!("{} {} on {} ({}) by {}", decision, command, target, request.request_id, request.approver);
+                        ApprovalResponse { description: Some(format!("{} on {}", command, target)), error: None }
+                    },
+                    Err(e) => ApprovalResponse { description: None, error: Some(e) },
+                };
+                let Some(reply) = msg.reply else {
+                    continue;
+                };
+                match codec.encode(&response) {
+                    Ok(payload) => {
+                        if let Err(e) = nats.publish(reply, payload.into()).await {
+                            warn!("Failed to answer approval request: {}", e);
+                        }
+                    },
+                    Err(e) => warn!("Failed to serialize approval response: {}", e),
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Approve or deny the command held as `request_id` by a server under `prefix`,
+/// returning what it was. The four-eyes check relies on NATS permissions: only
+/// approvers' credentials, and not the server's, should be allowed to publish on the
+/// approval subject.
+pub async fn send_decision(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    codec: Codec,
+    request: &ApprovalRequest,
+    timeout: Duration,
+) -> Result<String> {
+    let payload = codec.encode(request)?;
+    let reply = tokio::time::timeout(timeout, nats.request(subjects.approval(prefix), payload.into())).await
+        .map_err(|_| anyhow::anyhow!("No server holds a command as {} (no answer within {:?})", request.request_id, timeout))?
+        .map_err(|e| anyhow::anyhow!("Approval request failed: {}", e))?;
+    let response: ApprovalResponse = codec.decode(&reply.payload)?;
+    match (response.description, response.error) {
+        (_, Some(error)) => Err(anyhow::anyhow!(error)),
+        (Some(description), None) => Ok(description),
+        (None, None) => Err(anyhow::anyhow!("Empty reply from the server")),
+    }
+}
+
+/// Hand out results from every response subject in the order they arrived: to the
+/// command waiting for them, or printed if nothing is
+fn spawn_response_dispatcher(
+    mut received: mpsc::Receiver<(String, async_nats::Message)>,
+    codec: Codec,
+    output: OutputFormat,
+    pending: PendingRequests,
+    history: Arc<CommandHistory>,
+) {
+    tokio::spawn(async move {
+        while let Some((client_id, msg)) = received.recv().await {
+            info!("Response received from {} ({} bytes)", client_id, msg.payload.len());
+            SERVER_METRICS.response_received();
+            
+            match codec.decode::<CommandResult>(&msg.payload) {
+                Ok(result) => {
+                    history.result_received(&client_id, &result);
+                    if let Some(unclaimed) = pending.complete(result) {
+                        print_result(output, &client_id, &unclaimed);
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to parse response: {}", e);
+                    console!("\nReceived unparseable response from {}", client_id);
+                    console!("Raw payload: {}", String::from_utf8_lossy(&msg.payload));
+                }
+            }
+        }
+    });
+}
+
+/// Announce a client coming or going on its events subject
+async fn publish_event(nats: &Client, subjects: &SubjectLayout, prefix: &str, event: &Event) {
+    let subject = subjects.events(prefix);
+    match serde_json::to_vec(event) {
+        Ok(payload) => {
+            if let Err(e) = nats.publish(subject, payload.into()).await {
+                warn!("Failed to publish {:?}: {}", event, e);
+            }
+        },
+        Err(e) => warn!("Failed to serialize {:?}: {}", event, e),
+    }
+}
+
+fn now_rfc3339() -> String {
+    humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+}
+
+/// Read a registry written by `save_registry`. Restored clients are marked stale
+/// and get one stale period from now to send a heartbeat before they're evicted.
+async fn load_registry(path: &Path) -> Result<HashMap<String, ClientEntry>> {
+    let contents = match tokio::fs::read(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    
+    let mut entries: HashMap<String, ClientEntry> = from_slice(&contents)
+        .map_err(|e| RsNatsError::SerializationError(format!(
+            "Invalid client registry {}: {}", path.display(), e
+        )))?;
+    let now = SystemTime::now();
+    for entry in entries.values_mut() {
+        entry.stale = true;
+        entry.last_seen = now;
+    }
+    Ok(entries)
+}
+
+/// Write the registry as JSON, via a temporary file so a crash can't leave it half written
+async fn save_registry(path: &Path, clients: &RwLock<HashMap<String, ClientEntry>>) -> Result<()> {
+    let contents = serde_json::to_vec_pretty(&*clients.read().await)?;
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, contents).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// Publish a command to a client, refusing commands the client has said it doesn't handle
+#[allow(clippy::too_many_arguments)]
+async fn publish_command(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    codec: Codec,
+    jetstream: bool,
+    clients: &RwLock<HashMap<String, ClientEntry>>,
+    history: &CommandHistory,
+    client_id: &str,
+    request: &CommandRequest,
+) -> Result<()> {
+    let (command_subject, payload) = prepare_command(nats, subjects, prefix, codec, clients, history, client_id, request).await?;
+    if jetstream {
+        crate::jetstream::publish_command(nats, command_subject, payload).await?;
+    } else {
+        nats.publish(command_subject, payload.into()).await?;
+    }
+    SERVER_METRICS.command_sent();
+    Ok(())
+}
+
+/// Check a command can go to a client and encode it for its command subject,
+/// recording it as dispatched. Returns the subject and payload to send.
+#[allow(clippy::too_many_arguments)]
+async fn prepare_command(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    codec: Codec,
+    clients: &RwLock<HashMap<String, ClientEntry>>,
+    history: &CommandHistory,
+    client_id: &str,
+    request: &CommandRequest,
+) -> Result<(String, Vec<u8>)> {
+    if let Some(entry) = clients.read().await.get(client_id) {
+        if !entry.info.supports(&request.command) {
+            return Err(RsNatsError::Unsupported {
+                client_id: client_id.to_string(),
+                command: request.command.name().to_string(),
+            }.into());
+        }
+    }
+    
+    let command_subject = subjects.command(prefix, client_id);
+    let payload = codec.encode(request)?;
+    check_payload_size(nats, &command_subject, payload.len())?;
+    history.dispatched(client_id, request)?;
+    Ok((command_subject, payload))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_and_wait(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    codec: Codec,
+    jetstream: bool,
+    clients: &RwLock<HashMap<String, ClientEntry>>,
+    pending: &PendingRequests,
+    history: &CommandHistory,
+    client_id: &str,
+    cmd: Command,
+    timeout: Duration,
+) -> Result<CommandResult> {
+    send_request_and_wait(nats, subjects, prefix, codec, jetstream, clients, pending, history, client_id, CommandRequest::new(cmd), timeout).await
+}
+
+/// `send_and_wait` for a request whose id is already known
+#[allow(clippy::too_many_arguments)]
+async fn send_request_and_wait(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    codec: Codec,
+    jetstream: bool,
+    clients: &RwLock<HashMap<String, ClientEntry>>,
+    pending: &PendingRequests,
+    history: &CommandHistory,
+    client_id: &str,
+    request: CommandRequest,
+    timeout: Duration,
+) -> Result<CommandResult> {
+    let command = request.command.clone();
+    
+    // Subscribe before sending so the acknowledgement can't be missed
+    let ack_subject = subjects.ack(prefix, &request.request_id);
+    let mut acks = nats.subscribe(ack_subject).await?;
+    
+    // Clients from before request/reply, and every client in JetStream mode, which has
+    // no reply subject to answer on, send the result to the response subject instead
+    let mut rx = pending.register(&request.request_id);
+    
+    let reply = if jetstream {
+        if let Err(e) = publish_command(nats, subjects, prefix, codec, jetstream, clients, history, client_id, &request).await {
+            pending.cancel(&request.request_id);
+            return Err(e);
+        }
+        None
+    } else {
+        let (command_subject, payload) = match prepare_command(nats, subjects, prefix, codec, clients, history, client_id, &request).await {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                pending.cancel(&request.request_id);
+                return Err(e);
+            }
+        };
+        SERVER_METRICS.command_sent();
+        // Timed out below along with a result on the response subject
+        Some(nats.send_request(command_subject, Request::new().payload(payload.into()).timeout(None)))
+    };
+    let sent_time = SystemTime::now();
+    
+    let outcome = async {
+        let sent_at = Instant::now();
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        let reply = async {
+            match reply {
+                Some(reply) => reply.await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(reply);
+        let mut accepted = false;
+        loop {
+            tokio::select! {
+                reply = &mut reply => {
+                    let msg = match reply {
+                        Ok(msg) => msg,
+                        Err(e) if e.kind() == RequestErrorKind::NoResponders => {
+                            pending.cancel(&request.request_id);
+                            return Err(RsNatsError::CommandError(format!(
+                                "{} is not subscribed to commands", client_id
+                            )).into());
+                        },
+                        Err(e) => {
+                            pending.cancel(&request.request_id);
+                            return Err(e.into());
+                        },
+                    };
+                    pending.cancel(&request.request_id);
+                    SERVER_METRICS.response_received();
+                    let result = codec.decode::<CommandResult>(&msg.payload)?;
+                    history.result_received(client_id, &result);
+                    SERVER_METRICS.observe_latency(sent_at.elapsed());
+                    return Ok(result);
+                },
+                result = &mut rx => {
+                    return match result {
+                        Ok(result) => {
+                            SERVER_METRICS.observe_latency(sent_at.elapsed());
+                            Ok(result)
+                        },
+                        Err(_) => Err(RsNatsError::CommandError(format!(
+                            "Response channel for {} closed", request.request_id
+                        )).into()),
+                    };
+                },
+                Some(msg) = acks.next(), if !accepted => {
+                    match codec.decode::<CommandAck>(&msg.payload) {
+                        Ok(ack) => {
+                            debug!("{} accepted command {} after {:?}", ack.client_id, ack.request_id, sent_at.elapsed());
+                            accepted = true;
+                        },
+                        Err(e) => warn!("Ignoring unparseable acknowledgement for {}: {}", request.request_id, e),
                     }
+                },
+                _ = &mut deadline => {
+                    pending.cancel(&request.request_id);
+                    // Clients from before acknowledgements existed never send one
+                    return Err(if accepted {
+                        RsNatsError::StillRunning {
+                            client_id: client_id.to_string(),
+                            request_id: request.request_id.clone(),
+                        }
+                    } else {
+                        RsNatsError::Timeout(client_id.to_string())
+                    }.into());
+                },
+            }
+        }
+    }.await;
+    history.record(client_id, sent_time, command, &outcome);
+    outcome
+}
+
+/// Type `data` into the shell session reading `subject`
+async fn send_shell_input(nats: &Client, subject: &str, codec: Codec, data: Vec<u8>) -> Result<()> {
+    let payload = codec.encode(&ShellInput { data })?;
+    nats.publish(subject.to_string(), payload.into()).await?;
+    Ok(())
+}
+
+/// Send a `Ping` and return how long its result took to come back
+#[allow(clippy::too_many_arguments)]
+async fn ping(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    codec: Codec,
+    jetstream: bool,
+    clients: &RwLock<HashMap<String, ClientEntry>>,
+    pending: &PendingRequests,
+    history: &CommandHistory,
+    client_id: &str,
+    timeout: Duration,
+) -> Result<Duration> {
+    let sent_at = Instant::now();
+    send_and_wait(nats, subjects, prefix, codec, jetstream, clients, pending, history, client_id, Command::Ping, timeout).await?;
+    Ok(sent_at.elapsed())
+}
+
+/// Send a streaming command and feed its output chunks to `on_chunk` in sequence
+/// order. Chunks that arrive early are held until the gap before them is filled;
+/// duplicates and chunks that are never filled in are reported. Gives up if no
+/// chunk arrives for `idle_timeout`.
+#[allow(clippy::too_many_arguments)]
+async fn stream_command<F>(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    codec: Codec,
+    jetstream: bool,
+    clients: &RwLock<HashMap<String, ClientEntry>>,
+    pending: &PendingRequests,
+    history: &CommandHistory,
+    client_id: &str,
+    request: CommandRequest,
+    idle_timeout: Duration,
+    mut on_chunk: F,
+) -> Result<CommandResult>
+where
+    F: FnMut(&OutputChunk),
+{
+    let command = request.command.clone();
+    let request_id = request.request_id.clone();
+    
+    // Subscribe before sending so the first chunks can't be missed
+    let stream_subject = subjects.stream(prefix, &request_id);
+    let mut chunks = nats.subscribe(stream_subject).await?;
+    
+    // The client also sends a summary result; claim it so it isn't printed as unsolicited
+    let rx = pending.register(&request_id);
+    
+    if let Err(e) = publish_command(nats, subjects, prefix, codec, jetstream, clients, history, client_id, &request).await {
+        pending.cancel(&request_id);
+        return Err(e);
+    }
+    
+    let sent_time = SystemTime::now();
+    
+    let outcome = async {
+        let mut next_seq = 0;
+        let mut held: BTreeMap<u64, OutputChunk> = BTreeMap::new();
+        let mut done: Option<OutputChunk> = None;
+    
+        while done.as_ref().is_none_or(|d| next_seq < d.seq) {
+            let msg = match tokio::time::timeout(idle_timeout, chunks.next()).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break,
+                Err(_) => {
+                    pending.cancel(&request_id);
+                    return Err(RsNatsError::CommandError(format!(
+                        "No output from {} for {}s", client_id, idle_timeout.as_secs()
+                    )).into());
                 }
+            };
+            let chunk = match codec.decode::<OutputChunk>(&msg.payload) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    error!("Failed to parse output chunk: {}", e);
+                    continue;
+                }
+            };
+        
+            if chunk.done {
+                done = Some(chunk);
+                continue;
             }
-        });
+            if chunk.seq < next_seq || held.contains_key(&chunk.seq) {
+                warn!("Dropping duplicate output chunk {} for {}", chunk.seq, request_id);
+                continue;
+            }
+            if chunk.seq > next_seq {
+                warn!("Output chunk {} for {} arrived before chunk {}", chunk.seq, request_id, next_seq);
+            }
+            held.insert(chunk.seq, chunk);
+            while let Some(chunk) = held.remove(&next_seq) {
+                on_chunk(&chunk);
+                next_seq += 1;
+            }
+        }
+        let _ = chunks.unsubscribe().await;
+    
+        // Deliver whatever arrived past a gap that was never filled
+        if !held.is_empty() {
+            warn!("Output for {} is missing chunks from {}", request_id, next_seq);
+            for chunk in held.values() {
+                on_chunk(chunk);
+            }
+        }
+    
+        let Some(done) = done else {
+            pending.cancel(&request_id);
+            return Err(RsNatsError::CommandError(format!(
+                "Output stream for {} ended without completing", request_id
+            )).into());
+        };
+    
+        let response_timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+        match tokio::time::timeout(response_timeout, rx).await {
+            Ok(Ok(result)) => Ok(result),
+            _ => {
+                pending.cancel(&request_id);
+                let mut result = CommandResult::new(
+                    done.success,
+                    String::new(),
+                    done.error,
+                    CommandType::Shell,
+                );
+                result.request_id = Some(request_id);
+                result.exit_code = done.exit_code;
+                Ok(result)
+            }
+        }
+    }.await;
+    history.record(client_id, sent_time, command, &outcome);
+    outcome
+}
+
+/// Whether `command` is held for approval: reboot and power off, writing
+/// files, killing processes, restarting the client, and shell commands matching one of
+/// `patterns`
+fn needs_approval(command: &Command, patterns: &[String]) -> bool {
+    match command {
+        // Anything can be run in a shell session, so it is as dangerous as the worst command,
+        // and an uploaded file can replace a binary or a config the client's host trusts
+        Command::RebootMachine { .. } | Command::PowerOff | Command::StartShell { .. } | Command::UploadFile { .. }
+            | Command::KillProcess { .. } | Command::RestartAgent => true,
+        Command::Execute { command, .. } | Command::ScheduleExecute { command, .. } => is_dangerous(command, patterns),
+        _ => false,
+    }
+}
+
+/// Whether shell `command` matches one of the dangerous `patterns`
+fn is_dangerous(command: &str, patterns: &[String]) -> bool {
+    let command = command.trim();
+    patterns.iter().any(|pattern| wildcard_match(pattern, command))
+}
+
+/// Hold `command` for `target` under `request_id` until it is approved, then run
+/// `send`. It is dropped unsent when denied or once `timeout` passes.
+fn hold_for_approval<F>(held: &HeldCommands, request_id: String, target: &str, command: String, timeout: Duration, send: F)
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    let (decision_tx, decision_rx) = oneshot::channel();
+    console!("{} on {} needs approval, held as {} for {}; another operator approves it with `rs-nats approve {}`",
+        command, target, request_id, humantime::format_duration(timeout), request_id);
+    info!("Holding {} on {} for approval as {}", command, target, request_id);
+    let now = Instant::now();
+    held.lock().unwrap().insert(request_id.clone(), HeldCommand {
+        target: target.to_string(),
+        command,
+        held_at: now,
+        expires_at: now + timeout,
+        decision: decision_tx,
+    });
+    
+    let held = held.clone();
+    tokio::spawn(async move {
+        let decision = tokio::time::timeout(timeout, decision_rx).await;
+        // Already gone if it was approved or denied
+        held.lock().unwrap().remove(&request_id);
+        match decision {
+            Ok(Ok(true)) => send.await,
+            Ok(_) => {},
+            Err(_) => {
+                console!("No approval for {} within {}, not sent", request_id, humantime::format_duration(timeout));
+                warn!("Approval for {} timed out, command denied", request_id);
+            },
+        }
+    });
+}
+
+/// Approve or deny the held command `request_id`, returning what it was and where it goes
+fn decide(held: &HeldCommands, request_id: &str, approve: bool) -> Result<(String, String), String> {
+    let Some(entry) = held.lock().unwrap().remove(request_id) else {
+        return Err(format!("No command held as {}", request_id));
+    };
+    // Fails if the approval timed out just now
+    if entry.decision.send(approve).is_err() {
+        return Err(format!("Approval for {} has timed out", request_id));
+    }
+    Ok((entry.target, entry.command))
+}
+
+/// Send a command once it has been approved and print its result. Streaming shell
+/// commands are streamed, giving up after `timeout` without output.
+#[allow(clippy::too_many_arguments)]
+async fn send_approved(
+    nats: Client,
+    subjects: Arc<SubjectLayout>,
+    prefix: String,
+    codec: Codec,
+    jetstream: bool,
+    clients: Arc<RwLock<HashMap<String, ClientEntry>>>,
+    pending: PendingRequests,
+    history: Arc<CommandHistory>,
+    output: OutputFormat,
+    client_id: String,
+    request: CommandRequest,
+    timeout: Duration,
+) {
+    console!("Sending {} to {}", request.command, client_id);
+    let outcome = if let Command::Execute { stream: true, .. } = request.command {
+        stream_command(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, &client_id, request, timeout, print_chunk).await
+    } else {
+        send_request_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, &client_id, request, timeout).await
+    };
+    match outcome {
+        Ok(result) => print_result(output, &client_id, &result),
+        Err(e) => console!("{}", e),
+    }
+}
+
+/// Send `cmd` to every client in `targets`, given as client id and prefix, and print
+/// each result
+#[allow(clippy::too_many_arguments)]
+async fn broadcast(
+    nats: Client,
+    subjects: Arc<SubjectLayout>,
+    codec: Codec,
+    jetstream: bool,
+    clients: Arc<RwLock<HashMap<String, ClientEntry>>>,
+    pending: PendingRequests,
+    history: Arc<CommandHistory>,
+    output: OutputFormat,
+    targets: Vec<(String, String)>,
+    cmd: Command,
+    timeout: Duration,
+) {
+    let sends = targets.iter().map(|(client_id, prefix)| {
+        send_and_wait(&nats, &subjects, prefix, codec, jetstream, &clients, &pending, &history, client_id, cmd.clone(), timeout)
+    });
+    for ((client_id, _), outcome) in targets.iter().zip(join_all(sends).await) {
+        match outcome {
+            Ok(result) => print_result(output, client_id, &result),
+            Err(e) => console!("{}", e),
+        }
+    }
+}
+
+/// List the commands held for approval, oldest first
+fn print_held(held: &HeldCommands) {
+    let held = held.lock().unwrap();
+    if held.is_empty() {
+        console!("No commands waiting for approval");
+        return;
+    }
+    let mut entries: Vec<_> = held.iter().collect();
+    entries.sort_by_key(|(_, entry)| entry.held_at);
+    console!("Waiting for approval:");
+    let now = Instant::now();
+    for (request_id, entry) in entries {
+        let waiting = Duration::from_secs(now.duration_since(entry.held_at).as_secs());
+        let left = Duration::from_secs(entry.expires_at.saturating_duration_since(now).as_secs());
+        console!("  {} - {} on {} (held {}, {} left)", request_id, entry.command, entry.target,
+            humantime::format_duration(waiting), humantime::format_duration(left));
+    }
+}
+
+/// Write a streamed chunk straight to the console, stderr output to stderr
+fn print_chunk(chunk: &OutputChunk) {
+    use std::io::Write;
+    
+    let written = if chunk.stderr {
+        let mut stderr = std::io::stderr();
+        stderr.write_all(&chunk.data).and_then(|_| stderr.flush())
+    } else {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&chunk.data).and_then(|_| stdout.flush())
+    };
+    if let Err(e) = written {
+        error!("Failed to write output chunk {}: {}", chunk.seq, e);
+    }
+    transcript::write(&String::from_utf8_lossy(&chunk.data));
+}
+
+/// A `SystemInfo` field the console `list` command can filter on
+#[derive(Debug, Clone, Copy)]
+enum ClientField {
+    Os,
+    Host,
+    User,
+}
+
+/// Which clients the console `list` command shows
+#[derive(Default)]
+struct ClientFilter {
+    tags: Vec<(String, String)>,
+    /// Patterns with `*` wildcards, matched ignoring case
+    fields: Vec<(ClientField, String)>,
+}
+
+impl ClientFilter {
+    fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.fields.is_empty()
+    }
+    
+    fn matches(&self, info: &SystemInfo) -> bool {
+        info.has_tags(&self.tags) && self.fields.iter().all(|(field, pattern)| {
+            let value = match field {
+                ClientField::Os => &info.os_type,
+                ClientField::Host => &info.hostname,
+                ClientField::User => &info.username,
+            };
+            wildcard_match(pattern, value)
+        })
+    }
+}
+
+/// Parse the filters of the console `list` command: `os=`, `host=` and `user=`
+/// patterns, and `--tag key=value` pairs
+fn parse_list_filter(args: &[&str]) -> Result<ClientFilter, String> {
+    let mut filter = ClientFilter::default();
+    let mut rest = args;
+    while let Some(arg) = rest.first() {
+        if *arg == "--tag" {
+            let value = rest.get(1).ok_or("Missing value for --tag")?;
+            filter.tags.push(parse_tag(value)?);
+            rest = &rest[2..];
+            continue;
+        }
+        let (field, pattern) = match arg.split_once('=') {
+            Some(("os", pattern)) => (ClientField::Os, pattern),
+            Some(("host", pattern)) => (ClientField::Host, pattern),
+            Some(("user", pattern)) => (ClientField::User, pattern),
+            Some((name, _)) => return Err(format!("Unknown filter {}, expected os, host or user", name)),
+            None => return Err(format!("Unknown option {}", arg)),
+        };
+        filter.fields.push((field, pattern.to_string()));
+        rest = &rest[1..];
+    }
+    Ok(filter)
+}
+
+/// The commands in a script file: one per line, skipping blank lines and `#` comments
+fn script_commands(contents: &str) -> Vec<String> {
+    contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Run shell `commands` on a client one at a time, each after the previous one's
+/// result is in, and print a summary. Stops at the first failure unless
+/// `options.continue_on_error` is set.
+#[allow(clippy::too_many_arguments)]
+async fn run_script(
+    nats: Client,
+    subjects: Arc<SubjectLayout>,
+    prefix: String,
+    codec: Codec,
+    jetstream: bool,
+    clients: Arc<RwLock<HashMap<String, ClientEntry>>>,
+    pending: PendingRequests,
+    history: Arc<CommandHistory>,
+    output: OutputFormat,
+    client_id: String,
+    commands: Vec<String>,
+    options: ExecuteOptions,
+) {
+    let timeout = options.timeout.unwrap_or(Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS));
+    let env = if options.env.is_empty() { None } else { Some(options.env) };
+    let (mut succeeded, mut failed) = (0, 0);
+    
+    for (index, command) in commands.iter().enumerate() {
+        console!("[{}/{}] {}", index + 1, commands.len(), command);
+        let mut cmd = Command::Execute {
+            command: command.clone(),
+            cwd: options.cwd.clone(),
+            env: env.clone(),
+            stream: false,
+            stdin: None,
+            shell: options.shell,
+        };
+        if options.dry_run {
+            cmd = Command::DryRun { command: Box::new(cmd) };
+        }
         
-        // Wait for shutdown signal
-        let _ = shutdown_rx.recv().await;
-        info!("Server shutting down");
+        let success = match send_and_wait(&nats, &subjects, &prefix, codec, jetstream, &clients, &pending, &history, &client_id, cmd, timeout).await {
+            Ok(result) => {
+                print_result(output, &client_id, &result);
+                result.success
+            },
+            Err(e) => {
+                console!("{}", e);
+                false
+            },
+        };
+        if success {
+            succeeded += 1;
+        } else {
+            failed += 1;
+            if !options.continue_on_error {
+                break;
+            }
+        }
+    }
+    
+    let not_run = commands.len() - succeeded - failed;
+    let summary = format!("{} succeeded, {} failed, {} not run", succeeded, failed, not_run);
+    console!("Script on {} finished: {}", client_id, if failed == 0 { summary.green() } else { summary.red() });
+}
+
+/// Run a shell command on a client every `every`, clearing the screen before each
+/// result in text mode. Runs until the future is dropped; a run that takes longer than
+/// `every` delays the next one rather than overlapping it.
+#[allow(clippy::too_many_arguments)]
+async fn run_watch(
+    nats: &Client,
+    subjects: &SubjectLayout,
+    prefix: &str,
+    codec: Codec,
+    jetstream: bool,
+    clients: &RwLock<HashMap<String, ClientEntry>>,
+    pending: &PendingRequests,
+    history: &CommandHistory,
+    output: OutputFormat,
+    client_id: &str,
+    command: &str,
+    every: Duration,
+    options: ExecuteOptions,
+) {
+    let timeout = options.timeout.unwrap_or(Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS));
+    let env = if options.env.is_empty() { None } else { Some(options.env) };
+    let mut ticks = tokio::time::interval(every);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    
+    for iteration in 1u64.. {
+        ticks.tick().await;
+        let cmd = Command::Execute {
+            command: command.to_string(),
+            cwd: options.cwd.clone(),
+            env: env.clone(),
+            stream: false,
+            stdin: None,
+            shell: options.shell,
+        };
+        let result = send_and_wait(nats, subjects, prefix, codec, jetstream, clients, pending, history, client_id, cmd, timeout).await;
         
-        Ok(())
+        if output == OutputFormat::Text {
+            // Clear the screen and move to the top left, like watch(1)
+            print!("\x1b[2J\x1b[H");
+        }
+        console!("Every {} on {}: {}    #{} at {}, press Ctrl-C to stop",
+            humantime::format_duration(every), client_id, command, iteration, now_rfc3339());
+        match result {
+            Ok(result) => print_result(output, client_id, &result),
+            Err(e) => console!("{}", e),
+        }
+    }
+}
+
+/// Time between runs of `watch`: plain seconds like watch(1), or a duration like 500ms or 1m
+fn parse_interval(interval: &str) -> Result<Duration, String> {
+    let every = match interval.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => humantime::parse_duration(interval)
+            .map_err(|e| format!("Invalid interval {}, expected seconds or e.g. 500ms or 1m: {}", interval, e))?,
+    };
+    if every.is_zero() {
+        return Err("Interval must be longer than zero".to_string());
+    }
+    Ok(every)
+}
+
+/// Options accepted before the client id in the console `execute`, `script` and `watch` commands
+#[derive(Default)]
+struct ExecuteOptions {
+    cwd: Option<String>,
+    env: HashMap<String, String>,
+    stream: bool,
+    /// Only check the command on the client, see `Command::DryRun`
+    dry_run: bool,
+    timeout: Option<Duration>,
+    /// Local file whose contents are sent as the command's standard input
+    stdin_file: Option<String>,
+    /// Keep running a script's commands after one fails
+    continue_on_error: bool,
+    /// Instead of the client's default shell
+    shell: Option<Shell>,
+}
+
+/// Split leading `--option value` pairs off an `execute` line, returning the options
+/// and the remaining `<client_id> <command...>` words
+fn parse_execute_options<'a, 'b>(args: &'b [&'a str]) -> Result<(ExecuteOptions, &'b [&'a str]), String> {
+    let mut options = ExecuteOptions::default();
+    let mut rest = args;
+    
+    while let Some(flag) = rest.first().filter(|arg| arg.starts_with("--")) {
+        let switch = match *flag {
+            "--stream" => Some(&mut options.stream),
+            "--dry-run" => Some(&mut options.dry_run),
+            "--continue-on-error" => Some(&mut options.continue_on_error),
+            _ => None,
+        };
+        if let Some(switch) = switch {
+            *switch = true;
+            rest = &rest[1..];
+            continue;
+        }
+        let value = rest.get(1).ok_or_else(|| format!("Missing value for {}", flag))?;
+        match *flag {
+            "--cwd" => options.cwd = Some(value.to_string()),
+            "--stdin-file" => options.stdin_file = Some(value.to_string()),
+            "--shell" => options.shell = Some(value.parse::<Shell>()
+                .map_err(|_| format!("Expected sh, bash, pwsh, cmd or direct for --shell, got {}", value))?),
+            "--timeout" => {
+                let secs = value.parse::<u64>().ok().filter(|secs| *secs > 0)
+                    .ok_or_else(|| format!("Expected a positive number of seconds for --timeout, got {}", value))?;
+                options.timeout = Some(Duration::from_secs(secs));
+            },
+            "--env" => {
+                let (key, val) = value.split_once('=')
+                    .ok_or_else(|| format!("Expected KEY=VALUE for --env, got {}", value))?;
+                options.env.insert(key.to_string(), val.to_string());
+            },
+            other => return Err(format!("Unknown option {}", other)),
+        }
+        rest = &rest[2..];
+    }
+    
+    Ok((options, rest))
+}
+
+/// How the console prints clients, system information, and results
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Aligned, colored blocks for people
+    #[default]
+    Text,
+    /// One JSON value per line: an array of clients for `list`, and an object with
+    /// a `client_id` field for system information and results
+    Json,
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = RsNatsError;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(RsNatsError::ConfigError(format!(
+                "Unknown output format {}, expected text or json", other
+            ))),
+        }
+    }
+}
+
+/// A client as printed by `list` in JSON output
+#[derive(Serialize)]
+struct ClientSummary<'a> {
+    client_id: &'a str,
+    subject_prefix: &'a str,
+    last_seen: u64,
+    stale: bool,
+    #[serde(flatten)]
+    info: &'a SystemInfo,
+}
+
+impl<'a> ClientSummary<'a> {
+    fn new(client_id: &'a str, entry: &'a ClientEntry) -> Self {
+        ClientSummary {
+            client_id,
+            subject_prefix: &entry.subject_prefix,
+            last_seen: entry.last_seen.duration_since(std::time::UNIX_EPOCH).map_or(0, |since| since.as_secs()),
+            stale: entry.stale,
+            info: &entry.info,
+        }
+    }
+}
+
+/// Print `value` as a single line of JSON
+fn print_json(value: &impl Serialize) {
+    match serde_json::to_string(value) {
+        Ok(json) => console!("{}", json),
+        Err(e) => error!("Failed to serialize output: {}", e),
+    }
+}
+
+/// Width of the rules around a block of console output
+const RULE_WIDTH: usize = 60;
+
+/// Start a block of output about `client_id`, so results from many clients stay
+/// easy to tell apart
+fn print_header(client_id: &str, title: &str) {
+    let label = format!("--- {} | {} ", client_id, title);
+    let rule = "-".repeat(RULE_WIDTH.saturating_sub(label.len()));
+    console!("\n{}{}", label.bold(), rule.dimmed());
+}
+
+fn print_footer() {
+    console!("{}\n", "-".repeat(RULE_WIDTH).dimmed());
+}
+
+fn status_label(success: bool) -> ColoredString {
+    if success { "Success".green().bold() } else { "Failed".red().bold() }
+}
+
+/// Print an aligned `Name:  value` line
+fn print_field(name: &str, value: impl fmt::Display) {
+    console!("{}{}", format!("{:<10}", format!("{}:", name)).dimmed(), value);
+}
+
+/// How long a command took and, if the client reported it, when it started
+fn timing(result: &CommandResult) -> String {
+    match result.started_at {
+        Some(started_at) => format!("(started {}, took {}ms)", humantime::format_rfc3339_millis(started_at), result.duration_ms),
+        None => format!("(took {}ms)", result.duration_ms),
+    }
+}
+
+fn print_result(output: OutputFormat, client_id: &str, result: &CommandResult) {
+    if output == OutputFormat::Json {
+        #[derive(Serialize)]
+        struct ClientResult<'a> {
+            client_id: &'a str,
+            #[serde(flatten)]
+            result: &'a CommandResult,
+        }
+        print_json(&ClientResult { client_id, result });
+        return;
+    }
+    
+    print_header(client_id, "RESULT");
+    console!("{} {}", status_label(result.success), timing(result).dimmed());
+    console!("{}\n{}", "Output:".dimmed(), result.output);
+    if !result.stderr.is_empty() {
+        console!("{}\n{}", "Stderr:".yellow(), result.stderr);
+    }
+    if let Some(err) = &result.error {
+        console!("{} {}", "Error:".red().bold(), err);
+    }
+    print_footer();
+    
+    // Ensure output is displayed immediately
+    std::io::Write::flush(&mut std::io::stdout()).unwrap();
+}
+
+/// Print a client's system information; loopback interfaces are left out unless
+/// `all_interfaces`. JSON output always has all of them.
+fn print_system_info(output: OutputFormat, client_id: &str, info: &SystemInfo, all_interfaces: bool) {
+    if output == OutputFormat::Json {
+        #[derive(Serialize)]
+        struct ClientInfo<'a> {
+            client_id: &'a str,
+            #[serde(flatten)]
+            info: &'a SystemInfo,
+        }
+        print_json(&ClientInfo { client_id, info });
+        return;
+    }
+    
+    print_header(client_id, "SYSTEM INFO");
+    print_field("Hostname", &info.hostname);
+    print_field("User", &info.username);
+    print_field("OS", format!("{} {}", info.os_type, info.os_version.as_deref().unwrap_or("")));
+    print_field("Arch", &info.arch);
+    if !info.tags.is_empty() {
+        let mut tags: Vec<_> = info.tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        tags.sort();
+        print_field("Tags", tags.join(", "));
+    }
+    print_field("CPUs", info.cpu_count);
+    print_field("Memory", format_bytes(info.total_memory_bytes));
+    match info.uptime_secs {
+        Some(secs) => print_field("Uptime", format!("{}d {}h {}m", secs / 86400, secs % 86400 / 3600, secs % 3600 / 60)),
+        None => print_field("Uptime", "unknown"),
+    }
+    print_field("Protocol", info.protocol_version);
+    if !info.capabilities.is_empty() {
+        print_field("Commands", info.capabilities.join(", "));
+    }
+    let interfaces: Vec<_> = info.network.iter()
+        .filter(|interface| all_interfaces || !interface.loopback)
+        .collect();
+    if !interfaces.is_empty() {
+        console!("{}", "Network:".dimmed());
+        for interface in interfaces {
+            console!("  {} ({})", interface.name, interface.mac.as_deref().unwrap_or("no MAC"));
+            for ip in &interface.ipv4 {
+                console!("    {}", ip);
+            }
+            for ip in &interface.ipv6 {
+                console!("    {}", ip);
+            }
+        }
+    }
+    print_footer();
+}
+
+/// Clock skew beyond which `time` warns, enough to notice in logs and scheduled commands
+const CLOCK_SKEW_WARNING: Duration = Duration::from_secs(2);
+
+/// Print a client's clock and how far it is from ours. The client read its clock some
+/// time between `sent` and `received`, so it is compared with the middle of the two
+/// and the skew is only known to within half the round trip.
+fn print_time(output: OutputFormat, client_id: &str, time: &ClientTime, sent: SystemTime, received: SystemTime) {
+    let millis = |at: SystemTime| at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    let round_trip_ms = millis(received) - millis(sent);
+    let skew_ms = time.unix_millis as i64 - (millis(sent) + round_trip_ms / 2);
+    
+    if output == OutputFormat::Json {
+        #[derive(Serialize)]
+        struct ClientClock<'a> {
+            client_id: &'a str,
+            #[serde(flatten)]
+            time: &'a ClientTime,
+            /// How far the client's clock is ahead of the server's, negative if behind
+            skew_ms: i64,
+            round_trip_ms: i64,
+        }
+        print_json(&ClientClock { client_id, time, skew_ms, round_trip_ms });
+        return;
+    }
+    
+    print_header(client_id, "TIME");
+    print_field("UTC", &time.utc);
+    print_field("Local", &time.local);
+    let offset = time.utc_offset_secs.unsigned_abs();
+    print_field("Zone", format!("{} (UTC{}{:02}:{:02})", time.timezone.as_deref().unwrap_or("unknown"),
+        if time.utc_offset_secs < 0 { '-' } else { '+' }, offset / 3600, offset % 3600 / 60));
+    let skew = format!("{} {} this server (±{}ms)",
+        humantime::format_duration(Duration::from_millis(skew_ms.unsigned_abs())),
+        if skew_ms < 0 { "behind" } else { "ahead of" }, round_trip_ms / 2);
+    print_field("Skew", if skew_ms.unsigned_abs() > CLOCK_SKEW_WARNING.as_millis() as u64 {
+        format!("{}, check the clocks before scheduling commands", skew).red().bold()
+    } else {
+        skew.normal()
+    });
+    print_footer();
+}
+
+fn print_load(output: OutputFormat, client_id: &str, load: &LoadInfo) {
+    if output == OutputFormat::Json {
+        #[derive(Serialize)]
+        struct ClientLoad<'a> {
+            client_id: &'a str,
+            #[serde(flatten)]
+            load: &'a LoadInfo,
+        }
+        print_json(&ClientLoad { client_id, load });
+        return;
+    }
+    
+    print_header(client_id, "LOAD");
+    print_field("CPU", format!("{:.0}%", load.cpu_percent));
+    let cores: Vec<String> = load.core_percents.iter().map(|percent| format!("{:.0}", percent)).collect();
+    print_field("Cores", format!("{} ({}%)", cores.len(), cores.join(" ")));
+    if let Some([one, five, fifteen]) = load.load_average {
+        print_field("Load avg", format!("{:.2} {:.2} {:.2}", one, five, fifteen));
+    }
+    let used_percent = if load.total_memory_bytes == 0 {
+        0.0
+    } else {
+        load.used_memory_bytes as f64 * 100.0 / load.total_memory_bytes as f64
+    };
+    print_field("Memory", format!(
+        "{} of {} used ({:.0}%), {} free, {} available",
+        format_bytes(load.used_memory_bytes), format_bytes(load.total_memory_bytes), used_percent,
+        format_bytes(load.free_memory_bytes), format_bytes(load.available_memory_bytes),
+    ));
+    if load.total_swap_bytes == 0 {
+        print_field("Swap", "none");
+    } else {
+        print_field("Swap", format!("{} of {} used", format_bytes(load.used_swap_bytes), format_bytes(load.total_swap_bytes)));
+    }
+    print_footer();
+}
+
+fn print_service(output: OutputFormat, client_id: &str, info: &ServiceInfo) {
+    if output == OutputFormat::Json {
+        #[derive(Serialize)]
+        struct ClientService<'a> {
+            client_id: &'a str,
+            #[serde(flatten)]
+            info: &'a ServiceInfo,
+        }
+        print_json(&ClientService { client_id, info });
+        return;
+    }
+    
+    print_header(client_id, "SERVICE");
+    print_field("Service", &info.name);
+    let state = info.state.to_string();
+    print_field("State", match info.state {
+        ServiceState::Active => state.green().bold(),
+        ServiceState::Failed | ServiceState::NotFound => state.red().bold(),
+        _ => state.yellow().bold(),
+    });
+    if !info.details.is_empty() {
+        console!("{}", info.details);
+    }
+    print_footer();
+}
+
+fn print_probe(output: OutputFormat, client_id: &str, probe: &ProbeResult) {
+    if output == OutputFormat::Json {
+        #[derive(Serialize)]
+        struct ClientProbe<'a> {
+            client_id: &'a str,
+            #[serde(flatten)]
+            probe: &'a ProbeResult,
+        }
+        print_json(&ClientProbe { client_id, probe });
+        return;
+    }
+    
+    print_header(client_id, "TCP PROBE");
+    let target = match &probe.address {
+        Some(address) if *address != format!("{}:{}", probe.host, probe.port) => format!("{}:{} ({})", probe.host, probe.port, address),
+        _ => format!("{}:{}", probe.host, probe.port),
+    };
+    print_field("Target", target);
+    let outcome = probe.outcome.to_string();
+    print_field("Outcome", match probe.outcome {
+        ProbeOutcome::Connected => outcome.green().bold(),
+        _ => outcome.red().bold(),
+    });
+    print_field("Latency", format!("{:.1}ms", probe.latency_ms));
+    if let Some(error) = &probe.error {
+        print_field("Error", error);
+    }
+    print_footer();
+}
+
+fn print_agent_info(output: OutputFormat, client_id: &str, info: &AgentInfo) {
+    if output == OutputFormat::Json {
+        print_json(info);
+        return;
+    }
+    
+    print_header(client_id, "AGENT INFO");
+    print_field("Version", format!("{} ({}, protocol {})", info.version, info.build, info.protocol_version));
+    print_field("Process", format!("{} {}", info.pid, info.executable.as_deref().unwrap_or("")));
+    print_field("Uptime", format!("{}d {}h {}m", info.uptime_secs / 86400, info.uptime_secs % 86400 / 3600, info.uptime_secs % 3600 / 60));
+    print_field("NATS", &info.nats_url);
+    print_field("Prefix", &info.subject_prefix);
+    if let Some(dir) = &info.state_dir {
+        print_field("State", dir);
+    }
+    print_field("Delivery", if info.jetstream { "JetStream" } else { "core NATS" });
+    print_field("Payloads", format!(
+        "{}, {} compression{}", info.encoding, info.compression, if info.encrypted { ", encrypted" } else { "" }
+    ));
+    print_field("Logging", &info.log_level);
+    if let Some(path) = &info.log_file {
+        print_field("Log file", path);
+    }
+    print_field("Timeout", format!("{}s", info.command_timeout_secs));
+    if !info.shell.is_empty() {
+        print_field("Shell", &info.shell);
+    }
+    print_field("Heartbeat", format!("{}s", info.heartbeat_secs));
+    print_field("Files", format!(
+        "{} (download up to {}, output up to {})",
+        info.file_root.as_deref().unwrap_or("anywhere"),
+        format_bytes(info.max_download_bytes),
+        format_bytes(info.max_output_bytes),
+    ));
+    if info.rate_limit > 0.0 {
+        print_field("Rate", format!("{} commands/s", info.rate_limit));
+    } else {
+        print_field("Rate", "unlimited");
+    }
+    if info.max_concurrent_commands > 0 {
+        print_field("Parallel", format!("{} commands at once", info.max_concurrent_commands));
     }
-}
\ No newline at end of file
+    print_field("Power", if info.power_commands { "allowed" } else { "disabled" });
+    if info.policy_allow.is_empty() && info.policy_deny.is_empty() {
+        print_field("Policy", "none");
+    }
+    for rule in &info.policy_allow {
+        print_field("Policy", format!("allow {}", rule));
+    }
+    for rule in &info.policy_deny {
+        print_field("Policy", format!("deny {}", rule));
+    }
+    print_field("Redacted", info.redact_env.join(", "));
+    if !info.tags.is_empty() {
+        let mut tags: Vec<_> = info.tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        tags.sort();
+        print_field("Tags", tags.join(", "));
+    }
+    print_field("Commands", info.capabilities.join(", "));
+    if !info.custom_commands.is_empty() {
+        print_field("Custom", info.custom_commands.join(", "));
+    }
+    print_footer();
+}
+
+/// Lines of a file shown when `tail` starts, and by `logtail`
+const DEFAULT_TAIL_LINES: usize = 10;
+
+/// Wait between pings of `ping --count`
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn print_ping_summary(client_id: &str, sent: u32, round_trips: &[Duration]) {
+    let received = round_trips.len() as u32;
+    console!("\n--- {} ping statistics ---", client_id);
+    console!("{} sent, {} received, {:.0}% loss", sent, received, f64::from(sent - received) * 100.0 / f64::from(sent));
+    if let (Some(min), Some(max)) = (round_trips.iter().min(), round_trips.iter().max()) {
+        let avg = round_trips.iter().sum::<Duration>() / received;
+        console!("round-trip min/avg/max = {:.1}/{:.1}/{:.1} ms", millis(*min), millis(avg), millis(*max));
+    }
+}
+
+/// Lines of output shown per history entry before the rest is cut off
+const HISTORY_OUTPUT_LINES: usize = 5;
+
+fn print_history(client_id: &str, entries: &[HistoryEntry]) {
+    print_header(client_id, "HISTORY");
+    if entries.is_empty() {
+        console!("  No commands sent");
+    }
+    for entry in entries {
+        let result = &entry.result;
+        console!("{} {}", format!("[{}]", humantime::format_rfc3339_seconds(entry.sent_at)).dimmed(), entry.command);
+        console!("  {} {}", status_label(result.success), timing(result).dimmed());
+        let output = if result.stdout.is_empty() { &result.output } else { &result.stdout };
+        let lines: Vec<&str> = output.lines().collect();
+        for line in lines.iter().take(HISTORY_OUTPUT_LINES) {
+            console!("    {}", line);
+        }
+        if lines.len() > HISTORY_OUTPUT_LINES {
+            console!("    ... ({} more lines)", lines.len() - HISTORY_OUTPUT_LINES);
+        }
+        if let Some(err) = &result.error {
+            console!("  {} {}", "Error:".red().bold(), err);
+        }
+    }
+    print_footer();
+}
+
+/// Parse when a scheduled command should run: an RFC 3339 time such as
+/// `2030-01-31T02:00:00Z`, or a delay from now such as `+30m`. Returns seconds since the Unix epoch.
+fn parse_run_at(when: &str) -> Result<u64, String> {
+    let time = match when.strip_prefix('+') {
+        Some(delay) => humantime::parse_duration(delay)
+            .map(|delay| SystemTime::now() + delay)
+            .map_err(|e| format!("Invalid delay {}: {}", when, e))?,
+        None => humantime::parse_rfc3339_weak(when)
+            .map_err(|e| format!("Invalid time {}, expected e.g. 2030-01-31T02:00:00Z or +30m: {}", when, e))?,
+    };
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .map_err(|_| format!("Time {} is before 1970", when))
+}
+
+fn print_scheduled(client_id: &str, jobs: &[ScheduledJob]) {
+    console!("\nScheduled on {}:", client_id);
+    if jobs.is_empty() {
+        console!("  No scheduled commands");
+        return;
+    }
+    
+    let id_width = jobs.iter().map(|job| job.job_id.len()).max().unwrap_or(0).max("Job".len());
+    console!("  {:<id_width$}  {:<20}  Command", "Job", "Runs at");
+    for job in jobs {
+        console!("  {:<id_width$}  {:<20}  {}", job.job_id, format_unix_time(job.run_at), job.command);
+    }
+    console!();
+}
+
+fn print_connections(client_id: &str, connections: &[ConnectionInfo], note: &str) {
+    console!("\nNetwork connections on {}:", client_id);
+    if connections.is_empty() {
+        console!("  No sockets reported");
+    } else {
+        let local_width = connections.iter().map(|c| c.local_addr.len()).max().unwrap_or(0).max("Local".len());
+        let remote_width = connections.iter()
+            .map(|c| c.remote_addr.as_deref().map_or(1, str::len))
+            .max().unwrap_or(0).max("Remote".len());
+        console!("  {:<5}  {:<local_width$}  {:<remote_width$}  {:<11}  PID/Program", "Proto", "Local", "Remote", "State");
+        for connection in connections {
+            let owner = match (connection.pid, &connection.process) {
+                (Some(pid), Some(process)) => format!("{}/{}", pid, process),
+                (Some(pid), None) => pid.to_string(),
+                (None, _) => "-".to_string(),
+            };
+            console!("  {:<5}  {:<local_width$}  {:<remote_width$}  {:<11}  {}",
+                connection.protocol, connection.local_addr,
+                connection.remote_addr.as_deref().unwrap_or("-"),
+                connection.state.as_deref().unwrap_or("-"), owner);
+        }
+    }
+    if !note.is_empty() {
+        console!("  Note: {}", note);
+    }
+    console!();
+}
+
+fn print_subscriptions(subscriptions: &[Arc<SubscriptionStats>], waiting: usize) {
+    console!("\nSubscriptions:");
+    let subject_width = subscriptions.iter().map(|sub| sub.subject.len()).max().unwrap_or(0).max("Subject".len());
+    let queue_width = subscriptions.iter()
+        .map(|sub| sub.queue_group.as_deref().map_or(1, str::len))
+        .max().unwrap_or(0).max("Queue".len());
+    console!("  {:<subject_width$}  {:<15}  {:<queue_width$}  {:>8}  Open for", "Subject", "Purpose", "Queue", "Messages");
+    for sub in subscriptions {
+        let open_for = SystemTime::now().duration_since(sub.since).unwrap_or_default();
+        let open_for = humantime::format_duration(Duration::from_secs(open_for.as_secs())).to_string();
+        console!("  {:<subject_width$}  {:<15}  {:<queue_width$}  {:>8}  {}",
+            sub.subject, sub.purpose, sub.queue_group.as_deref().unwrap_or("-"),
+            sub.received.load(Ordering::Relaxed),
+            if sub.open.load(Ordering::Relaxed) { open_for } else { "closed".red().to_string() });
+    }
+    console!("{} commands waiting for a result, each with its own acknowledgement subscription", waiting);
+    console!();
+}
+
+fn print_packages(output: OutputFormat, client_id: &str, list: &PackageList) {
+    if output == OutputFormat::Json {
+        #[derive(Serialize)]
+        struct ClientPackages<'a> {
+            client_id: &'a str,
+            #[serde(flatten)]
+            list: &'a PackageList,
+        }
+        print_json(&ClientPackages { client_id, list });
+        return;
+    }
+    
+    console!("\nPackages on {} ({}, {} listed):", client_id, list.manager, list.packages.len());
+    if list.packages.is_empty() {
+        console!("  No packages found");
+    } else {
+        let name_width = list.packages.iter().map(|package| package.name.len()).max().unwrap_or(0).max("Name".len());
+        let version_width = list.packages.iter().map(|package| package.version.len()).max().unwrap_or(0).max("Version".len());
+        console!("  {:<name_width$}  {:<version_width$}  Arch", "Name", "Version");
+        for package in &list.packages {
+            console!("  {:<name_width$}  {:<version_width$}  {}", package.name, package.version, package.arch.as_deref().unwrap_or("-"));
+        }
+    }
+    if list.packages.len() < list.total {
+        console!("  Stopped at {} of {}, packages were removed while listing", list.packages.len(), list.total);
+    }
+    console!();
+}
+
+fn print_disk_usage(client_id: &str, disks: &[DiskInfo]) {
+    console!("\nDisk usage on {}:", client_id);
+    if disks.is_empty() {
+        console!("  No filesystems reported");
+        return;
+    }
+    
+    let mount_width = disks.iter().map(|disk| disk.mount_point.len()).max().unwrap_or(0).max("Mount".len());
+    console!("  {:<mount_width$}  {:<10}  {:>10}  {:>10}  {:>5}", "Mount", "FS", "Size", "Avail", "Use%");
+    for disk in disks {
+        console!("  {:<mount_width$}  {:<10}  {:>10}  {:>10}  {:>4.0}%{}",
+            disk.mount_point, disk.file_system, format_bytes(disk.total_bytes),
+            format_bytes(disk.available_bytes), disk.used_percent,
+            if disk.removable { " (removable)" } else { "" });
+    }
+    console!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn result_for(request_id: Option<&str>) -> CommandResult {
+        let mut result = CommandResult::new(true, "done".to_string(), None, CommandType::Internal);
+        result.request_id = request_id.map(str::to_string);
+        result
+    }
+    
+    #[test]
+    fn pending_request_gets_its_result() {
+        let pending = PendingRequests::default();
+        let mut first = pending.register("a");
+        let mut second = pending.register("b");
+        
+        assert!(pending.complete(result_for(Some("b"))).is_none());
+        assert_eq!(second.try_recv().unwrap().request_id.as_deref(), Some("b"));
+        assert!(first.try_recv().is_err());
+        
+        // Each result is delivered once; a duplicate has nobody left to go to
+        assert!(pending.complete(result_for(Some("b"))).is_some());
+    }
+    
+    #[test]
+    fn unclaimed_results_are_given_back() {
+        let pending = PendingRequests::default();
+        assert!(pending.complete(result_for(None)).is_some());
+        assert!(pending.complete(result_for(Some("unknown"))).is_some());
+        
+        let _waiting = pending.register("cancelled");
+        pending.cancel("cancelled");
+        assert!(pending.complete(result_for(Some("cancelled"))).is_some());
+        
+        let gone = pending.register("gone");
+        drop(gone);
+        assert!(pending.complete(result_for(Some("gone"))).is_some());
+    }
+    
+    #[test]
+    fn registering_again_replaces_the_waiter() {
+        let pending = PendingRequests::default();
+        let mut old = pending.register("a");
+        let mut new = pending.register("a");
+        
+        assert!(pending.complete(result_for(Some("a"))).is_none());
+        assert!(new.try_recv().is_ok());
+        assert!(matches!(old.try_recv(), Err(oneshot::error::TryRecvError::Closed)));
+    }
+    
+    #[tokio::test]
+    async fn concurrent_requests_each_get_their_own_result() {
+        let pending = PendingRequests::default();
+        let waiters: Vec<_> = (0..50)
+            .map(|i| {
+                let rx = pending.register(&i.to_string());
+                tokio::spawn(async move { rx.await.unwrap().request_id })
+            })
+            .collect();
+        
+        let completers: Vec<_> = (0..50).rev()
+            .map(|i| {
+                let pending = pending.clone();
+                tokio::spawn(async move { pending.complete(result_for(Some(&i.to_string()))) })
+            })
+            .collect();
+        for completer in completers {
+            assert!(completer.await.unwrap().is_none());
+        }
+        for (i, waiter) in waiters.into_iter().enumerate() {
+            assert_eq!(waiter.await.unwrap(), Some(i.to_string()));
+        }
+    }
+    
+    #[test]
+    fn commands_that_need_approval() {
+        let patterns: Vec<String> = DEFAULT_DANGEROUS_PATTERNS.iter().map(|pattern| pattern.to_string()).collect();
+        let execute = |command: &str| Command::Execute {
+            command: command.to_string(),
+            cwd: None,
+            env: None,
+            stream: false,
+            stdin: None,
+            shell: None,
+        };
+        
+        for command in [
+            Command::RebootMachine { delay_secs: None },
+            Command::PowerOff,
+            Command::UploadFile { path: "/usr/local/bin/tool".to_string(), contents: Vec::new(), mode: None },
+            Command::KillProcess { pid: 1, force: true },
+            Command::RestartAgent,
+            execute("rm -rf /var/cache/app"),
+        ] {
+            assert!(needs_approval(&command, &patterns), "{}", command);
+        }
+        for command in [Command::Ping, Command::DownloadFile { path: "/etc/hosts".to_string() }, execute("uptime")] {
+            assert!(!needs_approval(&command, &patterns), "{}", command);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Installing the client as a system service: a systemd unit on Linux, or a service
+//! registered with the service control manager on Windows.
+//!
+//! The service runs the current executable with the arguments given to `install`,
+//! restarts it if it fails, and starts it at boot. Stopping the service asks the
+//! client to shut down the same way SIGTERM does (see `shutdown_signal`).
+
+use anyhow::Result;
+use std::ffi::OsString;
+
+/// Name of the service unless one is given
+pub const DEFAULT_SERVICE_NAME: &str = "rs-nats-client";
+
+/// Register and start a service called `name` that runs this executable with `args`.
+/// Installing over an existing systemd unit of the same name replaces it.
+pub fn install(name: &str, args: &[OsString]) -> Result<()> {
+    let executable = std::env::current_exe()?;
+    platform::install(name, &executable, args)
+}
+
+/// Stop the service called `name` and remove it
+pub fn uninstall(name: &str) -> Result<()> {
+    platform::uninstall(name)
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use anyhow::{bail, Context, Result};
+    use log::info;
+    use std::ffi::OsString;
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    const UNIT_DIR: &str = "/etc/systemd/system";
+
+    fn unit_path(name: &str) -> PathBuf {
+        Path::new(UNIT_DIR).join(format!("{}.service", name))
+    }
+
+    pub fn install(name: &str, executable: &Path, args: &[OsString]) -> Result<()> {
+        let path = unit_path(name);
+        // Only root reads it, in case the options say more than they should
+        let write = || -> std::io::Result<()> {
+            let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&path)?;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            file.write_all(unit_file(executable, args).as_bytes())
+        };
+        write().with_context(|| format!("Failed to write {} (installing a service needs root)", path.display()))?;
+        info!("Wrote {}", path.display());
+
+        systemctl(&["daemon-reload"])?;
+        systemctl(&["enable", "--now", name])?;
+        info!("Service {} installed and started", name);
+        Ok(())
+    }
+
+    pub fn uninstall(name: &str) -> Result<()> {
+        let path = unit_path(name);
+        if !path.exists() {
+            bail!("Service {} is not installed ({} does not exist)", name, path.display());
+        }
+
+        systemctl(&["disable", "--now", name])?;
+        std::fs::remove_file(&path)
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+        systemctl(&["daemon-reload"])?;
+        info!("Service {} stopped and removed", name);
+        Ok(())
+    }
+
+    /// systemd stops the service with SIGTERM, which the client handles like Ctrl-C
+    fn unit_file(executable: &Path, args: &[OsString]) -> String {
+        let mut exec_start = quote(executable.as_os_str());
+        for arg in args {
+            exec_start.push(' ');
+            exec_start.push_str(&quote(arg));
+        }
+
+        format!(
+            "[Unit]\n\
+             Description=rs-nats remote support client\n\
+             Wants=network-online.target\n\
+             After=network-online.target\n\
+             \n\
+             [Service]\n\
+             ExecStart={}\n\
+             Restart=on-failure\n\
+             RestartSec=5\n\
+             \n\
+             [Install]\n\
+             WantedBy=multi-user.target\n",
+            exec_start,
+        )
+    }
+
+    /// Quote an argument for `ExecStart=`, escaping what systemd would otherwise
+    /// expand: `\` and `"` inside quotes, `%` specifiers and `$` variables
+    fn quote(arg: &std::ffi::OsStr) -> String {
+        let arg = arg.to_string_lossy()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%")
+            .replace('$', "$$");
+        format!("\"{}\"", arg)
+    }
+
+    fn systemctl(args: &[&str]) -> Result<()> {
+        let status = Command::new("systemctl").args(args).status()
+            .context("Failed to run systemctl")?;
+        if !status.success() {
+            bail!("systemctl {} failed ({})", args.join(" "), status);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use anyhow::{Context, Result};
+    use log::info;
+    use std::ffi::{OsStr, OsString};
+    use std::path::Path;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+    use windows_service::service::{
+        ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceState, ServiceType,
+    };
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    /// How long `uninstall` waits for the service to stop
+    const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn install(name: &str, executable: &Path, args: &[OsString]) -> Result<()> {
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        ).context("Failed to connect to the service manager (installing a service needs an administrator)")?;
+
+        // The executable must know it runs under the service manager, see `run_windows_service`
+        let mut launch_arguments = vec![OsString::from("--windows-service"), OsString::from(name)];
+        launch_arguments.extend(args.iter().cloned());
+
+        let info = ServiceInfo {
+            name: OsString::from(name),
+            display_name: OsString::from(format!("rs-nats client ({})", name)),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: executable.to_path_buf(),
+            launch_arguments,
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+            .with_context(|| format!("Failed to create service {}", name))?;
+        service.set_description("rs-nats remote support client")?;
+        service.start(&[] as &[&OsStr])
+            .with_context(|| format!("Failed to start service {}", name))?;
+        info!("Service {} installed and started", name);
+        Ok(())
+    }
+
+    pub fn uninstall(name: &str) -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .context("Failed to connect to the service manager")?;
+        let service = manager.open_service(name, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)
+            .with_context(|| format!("Failed to open service {}", name))?;
+
+        if service.query_status()?.current_state != ServiceState::Stopped {
+            service.stop().with_context(|| format!("Failed to stop service {}", name))?;
+            let started = Instant::now();
+            while service.query_status()?.current_state != ServiceState::Stopped && started.elapsed() < STOP_TIMEOUT {
+                sleep(Duration::from_millis(500));
+            }
+        }
+        // Removed once the last handle to it is closed
+        service.delete().with_context(|| format!("Failed to delete service {}", name))?;
+        info!("Service {} stopped and removed", name);
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    use crate::RsNatsError;
+    use anyhow::Result;
+    use std::ffi::OsString;
+    use std::path::Path;
+
+    pub fn install(_name: &str, _executable: &Path, _args: &[OsString]) -> Result<()> {
+        Err(RsNatsError::PlatformNotSupported.into())
+    }
+
+    pub fn uninstall(_name: &str) -> Result<()> {
+        Err(RsNatsError::PlatformNotSupported.into())
+    }
+}
+
+#[cfg(windows)]
+pub use windows::run_windows_service;
+
+#[cfg(windows)]
+mod windows {
+    use anyhow::Result;
+    use log::error;
+    use std::ffi::OsString;
+    use std::sync::{Mutex, OnceLock};
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    type Run = Box<dyn FnOnce() -> Result<()> + Send>;
+
+    /// Name of the service and what it runs, handed to `service_main` on the
+    /// dispatcher's thread
+    static SERVICE: OnceLock<(String, Mutex<Option<Run>>)> = OnceLock::new();
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Run `run` as the Windows service `name`, reporting its state to the service
+    /// manager. Blocks until `run` returns. Stopping the service calls
+    /// `request_shutdown`, so `run` should return once `shutdown_signal` does.
+    pub fn run_windows_service(name: &str, run: impl FnOnce() -> Result<()> + Send + 'static) -> Result<()> {
+        let _ = SERVICE.set((name.to_string(), Mutex::new(Some(Box::new(run)))));
+        service_dispatcher::start(name, ffi_service_main)?;
+        Ok(())
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let Some((name, run)) = SERVICE.get() else {
+            return;
+        };
+        let Some(run) = run.lock().unwrap().take() else {
+            return;
+        };
+
+        let status = match service_control_handler::register(name, |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                crate::request_shutdown();
+                ServiceControlHandlerResult::NoError
+            },
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }) {
+            Ok(status) => status,
+            Err(e) => {
+                error!("Failed to register service control handler: {}", e);
+                return;
+            }
+        };
+
+        set_state(&status, ServiceState::Running, ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN, 0);
+        let exit_code = match run() {
+            Ok(()) => 0,
+            Err(e) => {
+                error!("{:#}", e);
+                1
+            }
+        };
+        set_state(&status, ServiceState::Stopped, ServiceControlAccept::empty(), exit_code);
+    }
+
+    fn set_state(status: &ServiceStatusHandle, state: ServiceState, controls_accepted: ServiceControlAccept, exit_code: u32) {
+        let result = status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code: if exit_code == 0 { ServiceExitCode::Win32(0) } else { ServiceExitCode::ServiceSpecific(exit_code) },
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        });
+        if let Err(e) = result {
+            error!("Failed to report service state {:?}: {}", state, e);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Human-readable copy of a server console session.
+//!
+//! Everything the line-based console prints is appended to the transcript as shown
+//! but without colors, and every line the operator types is written with the time
+//! it was entered. Each session starts with a header line, so one file can collect
+//! several sessions across restarts. Unlike the audit log it is meant for reading
+//! back after an incident, not for checking.
+
+use crate::RsNatsError;
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+static TRANSCRIPT: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// `println!` that also appends the line to the transcript, if one was started
+macro_rules! console {
+    () => {
+        $crate::transcript::console!("")
+    };
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        println!("{}", line);
+        $crate::transcript::write(&format!("{}\n", line));
+    }};
+}
+pub(crate) use console;
+
+/// Append the console output to the file at `path` from now on
+pub fn start(path: &Path) -> Result<(), RsNatsError> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| {
+        RsNatsError::ConfigError(format!("Failed to open transcript {}: {}", path.display(), e))
+    })?;
+    writeln!(file, "\n=== Session started {} ===", now())
+        .map_err(|e| RsNatsError::ConfigError(format!("Failed to write transcript {}: {}", path.display(), e)))?;
+    TRANSCRIPT.set(Mutex::new(file))
+        .map_err(|_| RsNatsError::ConfigError("A transcript is already being written".to_string()))
+}
+
+/// Append a line the operator typed, with the time
+pub fn input(line: &str) {
+    write(&format!("[{}] > {}\n", now(), line));
+}
+
+/// Append `text` as it was printed, minus any color codes
+pub fn write(text: &str) {
+    let Some(file) = TRANSCRIPT.get() else {
+        return;
+    };
+    let mut file = file.lock().unwrap();
+    if let Err(e) = file.write_all(strip_ansi(text).as_bytes()) {
+        warn!("Failed to write transcript: {}", e);
+    }
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+/// `text` without ANSI escape sequences such as `\x1b[1;32m`
+fn strip_ansi(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            plain.push(c);
+            continue;
+        }
+        // A CSI sequence ends with a letter; drop a lone escape as well
+        if chars.clone().next() == Some('[') {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        }
+    }
+    plain
+}
@@ -0,0 +1,349 @@
+//! Full-screen server dashboard: a live client list, a command line, and a
+//! scrollable pane with the results of the commands sent from it.
+//!
+//! Keys: Up/Down select a client, Enter runs the typed command on it, PageUp/PageDown
+//! scroll the results, Esc or Ctrl-C quits. A typed command is run as a shell command,
+//! except `:ping` and `:sysinfo`.
+
+use crate::server::{ClientStatus, Server};
+use crate::{shutdown_signal, Command, CommandResult, DEFAULT_RESPONSE_TIMEOUT_SECS};
+use anyhow::Result;
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Table, TableState};
+use ratatui::Frame;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Log lines kept for the log pane
+const LOG_LINES_KEPT: usize = 200;
+
+/// Lines kept in the results pane
+const OUTPUT_LINES_KEPT: usize = 5000;
+
+/// How often the client list is refreshed without any input
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+static LOG_LINES: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Log target that keeps lines for the dashboard's log pane instead of writing
+/// them over the screen. Pass it to `env_logger::Target::Pipe` when using the dashboard.
+#[derive(Default)]
+pub struct LogWriter {
+    partial: Vec<u8>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            let mut lines = LOG_LINES.lock().unwrap_or_else(PoisonError::into_inner);
+            if lines.len() == LOG_LINES_KEPT {
+                lines.pop_front();
+            }
+            lines.push_back(String::from_utf8_lossy(&line).trim_end().to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a key press asks for
+enum Action {
+    Quit,
+    Send { client_id: String, label: String, command: Command },
+}
+
+/// A command sent from the dashboard that is still waiting for its result
+type InFlight<'a> = LocalBoxFuture<'a, (String, String, Result<CommandResult>)>;
+
+#[derive(Default)]
+struct App {
+    clients: Vec<ClientStatus>,
+    table: TableState,
+    input: String,
+    output: Vec<Line<'static>>,
+    /// How many lines the results pane is scrolled up from the bottom
+    scroll_back: usize,
+}
+
+/// Run the dashboard until the operator quits or a termination signal arrives.
+/// `server` must already be started.
+pub async fn run(server: &Server) -> Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = event_loop(server, &mut terminal).await;
+    ratatui::restore();
+    result
+}
+
+async fn event_loop(server: &Server, terminal: &mut ratatui::DefaultTerminal) -> Result<()> {
+    let mut events = spawn_event_reader();
+    let mut ticker = tokio::time::interval(REFRESH_INTERVAL);
+    let mut in_flight = FuturesUnordered::<InFlight>::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    let mut app = App::default();
+    app.push(Line::styled(
+        "Select a client with Up/Down, type a command and press Enter (:ping and :sysinfo are built in). Esc quits.",
+        Style::default().fg(Color::DarkGray),
+    ));
+
+    loop {
+        app.clients = server.clients().await;
+        app.clamp_selection();
+        terminal.draw(|frame| app.draw(frame, in_flight.len()))?;
+
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = ticker.tick() => {},
+            Some((client_id, label, result)) = in_flight.next(), if !in_flight.is_empty() => {
+                app.show_result(&client_id, &label, result);
+            },
+            event = events.recv() => {
+                let Some(event) = event else { break };
+                match app.handle_event(event) {
+                    Some(Action::Quit) => break,
+                    Some(Action::Send { client_id, label, command }) => {
+                        app.push(Line::styled(
+                            format!("> {}: {}", client_id, label),
+                            Style::default().fg(Color::Cyan),
+                        ));
+                        in_flight.push(async move {
+                            let timeout = Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS);
+                            let result = server.send_command_with_timeout(&client_id, command, timeout).await;
+                            (client_id, label, result)
+                        }.boxed_local());
+                    },
+                    None => {},
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Read terminal events on their own thread, since crossterm's reads block
+fn spawn_event_reader() -> mpsc::UnboundedReceiver<Event> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+impl App {
+    fn handle_event(&mut self, event: Event) -> Option<Action> {
+        let Event::Key(key) = event else {
+            return None;
+        };
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+
+        match key.code {
+            KeyCode::Esc => return Some(Action::Quit),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Some(Action::Quit),
+            KeyCode::Up => self.table.select_previous(),
+            KeyCode::Down => self.table.select_next(),
+            KeyCode::PageUp => self.scroll_back = (self.scroll_back + 10).min(self.output.len()),
+            KeyCode::PageDown => self.scroll_back = self.scroll_back.saturating_sub(10),
+            KeyCode::Backspace => {
+                self.input.pop();
+            },
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Enter => return self.submit(),
+            _ => {},
+        }
+        None
+    }
+
+    /// Turn the command line into a command for the selected client
+    fn submit(&mut self) -> Option<Action> {
+        let input = self.input.trim().to_string();
+        if input.is_empty() {
+            return None;
+        }
+        let Some(client) = self.table.selected().and_then(|i| self.clients.get(i)) else {
+            self.push(Line::styled("No client selected", Style::default().fg(Color::Red)));
+            return None;
+        };
+
+        let command = match input.as_str() {
+            ":ping" => Command::Ping,
+            ":sysinfo" => Command::GetSystemInfo,
+            other if other.starts_with(':') => {
+                self.push(Line::styled(
+                    format!("Unknown command {}, expected :ping or :sysinfo", other),
+                    Style::default().fg(Color::Red),
+                ));
+                return None;
+            },
+            other => Command::Execute {
+                command: other.to_string(),
+                cwd: None,
+                env: None,
+                stream: false,
+                stdin: None,
+                shell: None,
+            },
+        };
+
+        self.input.clear();
+        self.scroll_back = 0;
+        Some(Action::Send {
+            client_id: client.client_id.clone(),
+            label: input,
+            command,
+        })
+    }
+
+    fn show_result(&mut self, client_id: &str, label: &str, result: Result<CommandResult>) {
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                self.push(Line::styled(format!("< {}: {}: {}", client_id, label, e), Style::default().fg(Color::Red)));
+                return;
+            }
+        };
+
+        let (status, color) = if result.success { ("ok", Color::Green) } else { ("failed", Color::Red) };
+        self.push(Line::styled(
+            format!("< {}: {} {} in {}ms", client_id, label, status, result.duration_ms),
+            Style::default().fg(color).add_modifier(Modifier::BOLD),
+        ));
+
+        // System info comes back as compact JSON
+        let output = match serde_json::from_str::<serde_json::Value>(&result.output) {
+            Ok(value @ serde_json::Value::Object(_)) => {
+                serde_json::to_string_pretty(&value).unwrap_or(result.output)
+            },
+            _ => result.output,
+        };
+        for line in output.lines() {
+            self.push(Line::raw(line.to_string()));
+        }
+        for line in result.stderr.lines() {
+            self.push(Line::styled(line.to_string(), Style::default().fg(Color::Yellow)));
+        }
+        if let Some(err) = result.error {
+            self.push(Line::styled(err, Style::default().fg(Color::Red)));
+        }
+    }
+
+    fn push(&mut self, line: Line<'static>) {
+        if self.output.len() == OUTPUT_LINES_KEPT {
+            self.output.remove(0);
+        }
+        self.output.push(line);
+    }
+
+    /// Keep the selection on a client as clients come and go
+    fn clamp_selection(&mut self) {
+        match (self.table.selected(), self.clients.len()) {
+            (_, 0) => self.table.select(None),
+            (None, _) => self.table.select(Some(0)),
+            (Some(i), len) if i >= len => self.table.select(Some(len - 1)),
+            _ => {},
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, waiting: usize) {
+        let [main, logs, input] = Layout::vertical([
+            Constraint::Min(5),
+            Constraint::Length(8),
+            Constraint::Length(3),
+        ]).areas(frame.area());
+        let [clients, output] = Layout::horizontal([
+            Constraint::Percentage(45),
+            Constraint::Percentage(55),
+        ]).areas(main);
+
+        let rows = self.clients.iter().map(|client| {
+            let last_seen = if client.stale {
+                "stale".to_string()
+            } else {
+                format_age(client.last_seen.elapsed().unwrap_or_default())
+            };
+            Row::new(vec![
+                client.client_id.clone(),
+                client.info.hostname.clone(),
+                client.info.os_type.clone(),
+                last_seen,
+            ])
+        });
+        let table = Table::new(rows, [
+            Constraint::Percentage(35),
+            Constraint::Percentage(30),
+            Constraint::Percentage(15),
+            Constraint::Percentage(20),
+        ])
+            .header(Row::new(["ID", "Hostname", "OS", "Last seen"]).style(Style::default().add_modifier(Modifier::BOLD)))
+            .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .block(Block::default().borders(Borders::ALL).title(format!(" Clients ({}) ", self.clients.len())));
+        frame.render_stateful_widget(table, clients, &mut self.table);
+
+        // Follow the newest output unless scrolled back
+        let height = output.height.saturating_sub(2) as usize;
+        let bottom = self.output.len().saturating_sub(self.scroll_back);
+        let top = bottom.saturating_sub(height);
+        let title = if waiting > 0 {
+            format!(" Results ({} waiting) ", waiting)
+        } else {
+            " Results ".to_string()
+        };
+        let results = Paragraph::new(self.output[top..bottom].to_vec())
+            .block(Block::default().borders(Borders::ALL).title(title));
+        frame.render_widget(results, output);
+
+        let log_lines: Vec<Line> = {
+            let lines = LOG_LINES.lock().unwrap_or_else(PoisonError::into_inner);
+            let shown = logs.height.saturating_sub(2) as usize;
+            lines.iter().skip(lines.len().saturating_sub(shown)).map(|line| Line::raw(line.clone())).collect()
+        };
+        let log_pane = Paragraph::new(log_lines)
+            .style(Style::default().fg(Color::DarkGray))
+            .block(Block::default().borders(Borders::ALL).title(" Log "));
+        frame.render_widget(log_pane, logs);
+
+        let target = self.table.selected()
+            .and_then(|i| self.clients.get(i))
+            .map(|client| client.client_id.as_str())
+            .unwrap_or("no client");
+        let prompt = Paragraph::new(Line::from(vec![
+            Span::styled(format!("{}> ", target), Style::default().fg(Color::Cyan)),
+            Span::raw(self.input.as_str()),
+        ]))
+            .block(Block::default().borders(Borders::ALL).title(" Command "));
+        frame.render_widget(prompt, input);
+        frame.set_cursor_position((
+            input.x + 1 + target.len() as u16 + 2 + self.input.chars().count() as u16,
+            input.y + 1,
+        ));
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        _ => format!("{}h ago", secs / 3600),
+    }
+}